@@ -0,0 +1,438 @@
+// Minimal reading and writing of uncompressed DDS and KTX2 textures
+// Only what palette textures need: 2D, RGBA, optional mip chain
+
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Read, Write};
+
+// DDS header flags
+const DDSD_CAPS: u32 = 0x1;
+const DDSD_HEIGHT: u32 = 0x2;
+const DDSD_WIDTH: u32 = 0x4;
+const DDSD_PITCH: u32 = 0x8;
+const DDSD_PIXELFORMAT: u32 = 0x1000;
+const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+const DDPF_ALPHAPIXELS: u32 = 0x1;
+const DDPF_FOURCC: u32 = 0x4;
+const DDPF_RGB: u32 = 0x40;
+const DDSCAPS_COMPLEX: u32 = 0x8;
+const DDSCAPS_TEXTURE: u32 = 0x1000;
+const DDSCAPS_MIPMAP: u32 = 0x400000;
+const DDS_DIMENSION_TEXTURE2D: u32 = 3;
+const DDS_ALPHA_MODE_STRAIGHT: u32 = 1;
+
+// DXGI formats
+const DXGI_FORMAT_R32G32B32A32_FLOAT: u32 = 2;
+const DXGI_FORMAT_R16G16B16A16_UNORM: u32 = 11;
+const DXGI_FORMAT_R8G8B8A8_UNORM: u32 = 28;
+const DXGI_FORMAT_R8G8B8A8_UNORM_SRGB: u32 = 29;
+
+// Vulkan formats
+const VK_FORMAT_R8G8B8A8_UNORM: u32 = 37;
+const VK_FORMAT_R8G8B8A8_SRGB: u32 = 43;
+const VK_FORMAT_R16G16B16A16_UNORM: u32 = 91;
+const VK_FORMAT_R32G32B32A32_SFLOAT: u32 = 109;
+
+const KTX2_IDENTIFIER: [u8; 12] = [ 0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A ];
+const KTX2_HEADER_SIZE: usize = 80;
+
+// A 32 bit size halves down to 1x1 in at most 32 steps
+const MAX_LEVELS: usize = 33;
+
+// Data format descriptor values (Khronos Data Format Specification)
+const KHR_DF_MODEL_RGBSDA: u32 = 1;
+const KHR_DF_PRIMARIES_BT709: u32 = 1;
+const KHR_DF_TRANSFER_LINEAR: u32 = 1;
+const KHR_DF_TRANSFER_SRGB: u32 = 2;
+const KHR_DF_CHANNEL_ALPHA: u32 = 15;
+const KHR_DF_SAMPLE_DATATYPE_LINEAR: u32 = 0x10;
+const KHR_DF_SAMPLE_DATATYPE_SIGNED: u32 = 0x40;
+const KHR_DF_SAMPLE_DATATYPE_FLOAT: u32 = 0x80;
+
+/// Pixel layout of the texture data, always RGBA
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum PixelFormat {
+	/// 8 bits per channel, tagged as sRGB or as linear UNORM
+	Rgba8 { srgb: bool },
+	/// 16 bits per channel, linear UNORM
+	Rgba16,
+	/// 32 bit float per channel, linear
+	Rgba32F,
+}
+
+impl PixelFormat {
+	pub fn bytes_per_pixel( &self ) -> usize {
+		match self {
+			PixelFormat::Rgba8 { .. } => 4,
+			PixelFormat::Rgba16 => 8,
+			PixelFormat::Rgba32F => 16,
+		}
+	}
+
+	fn dxgi_format( &self ) -> u32 {
+		match self {
+			PixelFormat::Rgba8 { srgb: true } => DXGI_FORMAT_R8G8B8A8_UNORM_SRGB,
+			PixelFormat::Rgba8 { srgb: false } => DXGI_FORMAT_R8G8B8A8_UNORM,
+			PixelFormat::Rgba16 => DXGI_FORMAT_R16G16B16A16_UNORM,
+			PixelFormat::Rgba32F => DXGI_FORMAT_R32G32B32A32_FLOAT,
+		}
+	}
+
+	fn from_dxgi_format( dxgi_format: u32 ) -> Option< PixelFormat > {
+		match dxgi_format {
+			DXGI_FORMAT_R8G8B8A8_UNORM_SRGB => Some( PixelFormat::Rgba8 { srgb: true } ),
+			DXGI_FORMAT_R8G8B8A8_UNORM => Some( PixelFormat::Rgba8 { srgb: false } ),
+			DXGI_FORMAT_R16G16B16A16_UNORM => Some( PixelFormat::Rgba16 ),
+			DXGI_FORMAT_R32G32B32A32_FLOAT => Some( PixelFormat::Rgba32F ),
+			_ => None,
+		}
+	}
+
+	fn vk_format( &self ) -> u32 {
+		match self {
+			PixelFormat::Rgba8 { srgb: true } => VK_FORMAT_R8G8B8A8_SRGB,
+			PixelFormat::Rgba8 { srgb: false } => VK_FORMAT_R8G8B8A8_UNORM,
+			PixelFormat::Rgba16 => VK_FORMAT_R16G16B16A16_UNORM,
+			PixelFormat::Rgba32F => VK_FORMAT_R32G32B32A32_SFLOAT,
+		}
+	}
+
+	fn from_vk_format( vk_format: u32 ) -> Option< PixelFormat > {
+		match vk_format {
+			VK_FORMAT_R8G8B8A8_SRGB => Some( PixelFormat::Rgba8 { srgb: true } ),
+			VK_FORMAT_R8G8B8A8_UNORM => Some( PixelFormat::Rgba8 { srgb: false } ),
+			VK_FORMAT_R16G16B16A16_UNORM => Some( PixelFormat::Rgba16 ),
+			VK_FORMAT_R32G32B32A32_SFLOAT => Some( PixelFormat::Rgba32F ),
+			_ => None,
+		}
+	}
+}
+
+/// Uncompressed texture with its mip chain, level 0 first
+#[derive(Debug,Clone)]
+pub struct TextureData {
+	pub width: u32,
+	pub height: u32,
+	pub format: PixelFormat,
+	pub levels: Vec< Vec< u8 > >,
+}
+
+impl TextureData {
+	// Size of the given mip level, never below 1x1
+	pub fn level_size( &self, level: usize ) -> ( u32, u32 ) {
+		let shift = |size: u32| u32::try_from( level ).ok()
+			.and_then( |level| size.checked_shr( level ) )
+			.unwrap_or( 0 )
+			.max( 1 );
+		( shift( self.width ), shift( self.height ) )
+	}
+
+	// Bytes of the given mip level, an error if that doesn't fit into memory
+	fn level_length( &self, level: usize ) -> std::io::Result< usize > {
+		let ( w, h ) = self.level_size( level );
+		( w as usize ).checked_mul( h as usize )
+			.and_then( |pixels| pixels.checked_mul( self.format.bytes_per_pixel() ) )
+			.ok_or_else( || invalid_data( &format!( "level {} is too large", level ) ) )
+	}
+
+	fn check( &self ) -> std::io::Result< () > {
+		if self.levels.is_empty() {
+			return Err( invalid_data( "texture without levels" ) );
+		}
+		if self.levels.len() > MAX_LEVELS {
+			return Err( invalid_data( &format!( "too many levels ({})", self.levels.len() ) ) );
+		}
+		for ( level, data ) in self.levels.iter().enumerate() {
+			if data.len() != self.level_length( level )? {
+				return Err( invalid_data( &format!( "wrong data size for level {}", level ) ) );
+			}
+		}
+		Ok( () )
+	}
+}
+
+fn invalid_data( message: &str ) -> Error {
+	Error::new( ErrorKind::InvalidData, message.to_string() )
+}
+
+fn write_u32( out: &mut Vec< u8 >, v: u32 ) {
+	out.extend_from_slice( &v.to_le_bytes() );
+}
+
+fn write_u64( out: &mut Vec< u8 >, v: u64 ) {
+	out.extend_from_slice( &v.to_le_bytes() );
+}
+
+fn read_u32( data: &[u8], offset: usize ) -> std::io::Result< u32 > {
+	offset.checked_add( 4 )
+		.and_then( |end| data.get( offset..end ) )
+		.map( |b| u32::from_le_bytes( [ b[ 0 ], b[ 1 ], b[ 2 ], b[ 3 ] ] ) )
+		.ok_or_else( || invalid_data( "unexpected end of file" ) )
+}
+
+fn read_u64( data: &[u8], offset: usize ) -> std::io::Result< u64 > {
+	Ok( read_u32( data, offset )? as u64 | ( ( read_u32( data, offset + 4 )? as u64 ) << 32 ) )
+}
+
+fn read_usize( data: &[u8], offset: usize ) -> std::io::Result< usize > {
+	usize::try_from( read_u64( data, offset )? ).map_err( |_| invalid_data( "offset or length too large" ) )
+}
+
+fn read_level_count( data: &[u8], offset: usize ) -> std::io::Result< usize > {
+	let level_count = read_u32( data, offset )?.max( 1 ) as usize;
+	if level_count > MAX_LEVELS {
+		return Err( invalid_data( &format!( "too many levels ({})", level_count ) ) );
+	}
+	Ok( level_count )
+}
+
+fn read_file( filename: &str ) -> std::io::Result< Vec< u8 > > {
+	let mut data = Vec::new();
+	std::fs::File::open( filename )?.read_to_end( &mut data )?;
+	Ok( data )
+}
+
+fn slice( data: &[u8], offset: usize, length: usize ) -> std::io::Result< Vec< u8 > > {
+	offset.checked_add( length )
+		.and_then( |end| data.get( offset..end ) )
+		.map( |d| d.to_vec() )
+		.ok_or_else( || invalid_data( "unexpected end of file" ) )
+}
+
+pub fn write_dds( filename: &str, texture: &TextureData ) -> std::io::Result< () > {
+	texture.check()?;
+
+	let mipmapped = texture.levels.len() > 1;
+	let mut out = Vec::new();
+
+	out.extend_from_slice( b"DDS " );
+	write_u32( &mut out, 124 );
+	let mut flags = DDSD_CAPS | DDSD_HEIGHT | DDSD_WIDTH | DDSD_PITCH | DDSD_PIXELFORMAT;
+	if mipmapped {
+		flags |= DDSD_MIPMAPCOUNT;
+	}
+	write_u32( &mut out, flags );
+	write_u32( &mut out, texture.height );
+	write_u32( &mut out, texture.width );
+	write_u32( &mut out, texture.width * texture.format.bytes_per_pixel() as u32 );	// pitch
+	write_u32( &mut out, 0 );	// depth
+	write_u32( &mut out, texture.levels.len() as u32 );
+	for _ in 0..11 {
+		write_u32( &mut out, 0 );	// reserved
+	}
+
+	// Pixel format, the actual format is in the DX10 header
+	write_u32( &mut out, 32 );
+	write_u32( &mut out, DDPF_FOURCC );
+	out.extend_from_slice( b"DX10" );
+	for _ in 0..5 {
+		write_u32( &mut out, 0 );	// bit count and masks
+	}
+
+	let mut caps = DDSCAPS_TEXTURE;
+	if mipmapped {
+		caps |= DDSCAPS_COMPLEX | DDSCAPS_MIPMAP;
+	}
+	write_u32( &mut out, caps );
+	for _ in 0..4 {
+		write_u32( &mut out, 0 );	// caps2, caps3, caps4, reserved
+	}
+
+	// DX10 header
+	write_u32( &mut out, texture.format.dxgi_format() );
+	write_u32( &mut out, DDS_DIMENSION_TEXTURE2D );
+	write_u32( &mut out, 0 );	// misc flags
+	write_u32( &mut out, 1 );	// array size
+	write_u32( &mut out, DDS_ALPHA_MODE_STRAIGHT );
+
+	for level in &texture.levels {
+		out.extend_from_slice( level );
+	}
+
+	std::fs::File::create( filename )?.write_all( &out )
+}
+
+pub fn read_dds( filename: &str ) -> std::io::Result< TextureData > {
+	let data = read_file( filename )?;
+	if data.get( 0..4 ) != Some( b"DDS " ) {
+		return Err( invalid_data( "not a DDS file" ) );
+	}
+
+	let height = read_u32( &data, 12 )?;
+	let width = read_u32( &data, 16 )?;
+	let level_count = read_level_count( &data, 28 )?;
+	let pf_flags = read_u32( &data, 80 )?;
+	let fourcc = slice( &data, 84, 4 )?;
+
+	let ( format, mut offset, swizzle ) = if pf_flags & DDPF_FOURCC != 0 && fourcc == b"DX10" {
+		let dxgi_format = read_u32( &data, 128 )?;
+		let format = PixelFormat::from_dxgi_format( dxgi_format )
+			.ok_or_else( || invalid_data( &format!( "unsupported DXGI format {}", dxgi_format ) ) )?;
+		( format, 148, false )
+	} else if pf_flags & DDPF_RGB != 0 && read_u32( &data, 88 )? == 32 {
+		// Legacy 32 bit RGBA as written by many tools, either RGBA or BGRA in memory
+		let r_mask = read_u32( &data, 92 )?;
+		let a_mask = if pf_flags & DDPF_ALPHAPIXELS != 0 { read_u32( &data, 104 )? } else { 0 };
+		if a_mask != 0 && a_mask != 0xff000000 {
+			return Err( invalid_data( "unsupported DDS channel masks" ) );
+		}
+		match r_mask {
+			0x000000ff => ( PixelFormat::Rgba8 { srgb: true }, 128, false ),
+			0x00ff0000 => ( PixelFormat::Rgba8 { srgb: true }, 128, true ),
+			_ => return Err( invalid_data( "unsupported DDS channel masks" ) ),
+		}
+	} else {
+		return Err( invalid_data( "unsupported DDS pixel format (only uncompressed RGBA)" ) );
+	};
+
+	let mut texture = TextureData { width, height, format, levels: Vec::new() };
+	for level in 0..level_count {
+		let length = texture.level_length( level )?;
+		let mut level_data = slice( &data, offset, length )?;
+		if swizzle {
+			for pixel in level_data.chunks_exact_mut( 4 ) {
+				pixel.swap( 0, 2 );
+			}
+		}
+		texture.levels.push( level_data );
+		offset = offset.checked_add( length ).ok_or_else( || invalid_data( "unexpected end of file" ) )?;
+	}
+
+	Ok( texture )
+}
+
+// Basic data format descriptor for the RGBA pixel formats
+fn ktx2_dfd( format: PixelFormat ) -> Vec< u8 > {
+	let ( bits, transfer, data_type, lower, upper ) = match format {
+		PixelFormat::Rgba8 { srgb } => (
+			8,
+			if srgb { KHR_DF_TRANSFER_SRGB } else { KHR_DF_TRANSFER_LINEAR },
+			0,
+			0,
+			255,
+		),
+		PixelFormat::Rgba16 => ( 16, KHR_DF_TRANSFER_LINEAR, 0, 0, 65535 ),
+		PixelFormat::Rgba32F => (
+			32,
+			KHR_DF_TRANSFER_LINEAR,
+			KHR_DF_SAMPLE_DATATYPE_FLOAT | KHR_DF_SAMPLE_DATATYPE_SIGNED,
+			( -1.0f32 ).to_bits(),
+			1.0f32.to_bits(),
+		),
+	};
+
+	let sample_count = 4;
+	let block_size = 24 + 16 * sample_count;
+
+	let mut dfd = Vec::new();
+	write_u32( &mut dfd, 4 + block_size );
+	write_u32( &mut dfd, 0 );	// vendor Khronos, basic descriptor type
+	write_u32( &mut dfd, 2 | ( block_size << 16 ) );	// version 1.3
+	write_u32( &mut dfd, KHR_DF_MODEL_RGBSDA | ( KHR_DF_PRIMARIES_BT709 << 8 ) | ( transfer << 16 ) );
+	write_u32( &mut dfd, 0 );	// 1x1x1x1 texel block
+	write_u32( &mut dfd, format.bytes_per_pixel() as u32 );	// bytes in plane 0
+	write_u32( &mut dfd, 0 );
+
+	for ( i, channel ) in [ 0, 1, 2, KHR_DF_CHANNEL_ALPHA ].iter().enumerate() {
+		let mut channel_type = channel | data_type;
+		// Alpha is never sRGB encoded
+		if *channel == KHR_DF_CHANNEL_ALPHA && transfer == KHR_DF_TRANSFER_SRGB {
+			channel_type |= KHR_DF_SAMPLE_DATATYPE_LINEAR;
+		}
+		write_u32( &mut dfd, ( i as u32 * bits ) | ( ( bits - 1 ) << 16 ) | ( channel_type << 24 ) );
+		write_u32( &mut dfd, 0 );	// sample position
+		write_u32( &mut dfd, lower );
+		write_u32( &mut dfd, upper );
+	}
+
+	dfd
+}
+
+fn align( offset: usize, alignment: usize ) -> usize {
+	offset.div_ceil( alignment ) * alignment
+}
+
+pub fn write_ktx2( filename: &str, texture: &TextureData ) -> std::io::Result< () > {
+	texture.check()?;
+
+	let level_count = texture.levels.len();
+	let dfd = ktx2_dfd( texture.format );
+	let dfd_offset = KTX2_HEADER_SIZE + 24 * level_count;
+
+	// Level data follows the descriptor, smallest level first,
+	// each aligned to the texel size (and 4 bytes)
+	let alignment = texture.format.bytes_per_pixel().max( 4 );
+	let mut level_offsets = vec![ 0; level_count ];
+	let mut offset = dfd_offset + dfd.len();
+	for level in ( 0..level_count ).rev() {
+		offset = align( offset, alignment );
+		level_offsets[ level ] = offset;
+		offset += texture.levels[ level ].len();
+	}
+
+	let mut out = Vec::new();
+	out.extend_from_slice( &KTX2_IDENTIFIER );
+	write_u32( &mut out, texture.format.vk_format() );
+	write_u32( &mut out, match texture.format {
+		PixelFormat::Rgba8 { .. } => 1,
+		PixelFormat::Rgba16 => 2,
+		PixelFormat::Rgba32F => 4,
+	} );	// type size
+	write_u32( &mut out, texture.width );
+	write_u32( &mut out, texture.height );
+	write_u32( &mut out, 0 );	// depth
+	write_u32( &mut out, 0 );	// layers
+	write_u32( &mut out, 1 );	// faces
+	write_u32( &mut out, level_count as u32 );
+	write_u32( &mut out, 0 );	// no supercompression
+
+	write_u32( &mut out, dfd_offset as u32 );
+	write_u32( &mut out, dfd.len() as u32 );
+	write_u32( &mut out, 0 );	// no key/value data
+	write_u32( &mut out, 0 );
+	write_u64( &mut out, 0 );	// no supercompression global data
+	write_u64( &mut out, 0 );
+
+	for ( level, data ) in texture.levels.iter().enumerate() {
+		write_u64( &mut out, level_offsets[ level ] as u64 );
+		write_u64( &mut out, data.len() as u64 );
+		write_u64( &mut out, data.len() as u64 );
+	}
+
+	out.extend_from_slice( &dfd );
+
+	for level in ( 0..level_count ).rev() {
+		out.resize( level_offsets[ level ], 0 );
+		out.extend_from_slice( &texture.levels[ level ] );
+	}
+
+	std::fs::File::create( filename )?.write_all( &out )
+}
+
+pub fn read_ktx2( filename: &str ) -> std::io::Result< TextureData > {
+	let data = read_file( filename )?;
+	if data.get( 0..12 ) != Some( &KTX2_IDENTIFIER[ .. ] ) {
+		return Err( invalid_data( "not a KTX2 file" ) );
+	}
+
+	let vk_format = read_u32( &data, 12 )?;
+	let format = PixelFormat::from_vk_format( vk_format )
+		.ok_or_else( || invalid_data( &format!( "unsupported Vulkan format {}", vk_format ) ) )?;
+	let width = read_u32( &data, 20 )?;
+	let height = read_u32( &data, 24 )?.max( 1 );
+	let level_count = read_level_count( &data, 40 )?;
+	if read_u32( &data, 44 )? != 0 {
+		return Err( invalid_data( "supercompressed KTX2 files are not supported" ) );
+	}
+
+	let mut levels = Vec::new();
+	for level in 0..level_count {
+		let index = KTX2_HEADER_SIZE + 24 * level;
+		let offset = read_usize( &data, index )?;
+		let length = read_usize( &data, index + 8 )?;
+		levels.push( slice( &data, offset, length )? );
+	}
+
+	let texture = TextureData { width, height, format, levels };
+	texture.check()?;
+	Ok( texture )
+}
@@ -0,0 +1,160 @@
+// Color expressions: CSS colors, references to palette entries and functions deriving new colors
+// e.g. "mix(red, @3, 30%)", "darken(@skin, 5%)", "lighten(#884422, 10%)" or "rotate-hue(teal, 30deg)"
+// Everything is evaluated in OKLab/OKLCH.
+
+use crate::lowtexpal::{Color, ColorParseError, Component, LowTexPal};
+
+// Plenty for hand written expressions, and far from overflowing the stack
+const MAX_DEPTH: usize = 32;
+
+/// Evaluates a color expression, @N refers to the (1-based) palette entry N and @last to the last entry
+pub fn evaluate( expression: &str, palette: &[Color] ) -> Result< Color, ColorParseError > {
+	evaluate_named( expression, palette, &[] )
+}
+
+/// Evaluates a color expression, where entries can also be referred to by name as @name
+pub fn evaluate_named( expression: &str, palette: &[Color], names: &[Option< String >] ) -> Result< Color, ColorParseError > {
+	evaluate_nested( expression, palette, names, 0 )
+}
+
+fn evaluate_nested( expression: &str, palette: &[Color], names: &[Option< String >], depth: usize ) -> Result< Color, ColorParseError > {
+	let expression = expression.trim();
+
+	if expression.starts_with( '@' ) {
+		return resolve_reference( expression, palette.len(), names )
+			.map( |index| palette[ index - 1 ] )
+			.ok_or_else( || ColorParseError::InvalidReference {
+				reference: expression.to_string(),
+				entries: palette.len(),
+			} );
+	}
+
+	let Some( ( name, rest ) ) = expression.split_once( '(' ) else {
+		return Color::parse( expression );
+	};
+	let name = name.trim().to_ascii_lowercase();
+	if !matches!( name.as_str(), "mix" | "lighten" | "darken" | "rotate-hue" ) {
+		return Color::parse( expression );
+	}
+	if depth >= MAX_DEPTH {
+		return Err( ColorParseError::NestingTooDeep( MAX_DEPTH ) );
+	}
+	let evaluate_argument = |argument: &str| evaluate_nested( argument, palette, names, depth + 1 );
+
+	let inner = rest.trim_end().strip_suffix( ')' ).ok_or_else( || ColorParseError::InvalidSyntax( expression.to_string() ) )?;
+	let arguments = split_arguments( inner ).ok_or_else( || ColorParseError::InvalidSyntax( expression.to_string() ) )?;
+
+	let argument_count = |expected: &'static str, range: std::ops::RangeInclusive< usize >| {
+		if range.contains( &arguments.len() ) {
+			Ok( () )
+		} else {
+			Err( ColorParseError::ArgumentCount { function: name.clone(), expected, found: arguments.len() } )
+		}
+	};
+	let number = |position: usize, kind: Component, expected: &'static str| {
+		let value = arguments[ position - 1 ];
+		Color::parse_component( value, kind ).ok_or_else( || ColorParseError::InvalidArgument {
+			function: name.clone(),
+			position,
+			value: value.to_string(),
+			expected,
+		} )
+	};
+
+	match name.as_str() {
+		"mix" => {
+			argument_count( "2 or 3", 2..=3 )?;
+			let start = evaluate_argument( arguments[ 0 ] )?;
+			let end = evaluate_argument( arguments[ 1 ] )?;
+			let amount = if arguments.len() == 3 {
+				let amount = number( 3, Component::Number( 1.0 ), "a percentage" )?;
+				// Amounts outside of 0-100% would extrapolate
+				if !( 0.0..=1.0 ).contains( &amount ) {
+					return Err( ColorParseError::InvalidArgument {
+						function: name.clone(),
+						position: 3,
+						value: arguments[ 2 ].to_string(),
+						expected: "a percentage between 0% and 100%",
+					} );
+				}
+				amount
+			} else {
+				0.5
+			};
+			Ok( mix( &start, &end, amount ) )
+		},
+		"lighten" | "darken" => {
+			argument_count( "2", 2..=2 )?;
+			let color = evaluate_argument( arguments[ 0 ] )?;
+			let amount = number( 2, Component::Number( 1.0 ), "a percentage" )?;
+			let amount = if name == "darken" { -amount } else { amount };
+
+			let mut lch = color.to_oklch();
+			lch[ 0 ] = ( lch[ 0 ] + amount ).clamp( 0.0, 1.0 );
+			Ok( from_oklch( lch, color.alpha() ) )
+		},
+		_ => {
+			argument_count( "2", 2..=2 )?;
+			let color = evaluate_argument( arguments[ 0 ] )?;
+			let degrees = number( 2, Component::Hue, "a hue (number or angle)" )?;
+
+			let mut lch = color.to_oklch();
+			lch[ 2 ] += degrees.to_radians();
+			Ok( from_oklch( lch, color.alpha() ) )
+		},
+	}
+}
+
+/// Index (1-based) of the entry an @N, @last or @name reference points to
+pub fn resolve_reference( reference: &str, entries: usize, names: &[Option< String >] ) -> Option< usize > {
+	let reference = reference.trim().strip_prefix( '@' )?;
+	let index = match reference {
+		"last" => entries,
+		_ => match reference.parse::< usize >() {
+			Ok( entry ) => entry,
+			Err( _ ) => names.iter().position( |n| n.as_deref() == Some( reference ) )? + 1,
+		},
+	};
+	( 1..=entries ).contains( &index ).then_some( index )
+}
+
+// Mixes in OKLab with premultiplied alpha (like CSS color-mix), amount is the share of the second color
+fn mix( start: &Color, end: &Color, amount: f32 ) -> Color {
+	let ( lab, alpha ) = LowTexPal::interpolate( start.to_oklab(), end.to_oklab(), start.alpha(), end.alpha(), amount, true, None );
+	Color::from_linear( Color::oklab_to_linear_srgb( lab ), alpha )
+}
+
+// Keeps out of gamut results, they are mapped when saving
+fn from_oklch( lch: [f32; 3], alpha: f32 ) -> Color {
+	Color::from_linear( Color::oklab_to_linear_srgb( Color::oklch_to_oklab( lch ) ), alpha )
+}
+
+// Splits at top level commas, so arguments can be expressions with commas themselves
+fn split_arguments( inner: &str ) -> Option< Vec< &str > > {
+	let mut arguments = Vec::new();
+	let mut depth = 0;
+	let mut start = 0;
+
+	for ( i, c ) in inner.char_indices() {
+		match c {
+			'(' => depth += 1,
+			')' => {
+				if depth == 0 {
+					return None;
+				}
+				depth -= 1;
+			},
+			',' if depth == 0 => {
+				arguments.push( inner[ start..i ].trim() );
+				start = i + 1;
+			},
+			_ => {},
+		}
+	}
+	if depth != 0 {
+		return None;
+	}
+	arguments.push( inner[ start.. ].trim() );
+
+	Some( arguments )
+}
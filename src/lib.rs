@@ -1,7 +1,28 @@
+pub mod container;
+pub mod expression;
 pub mod lowtexpal;
+pub mod model;
+pub mod query;
+pub mod remap;
 
 #[cfg(test)]
 mod tests;
 
 pub use lowtexpal::LowTexPal;
+pub use lowtexpal::BitDepth;
 pub use lowtexpal::Color;
+pub use lowtexpal::ColorParseError;
+pub use lowtexpal::DeltaE;
+pub use lowtexpal::Encoding;
+pub use lowtexpal::GamutMapping;
+pub use lowtexpal::GradientOptions;
+pub use lowtexpal::Layout;
+pub use lowtexpal::Material;
+pub use lowtexpal::MaterialMaps;
+pub use lowtexpal::MaterialScalar;
+pub use lowtexpal::MAX_IMAGE_SIZE;
+pub use lowtexpal::NearestEntry;
+pub use lowtexpal::OutOfGamut;
+pub use lowtexpal::SortKey;
+pub use lowtexpal::TextureFormat;
+pub use remap::RemapEntry;
@@ -1,7 +1,44 @@
+use crate::container::{self, PixelFormat, TextureData};
+use crate::expression;
+use crate::remap::RemapEntry;
+
 // OKLab color space conversion matrices and constants
 // Based on Björn Ottosson's OKLab specification (bottosson.github.io/posts/oklab)
 
+// Chroma below which an OKLCH hue is considered powerless (CSS Color 4, section 12.4)
+const ACHROMATIC_CHROMA: f32 = 0.0004;
+
+// Largest number of cells per row the rows layout grows to
+const MAX_ROWS_LAYOUT_SIZE: u32 = 64;
+
+// Cells per row a new fixed palette reserves without a minimum width
+const FIXED_LAYOUT_DEFAULT_SIZE: u32 = 16;
+
+/// Largest side of a saved palette image in pixels
+pub const MAX_IMAGE_SIZE: u32 = 16384;
+
+// CSS Color 4 gamut mapping: just noticeable difference and search precision (ΔE OK)
+const GAMUT_MAPPING_JND: f32 = 0.02;
+const GAMUT_MAPPING_EPSILON: f32 = 0.0001;
+
+// Tolerance for float error when checking linear sRGB against the unit cube
+// (sRGB primaries round trip through the f32 OKLab matrices with errors up to ~0.0008)
+const GAMUT_EPSILON: f32 = 0.001;
+
+// Tolerance for float error before a channel counts as brighter than 1.0
+const HDR_EPSILON: f32 = 0.0001;
+
+// PNG gAMA values are stored times 100000, sRGB is approximated as 1/2.2
+const PNG_GAMMA_SRGB: u32 = 45455;
+const PNG_GAMMA_LINEAR: u32 = 100000;
+
+// ITU-T H.273 code points for cICP: BT.709 primaries, sRGB or linear transfer, RGB, full range
+const CICP_PRIMARIES_BT709: u8 = 1;
+const CICP_TRANSFER_SRGB: u8 = 13;
+const CICP_TRANSFER_LINEAR: u8 = 8;
+
 // M1: Linear sRGB to LMS
+#[allow(clippy::excessive_precision)]
 const M1: [[f32; 3]; 3] = [
 	[0.4122214708, 0.5363325363, 0.0514459929],
 	[0.2119034982, 0.6806995451, 0.1073969566],
@@ -9,6 +46,7 @@ const M1: [[f32; 3]; 3] = [
 ];
 
 // M1^-1: LMS to Linear sRGB
+#[allow(clippy::excessive_precision)]
 const M1_INV: [[f32; 3]; 3] = [
 	[ 4.0767245293, -3.3077216883,  0.2309759054],
 	[-1.2681437731,  2.6093323231, -0.3411344290],
@@ -16,6 +54,7 @@ const M1_INV: [[f32; 3]; 3] = [
 ];
 
 // M2: L'M'S' to OKLab
+#[allow(clippy::excessive_precision)]
 const M2: [[f32; 3]; 3] = [
 	[ 0.2104542553,  0.7936177850, -0.0040720468],
 	[ 1.9779984951, -2.4285922050,  0.4505937099],
@@ -23,12 +62,460 @@ const M2: [[f32; 3]; 3] = [
 ];
 
 // M2^-1: OKLab to L'M'S'
+#[allow(clippy::excessive_precision)]
 const M2_INV: [[f32; 3]; 3] = [
 	[1.0000000000,  0.3963377774,  0.2158037573],
 	[1.0000000000, -0.1055613458, -0.0638541728],
 	[1.0000000000, -0.0894841775, -1.2914855480],
 ];
 
+// Linear Display P3 to linear sRGB (through XYZ D65, CSS Color 4)
+#[allow(clippy::excessive_precision)]
+const P3_TO_SRGB: [[f32; 3]; 3] = [
+	[ 1.2249401763, -0.2249401763,  0.0000000000],
+	[-0.0420569547,  1.0420569547,  0.0000000000],
+	[-0.0196375546, -0.0786360456,  1.0982736001],
+];
+
+#[allow(clippy::excessive_precision)]
+const SRGB_TO_P3: [[f32; 3]; 3] = [
+	[0.8224619687, 0.1775380313, 0.0000000000],
+	[0.0331941989, 0.9668058011, 0.0000000000],
+	[0.0170826307, 0.0723974407, 0.9105199286],
+];
+
+// Linear Rec.2020 to linear sRGB (through XYZ D65, CSS Color 4)
+#[allow(clippy::excessive_precision)]
+const REC2020_TO_SRGB: [[f32; 3]; 3] = [
+	[ 1.6604910021, -0.5876411388, -0.0728498633],
+	[-0.1245504745,  1.1328998971, -0.0083494226],
+	[-0.0181507634, -0.1005788980,  1.1187296614],
+];
+
+#[allow(clippy::excessive_precision)]
+const SRGB_TO_REC2020: [[f32; 3]; 3] = [
+	[0.6274038959, 0.3292830384, 0.0433130657],
+	[0.0690972894, 0.9195403951, 0.0113623156],
+	[0.0163914389, 0.0880133079, 0.8955952532],
+];
+
+// XYZ (D50, as used by CIE Lab) to linear sRGB, including the Bradford adaptation to D65 (CSS Color 4)
+#[allow(clippy::excessive_precision)]
+const XYZ_D50_TO_SRGB: [[f32; 3]; 3] = [
+	[ 3.1341358529, -1.6173859980, -0.4906622179],
+	[-0.9787954766,  1.9162543774,  0.0334428734],
+	[ 0.0719553926, -0.2289767598,  1.4053860351],
+];
+
+#[allow(clippy::excessive_precision)]
+const SRGB_TO_XYZ_D50: [[f32; 3]; 3] = [
+	[0.4360657469, 0.3851515096, 0.1430784200],
+	[0.2224931771, 0.7168870131, 0.0606198098],
+	[0.0139239215, 0.0970813242, 0.7140993568],
+];
+
+// Linear sRGB to XYZ (D65) and back
+#[allow(clippy::excessive_precision)]
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+	[0.4123907993, 0.3575843394, 0.1804807884],
+	[0.2126390059, 0.7151686788, 0.0721923154],
+	[0.0193308187, 0.1191947798, 0.9505321522],
+];
+
+#[allow(clippy::excessive_precision)]
+const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+	[ 3.2409699419, -1.5373831776, -0.4986107603],
+	[-0.9692436363,  1.8759675015,  0.0415550574],
+	[ 0.0556300797, -0.2039769589,  1.0569715142],
+];
+
+// Bradford cone response matrix, for chromatic adaptation
+#[allow(clippy::excessive_precision)]
+const BRADFORD: [[f32; 3]; 3] = [
+	[ 0.8951,  0.2664, -0.1614],
+	[-0.7502,  1.7135,  0.0367],
+	[ 0.0389, -0.0685,  1.0296],
+];
+
+#[allow(clippy::excessive_precision)]
+const BRADFORD_INV: [[f32; 3]; 3] = [
+	[ 0.9869929055, -0.1470542564, 0.1599626517],
+	[ 0.4323052697,  0.5183602715, 0.0492912282],
+	[-0.0085286646,  0.0400428217, 0.9684866958],
+];
+
+// Range of the Planckian locus approximation (Kim et al.)
+const MIN_KELVIN: f32 = 1667.0;
+const MAX_KELVIN: f32 = 25000.0;
+
+// D50 white point (x 0.3457, y 0.3585) as XYZ
+#[allow(clippy::excessive_precision)]
+const D50_WHITE: [f32; 3] = [0.9642956764, 1.0, 0.8251046025];
+
+// CIE Lab constants, exact rationals as in CSS Color 4
+const LAB_KAPPA: f32 = 24389.0 / 27.0;
+const LAB_EPSILON: f32 = 216.0 / 24389.0;
+
+// Rec.2020 transfer function constants
+#[allow(clippy::excessive_precision)]
+const REC2020_ALPHA: f32 = 1.09929682680944;
+#[allow(clippy::excessive_precision)]
+const REC2020_BETA: f32 = 0.018053968510807;
+
+/// How OKLab/OKLCH values outside of the sRGB gamut are brought back into it
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum GamutMapping {
+	/// Clamp each linear RGB channel independently (fast, but shifts hue and lightness)
+	Clip,
+	/// Reduce OKLCH chroma until the clipped result is within a ΔE OK of 0.02 (CSS Color 4)
+	Css,
+}
+
+impl GamutMapping {
+	pub fn from_name( name: &str ) -> Option< GamutMapping > {
+		match name.to_lowercase().as_str() {
+			"clip" => Some( GamutMapping::Clip ),
+			"css" => Some( GamutMapping::Css ),
+			_ => None,
+		}
+	}
+}
+
+/// Bits per channel of the saved palette texture
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum BitDepth {
+	Eight,
+	Sixteen,
+}
+
+impl BitDepth {
+	pub fn from_bits( bits: u32 ) -> Option< BitDepth > {
+		match bits {
+			8 => Some( BitDepth::Eight ),
+			16 => Some( BitDepth::Sixteen ),
+			_ => None,
+		}
+	}
+}
+
+/// Transfer function of the stored 8 and 16 bit color values
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Encoding {
+	Srgb,
+	Linear,
+}
+
+impl Encoding {
+	pub fn from_name( name: &str ) -> Option< Encoding > {
+		match name.to_lowercase().as_str() {
+			"srgb" => Some( Encoding::Srgb ),
+			"linear" => Some( Encoding::Linear ),
+			_ => None,
+		}
+	}
+}
+
+/// File format of the palette texture
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum TextureFormat {
+	Png,
+	Tga,
+	Bmp,
+	/// Uncompressed DDS with DX10 header, sRGB tagged for 8 bit, linear for 16 bit and float
+	Dds,
+	/// Uncompressed KTX2, sRGB tagged for 8 bit, linear for 16 bit and float
+	Ktx2,
+	/// OpenEXR, linear float values
+	Exr,
+	/// Radiance RGBE, linear float values without alpha
+	Hdr,
+}
+
+impl TextureFormat {
+	pub fn from_name( name: &str ) -> Option< TextureFormat > {
+		match name.to_lowercase().as_str() {
+			"png" => Some( TextureFormat::Png ),
+			"tga" => Some( TextureFormat::Tga ),
+			"bmp" => Some( TextureFormat::Bmp ),
+			"dds" => Some( TextureFormat::Dds ),
+			"ktx2" => Some( TextureFormat::Ktx2 ),
+			"exr" => Some( TextureFormat::Exr ),
+			"hdr" => Some( TextureFormat::Hdr ),
+			_ => None,
+		}
+	}
+
+	/// Picks the format from the file extension, falling back to PNG
+	pub fn from_filename( filename: &str ) -> TextureFormat {
+		let extension = std::path::Path::new( filename )
+			.extension()
+			.map( |e| e.to_string_lossy().to_lowercase() )
+			.unwrap_or_default();
+
+		Self::from_name( &extension ).unwrap_or( TextureFormat::Png )
+	}
+
+	// Format for the image crate, DDS and KTX2 are handled by the container module
+	fn image_format( &self ) -> image::ImageFormat {
+		match self {
+			TextureFormat::Tga => image::ImageFormat::Tga,
+			TextureFormat::Bmp => image::ImageFormat::Bmp,
+			TextureFormat::Dds => image::ImageFormat::Dds,
+			TextureFormat::Exr => image::ImageFormat::OpenExr,
+			TextureFormat::Hdr => image::ImageFormat::Hdr,
+			TextureFormat::Png | TextureFormat::Ktx2 => image::ImageFormat::Png,
+		}
+	}
+
+	/// True if the format always stores linear float values, keeping values brighter than 1.0
+	pub fn is_hdr( &self ) -> bool {
+		matches!( self, TextureFormat::Exr | TextureFormat::Hdr )
+	}
+}
+
+/// Material scalars of a palette entry, written to companion textures with the same layout
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct Material {
+	pub roughness: f32,
+	pub metallic: f32,
+	/// Emission strength (0-1), e.g. a mask for emissive cells
+	pub emission: f32,
+}
+
+impl Default for Material {
+	fn default() -> Self {
+		Material {
+			roughness: 1.0,
+			metallic: 0.0,
+			emission: 0.0,
+		}
+	}
+}
+
+/// A single material scalar, e.g. to pack into the alpha channel
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum MaterialScalar {
+	Roughness,
+	Metallic,
+	Emission,
+}
+
+impl MaterialScalar {
+	pub fn from_name( name: &str ) -> Option< MaterialScalar > {
+		match name.to_lowercase().as_str() {
+			"roughness" => Some( MaterialScalar::Roughness ),
+			"metallic" => Some( MaterialScalar::Metallic ),
+			"emission" => Some( MaterialScalar::Emission ),
+			_ => None,
+		}
+	}
+}
+
+impl Material {
+	pub fn scalar( &self, scalar: MaterialScalar ) -> f32 {
+		match scalar {
+			MaterialScalar::Roughness => self.roughness,
+			MaterialScalar::Metallic => self.metallic,
+			MaterialScalar::Emission => self.emission,
+		}
+	}
+
+	pub fn set_scalar( &mut self, scalar: MaterialScalar, value: f32 ) {
+		match scalar {
+			MaterialScalar::Roughness => self.roughness = value,
+			MaterialScalar::Metallic => self.metallic = value,
+			MaterialScalar::Emission => self.emission = value,
+		}
+	}
+}
+
+/// How material scalars are written next to the palette texture
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum MaterialMaps {
+	/// One grayscale texture per scalar: <name>_roughness.png, <name>_metallic.png, <name>_emission.png
+	Separate,
+	/// Packed <name>_orm.png (R: occlusion, G: roughness, B: metallic) and <name>_emission.png
+	Orm,
+}
+
+impl MaterialMaps {
+	pub fn from_name( name: &str ) -> Option< MaterialMaps > {
+		match name.to_lowercase().as_str() {
+			"separate" => Some( MaterialMaps::Separate ),
+			"orm" => Some( MaterialMaps::Orm ),
+			_ => None,
+		}
+	}
+}
+
+/// Options for gradient generation
+#[derive(Debug,Copy,Clone)]
+pub struct GradientOptions {
+	pub gamut_mapping: GamutMapping,
+	/// Interpolate premultiplied instead of straight alpha (CSS Color 4, section 12.3)
+	pub premultiplied_alpha: bool,
+}
+
+impl Default for GradientOptions {
+	fn default() -> Self {
+		GradientOptions {
+			gamut_mapping: GamutMapping::Clip,
+			premultiplied_alpha: false,
+		}
+	}
+}
+
+/// Color difference metric
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum DeltaE {
+	/// Euclidean distance in OKLab, 0.02 is about a just noticeable difference
+	Ok,
+	/// Euclidean distance in CIE Lab, 2.3 is about a just noticeable difference
+	Cie76,
+	/// CIE94 with graphic arts weights, asymmetric: the first color is the reference
+	Cie94,
+	/// CIEDE2000
+	Ciede2000,
+}
+
+impl DeltaE {
+	pub fn from_name( name: &str ) -> Option< DeltaE > {
+		match name.to_lowercase().as_str() {
+			"ok" => Some( DeltaE::Ok ),
+			"cie76" => Some( DeltaE::Cie76 ),
+			"cie94" => Some( DeltaE::Cie94 ),
+			"ciede2000" => Some( DeltaE::Ciede2000 ),
+			_ => None,
+		}
+	}
+}
+
+/// Order of the entries after sorting
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum SortKey {
+	/// OKLCH hue, gray entries first
+	Hue,
+	/// OKLab lightness
+	Lightness,
+	/// OKLCH chroma
+	Chroma,
+	/// Gray entries first, then hue in 30° steps and lightness within each step
+	Oklch,
+	/// Relative luminance (linear Rec. 709 weights)
+	Luminance,
+	/// A path through all entries, from the darkest one, with small ΔE OK between neighbours
+	Path,
+}
+
+impl SortKey {
+	pub fn from_name( name: &str ) -> Option< SortKey > {
+		match name.to_lowercase().as_str() {
+			"hue" => Some( SortKey::Hue ),
+			"lightness" => Some( SortKey::Lightness ),
+			"chroma" => Some( SortKey::Chroma ),
+			"oklch" => Some( SortKey::Oklch ),
+			"luminance" => Some( SortKey::Luminance ),
+			"path" => Some( SortKey::Path ),
+			_ => None,
+		}
+	}
+}
+
+/// How entries are arranged in the texture
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Layout {
+	/// Row by row in entry order
+	Compact,
+	/// Each group on its own row(s), ungrouped entries fill the holes
+	Rows,
+	/// The texture keeps its size and entries keep their cells, new entries fill free cells
+	Fixed,
+}
+
+impl Layout {
+	pub fn from_name( name: &str ) -> Option< Layout > {
+		match name.to_lowercase().as_str() {
+			"compact" => Some( Layout::Compact ),
+			"rows" => Some( Layout::Rows ),
+			"fixed" => Some( Layout::Fixed ),
+			_ => None,
+		}
+	}
+}
+
+/// A palette entry that could not be represented exactly in sRGB
+#[derive(Debug,Copy,Clone)]
+pub struct OutOfGamut {
+	/// Index as returned by the add_* methods
+	pub index: usize,
+	/// ΔE OK between the requested and the stored color
+	pub delta_e: f32,
+}
+
+/// A palette entry close to a queried color
+#[derive(Debug,Copy,Clone)]
+pub struct NearestEntry {
+	/// Index as returned by the add_* methods
+	pub index: usize,
+	/// Difference between the queried and the entry color
+	pub delta_e: f32,
+}
+
+/// Why a color string could not be parsed
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum ColorParseError {
+	/// Not a known color name, hex value or legacy rgb()/hsl() color
+	UnknownColor( String ),
+	/// Something like "name(...)" was expected
+	InvalidSyntax( String ),
+	/// The color space passed to color() is not supported
+	UnknownColorSpace( String ),
+	/// The function got too few or too many components
+	ComponentCount { function: String, expected: usize, found: usize },
+	/// A component is not a valid value at its position, e.g. a percentage for a hue
+	InvalidComponent { function: String, position: usize, value: String, expected: &'static str },
+	/// An expression function like mix() got too few or too many arguments
+	ArgumentCount { function: String, expected: &'static str, found: usize },
+	/// An argument of an expression function is not valid at its position
+	InvalidArgument { function: String, position: usize, value: String, expected: &'static str },
+	/// A reference like @12 to a palette entry that doesn't exist
+	InvalidReference { reference: String, entries: usize },
+	/// A color temperature like 3200K outside of the supported range
+	InvalidTemperature( String ),
+	/// Expression functions nested deeper than the given number of levels
+	NestingTooDeep( usize ),
+}
+
+impl std::fmt::Display for ColorParseError {
+	fn fmt( &self, f: &mut std::fmt::Formatter ) -> std::fmt::Result {
+		match self {
+			ColorParseError::UnknownColor( color ) => write!( f, "unknown color '{}'", color ),
+			ColorParseError::InvalidSyntax( color ) => write!( f, "invalid syntax '{}', expected e.g. oklch(70% 0.15 140)", color ),
+			ColorParseError::UnknownColorSpace( space ) => write!( f, "unknown color space '{}' in color(), expected srgb, srgb-linear, display-p3 or rec2020", space ),
+			ColorParseError::ComponentCount { function, expected, found } => write!( f, "{}() expects {} components, found {}", function, expected, found ),
+			ColorParseError::InvalidComponent { function, position, value, expected } => write!( f, "{}() component {} '{}' is not {}", function, position, value, expected ),
+			ColorParseError::ArgumentCount { function, expected, found } => write!( f, "{}() expects {} arguments, found {}", function, expected, found ),
+			ColorParseError::InvalidArgument { function, position, value, expected } => write!( f, "{}() argument {} '{}' is not {}", function, position, value, expected ),
+			ColorParseError::InvalidReference { reference, entries } => write!( f, "'{}' does not refer to one of the {} palette entries", reference, entries ),
+			ColorParseError::InvalidTemperature( temperature ) => write!( f, "temperature '{}' is outside of {}K-{}K", temperature, MIN_KELVIN, MAX_KELVIN ),
+			ColorParseError::NestingTooDeep( max_depth ) => write!( f, "expressions can be nested at most {} levels deep", max_depth ),
+		}
+	}
+}
+
+impl std::error::Error for ColorParseError {}
+
+// What a color function component may be, and what 100% stands for
+#[derive(Debug,Copy,Clone)]
+pub(crate) enum Component {
+	/// Number or percentage of the given reference value
+	Number( f32 ),
+	/// Number (degrees) or angle with deg, rad, grad or turn unit
+	Hue,
+	/// Only a percentage or a number in the range the percentage maps to (hwb whiteness/blackness)
+	Percentage,
+}
+
 #[derive(Debug,Copy,Clone)]
 pub struct Color {
 	rgba: [f32;4],
@@ -61,6 +548,19 @@ impl From<&image::Rgba<u8>> for Color {
 	}
 }
 
+impl From<&image::Rgba<u16>> for Color {
+	fn from(src: &image::Rgba<u16> ) -> Self {
+		Color {
+			rgba: [
+				( src[ 0 ] as f32 ) / 65535.0,
+				( src[ 1 ] as f32 ) / 65535.0,
+				( src[ 2 ] as f32 ) / 65535.0,
+				( src[ 3 ] as f32 ) / 65535.0,
+			],
+		}
+	}
+}
+
 impl std::ops::Sub for Color {
 	type Output = Color;
 	fn sub(self, other: Color) -> <Self as std::ops::Sub<Color>>::Output {
@@ -105,19 +605,99 @@ impl std::ops::AddAssign for Color {
 }
 
 impl Color {
+	// Round to nearest, so e.g. 0.999 becomes 255 and not 254
+	fn quantize_u8( c: f32 ) -> u8 {
+		( c.clamp( 0.0, 1.0 ) * 255.0 ).round() as u8
+	}
+
 	pub fn rgba_u8(&self) -> [u8;4] {
 		[
-			( self.rgba[ 0 ] * 255.0 ) as u8,
-			( self.rgba[ 1 ] * 255.0 ) as u8,
-			( self.rgba[ 2 ] * 255.0 ) as u8,
-			( self.rgba[ 3 ] * 255.0 ) as u8,
+			Self::quantize_u8( self.rgba[ 0 ] ),
+			Self::quantize_u8( self.rgba[ 1 ] ),
+			Self::quantize_u8( self.rgba[ 2 ] ),
+			Self::quantize_u8( self.rgba[ 3 ] ),
+		]
+	}
+
+	fn quantize_u16( c: f32 ) -> u16 {
+		( c.clamp( 0.0, 1.0 ) * 65535.0 ).round() as u16
+	}
+
+	pub fn rgba_u16(&self) -> [u16;4] {
+		[
+			Self::quantize_u16( self.rgba[ 0 ] ),
+			Self::quantize_u16( self.rgba[ 1 ] ),
+			Self::quantize_u16( self.rgba[ 2 ] ),
+			Self::quantize_u16( self.rgba[ 3 ] ),
 		]
 	}
 
+	/// ΔE OK introduced by storing the color with the given bits per channel
+	pub fn quantization_error(&self, bit_depth: BitDepth) -> f32 {
+		let quantized: Color = match bit_depth {
+			BitDepth::Eight => self.rgba_u8().into(),
+			BitDepth::Sixteen => ( &image::Rgba( self.rgba_u16() ) ).into(),
+		};
+		Self::delta_e_ok( self.to_oklab(), quantized.to_oklab() )
+	}
+
 	pub fn rgba(&self) -> [f32;4] {
 		self.rgba
 	}
 
+	pub fn alpha(&self) -> f32 {
+		self.rgba[ 3 ]
+	}
+
+	pub fn with_alpha(&self, alpha: f32) -> Color {
+		Color {
+			rgba: [ self.rgba[ 0 ], self.rgba[ 1 ], self.rgba[ 2 ], alpha ],
+		}
+	}
+
+	/// Creates a color from linear RGB, values above 1.0 are kept for HDR entries
+	pub fn from_linear( rgb: [f32; 3], alpha: f32 ) -> Color {
+		Color {
+			rgba: [
+				Self::linear_to_srgb( rgb[ 0 ] ),
+				Self::linear_to_srgb( rgb[ 1 ] ),
+				Self::linear_to_srgb( rgb[ 2 ] ),
+				alpha,
+			],
+		}
+	}
+
+	/// Creates a color from sRGB encoded values (0-1)
+	pub fn from_srgb( rgb: [f32; 3], alpha: f32 ) -> Color {
+		Color {
+			rgba: [ rgb[ 0 ], rgb[ 1 ], rgb[ 2 ], alpha ],
+		}
+	}
+
+	/// Linear RGB and alpha, not clamped
+	pub fn rgba_linear( &self ) -> [f32; 4] {
+		[
+			Self::srgb_to_linear( self.rgba[ 0 ] ),
+			Self::srgb_to_linear( self.rgba[ 1 ] ),
+			Self::srgb_to_linear( self.rgba[ 2 ] ),
+			self.rgba[ 3 ],
+		]
+	}
+
+	/// Scales the linear RGB values, e.g. for emissive entries
+	pub fn with_intensity( &self, intensity: f32 ) -> Color {
+		let linear = self.rgba_linear();
+		Self::from_linear(
+			[ linear[ 0 ] * intensity, linear[ 1 ] * intensity, linear[ 2 ] * intensity ],
+			linear[ 3 ],
+		)
+	}
+
+	/// True if any channel is brighter than what fits into a regular texture
+	pub fn is_hdr( &self ) -> bool {
+		self.rgba[ 0..3 ].iter().any( |&c| c > 1.0 + HDR_EPSILON )
+	}
+
 	pub fn is_empty( &self ) -> bool {
 		self.rgba == [0f32;4]
 	}
@@ -149,6 +729,17 @@ impl Color {
 		]
 	}
 
+	// Matrix multiplication helper: 3x3 matrix * 3x3 matrix
+	fn matrix_mul_matrix(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+		let mut result = [[0.0; 3]; 3];
+		for (r, row) in result.iter_mut().enumerate() {
+			for (c, value) in row.iter_mut().enumerate() {
+				*value = a[r][0] * b[0][c] + a[r][1] * b[1][c] + a[r][2] * b[2][c];
+			}
+		}
+		result
+	}
+
 	// Convert sRGB (0-1) to OKLab
 	pub fn to_oklab(&self) -> [f32; 3] {
 		// 1. sRGB to linear RGB
@@ -168,8 +759,8 @@ impl Color {
 		Self::matrix_mul_3x3(&M2, [l_prime, m_prime, s_prime])
 	}
 
-	// Create Color from OKLab (L, a, b)
-	pub fn from_oklab(lab: [f32; 3]) -> Self {
+	// Convert OKLab to linear sRGB, without clamping
+	pub(crate) fn oklab_to_linear_srgb(lab: [f32; 3]) -> [f32; 3] {
 		// 1. OKLab to L'M'S'
 		let lms_prime = Self::matrix_mul_3x3(&M2_INV, lab);
 
@@ -179,19 +770,190 @@ impl Color {
 		let s = lms_prime[2].powi(3);
 
 		// 3. LMS to linear RGB
-		let rgb_lin = Self::matrix_mul_3x3(&M1_INV, [l, m, s]);
+		Self::matrix_mul_3x3(&M1_INV, [l, m, s])
+	}
+
+	// True if the OKLab value can be represented in sRGB without clamping
+	pub fn oklab_in_gamut(lab: [f32; 3]) -> bool {
+		Self::oklab_to_linear_srgb(lab)
+			.iter()
+			.all(|&c| (-GAMUT_EPSILON..=1.0 + GAMUT_EPSILON).contains(&c))
+	}
+
+	/// Difference between two colors with the given metric
+	pub fn delta_e( &self, other: &Color, method: DeltaE ) -> f32 {
+		match method {
+			DeltaE::Ok => Self::delta_e_ok( self.to_oklab(), other.to_oklab() ),
+			DeltaE::Cie76 => Self::delta_e_cie76( self.to_lab(), other.to_lab() ),
+			DeltaE::Cie94 => Self::delta_e_cie94( self.to_lab(), other.to_lab() ),
+			DeltaE::Ciede2000 => Self::delta_e_ciede2000( self.to_lab(), other.to_lab() ),
+		}
+	}
+
+	/// Euclidean distance in OKLab (ΔE OK)
+	pub fn delta_e_ok(lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+		let dl = lab1[0] - lab2[0];
+		let da = lab1[1] - lab2[1];
+		let db = lab1[2] - lab2[2];
+		(dl * dl + da * da + db * db).sqrt()
+	}
+
+	/// Euclidean distance in CIE Lab (ΔE*ab 1976)
+	pub fn delta_e_cie76(lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+		Self::delta_e_ok(lab1, lab2)
+	}
+
+	/// CIE94 with graphic arts weights (kL = 1, K1 = 0.045, K2 = 0.015), lab1 is the reference
+	pub fn delta_e_cie94(lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+		let [l1, a1, b1] = lab1.map(|v| v as f64);
+		let [l2, a2, b2] = lab2.map(|v| v as f64);
+
+		let c1 = (a1 * a1 + b1 * b1).sqrt();
+		let c2 = (a2 * a2 + b2 * b2).sqrt();
+		let dl = l1 - l2;
+		let dc = c1 - c2;
+		let da = a1 - a2;
+		let db = b1 - b2;
+		// ΔH² can end up slightly negative from float error
+		let dh2 = (da * da + db * db - dc * dc).max(0.0);
+
+		let sc = 1.0 + 0.045 * c1;
+		let sh = 1.0 + 0.015 * c1;
+		(dl * dl + (dc / sc).powi(2) + dh2 / (sh * sh)).sqrt() as f32
+	}
+
+	/// CIEDE2000 (kL = kC = kH = 1), following Sharma, Wu and Dalal (2005)
+	pub fn delta_e_ciede2000(lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+		let [l1, a1, b1] = lab1.map(|v| v as f64);
+		let [l2, a2, b2] = lab2.map(|v| v as f64);
+
+		// a' with the G correction for neutral colors
+		let c_mean = ((a1 * a1 + b1 * b1).sqrt() + (a2 * a2 + b2 * b2).sqrt()) / 2.0;
+		let c_mean7 = c_mean.powi(7);
+		let g = 0.5 * (1.0 - (c_mean7 / (c_mean7 + 25f64.powi(7))).sqrt());
+		let a1p = a1 * (1.0 + g);
+		let a2p = a2 * (1.0 + g);
+
+		let c1p = (a1p * a1p + b1 * b1).sqrt();
+		let c2p = (a2p * a2p + b2 * b2).sqrt();
+		let hue = |b: f64, a: f64| if b == 0.0 && a == 0.0 { 0.0 } else { b.atan2(a).to_degrees().rem_euclid(360.0) };
+		let h1p = hue(b1, a1p);
+		let h2p = hue(b2, a2p);
+
+		let dlp = l2 - l1;
+		let dcp = c2p - c1p;
+		let dhp = if c1p * c2p == 0.0 {
+			0.0
+		} else if (h2p - h1p).abs() <= 180.0 {
+			h2p - h1p
+		} else if h2p - h1p > 180.0 {
+			h2p - h1p - 360.0
+		} else {
+			h2p - h1p + 360.0
+		};
+		let dhp_big = 2.0 * (c1p * c2p).sqrt() * (dhp / 2.0).to_radians().sin();
+
+		let lp_mean = (l1 + l2) / 2.0;
+		let cp_mean = (c1p + c2p) / 2.0;
+		let hp_mean = if c1p * c2p == 0.0 {
+			h1p + h2p
+		} else if (h1p - h2p).abs() <= 180.0 {
+			(h1p + h2p) / 2.0
+		} else if h1p + h2p < 360.0 {
+			(h1p + h2p + 360.0) / 2.0
+		} else {
+			(h1p + h2p - 360.0) / 2.0
+		};
+
+		let t = 1.0 - 0.17 * (hp_mean - 30.0).to_radians().cos()
+			+ 0.24 * (2.0 * hp_mean).to_radians().cos()
+			+ 0.32 * (3.0 * hp_mean + 6.0).to_radians().cos()
+			- 0.20 * (4.0 * hp_mean - 63.0).to_radians().cos();
+		let d_theta = 30.0 * (-((hp_mean - 275.0) / 25.0).powi(2)).exp();
+		let cp_mean7 = cp_mean.powi(7);
+		let rc = 2.0 * (cp_mean7 / (cp_mean7 + 25f64.powi(7))).sqrt();
+		let sl = 1.0 + 0.015 * (lp_mean - 50.0).powi(2) / (20.0 + (lp_mean - 50.0).powi(2)).sqrt();
+		let sc = 1.0 + 0.045 * cp_mean;
+		let sh = 1.0 + 0.015 * cp_mean * t;
+		let rt = -(2.0 * d_theta).to_radians().sin() * rc;
+
+		let l = dlp / sl;
+		let c = dcp / sc;
+		let h = dhp_big / sh;
+		(l * l + c * c + h * h + rt * c * h).sqrt() as f32
+	}
 
-		// 4. Linear RGB to sRGB with clamping
+	// Create Color from OKLab (L, a, b)
+	pub fn from_oklab(lab: [f32; 3]) -> Self {
+		let rgb_lin = Self::oklab_to_linear_srgb(lab);
+
+		// Linear RGB to sRGB with clamping
 		Color {
 			rgba: [
-				Self::linear_to_srgb(rgb_lin[0].max(0.0).min(1.0)),
-				Self::linear_to_srgb(rgb_lin[1].max(0.0).min(1.0)),
-				Self::linear_to_srgb(rgb_lin[2].max(0.0).min(1.0)),
+				Self::linear_to_srgb(rgb_lin[0].clamp(0.0, 1.0)),
+				Self::linear_to_srgb(rgb_lin[1].clamp(0.0, 1.0)),
+				Self::linear_to_srgb(rgb_lin[2].clamp(0.0, 1.0)),
 				1.0, // full alpha
 			],
 		}
 	}
 
+	// Create Color from OKLab, bringing out of gamut values into sRGB with the given method
+	pub fn from_oklab_mapped(lab: [f32; 3], gamut_mapping: GamutMapping) -> Self {
+		match gamut_mapping {
+			GamutMapping::Clip => Self::from_oklab(lab),
+			GamutMapping::Css => Self::from_oklab_css_mapped(lab),
+		}
+	}
+
+	// CSS Color 4 gamut mapping (binary search on OKLCH chroma, section 13.2)
+	fn from_oklab_css_mapped(lab: [f32; 3]) -> Self {
+		let lch = Self::oklab_to_oklch(lab);
+
+		if lch[0] >= 1.0 {
+			return Color{ rgba: [ 1.0, 1.0, 1.0, 1.0 ] };
+		}
+		if lch[0] <= 0.0 {
+			return Color{ rgba: [ 0.0, 0.0, 0.0, 1.0 ] };
+		}
+		if Self::oklab_in_gamut(lab) {
+			return Self::from_oklab(lab);
+		}
+
+		let mut clipped = Self::from_oklab(lab);
+		if Self::delta_e_ok(clipped.to_oklab(), lab) < GAMUT_MAPPING_JND {
+			return clipped;
+		}
+
+		let mut min = 0.0;
+		let mut max = lch[1];
+		let mut min_in_gamut = true;
+
+		while max - min > GAMUT_MAPPING_EPSILON {
+			let chroma = (min + max) * 0.5;
+			let current = Self::oklch_to_oklab([lch[0], chroma, lch[2]]);
+
+			if min_in_gamut && Self::oklab_in_gamut(current) {
+				min = chroma;
+				continue;
+			}
+
+			clipped = Self::from_oklab(current);
+			let e = Self::delta_e_ok(clipped.to_oklab(), current);
+			if e < GAMUT_MAPPING_JND {
+				if GAMUT_MAPPING_JND - e < GAMUT_MAPPING_EPSILON {
+					return clipped;
+				}
+				min_in_gamut = false;
+				min = chroma;
+			} else {
+				max = chroma;
+			}
+		}
+
+		clipped
+	}
+
 	// Convert OKLab to OKLCH
 	pub fn oklab_to_oklch(lab: [f32; 3]) -> [f32; 3] {
 		let l = lab[0];
@@ -217,95 +979,999 @@ impl Color {
 		Self::oklab_to_oklch(self.to_oklab())
 	}
 
+	// True if the color is (close to) black, white, or gray, i.e. its OKLCH hue is powerless
+	pub fn is_achromatic(&self) -> bool {
+		self.to_oklch()[1] < ACHROMATIC_CHROMA
+	}
+
+	// Replace powerless hues before interpolation (CSS Color 4, section 12.4)
+	// An achromatic endpoint adopts the hue of the other endpoint,
+	// so e.g. white -> blue stays on blue's hue instead of swinging through random ones
+	pub fn fix_missing_hues(start_lch: [f32; 3], end_lch: [f32; 3]) -> ([f32; 3], [f32; 3]) {
+		let start_achromatic = start_lch[1] < ACHROMATIC_CHROMA;
+		let end_achromatic = end_lch[1] < ACHROMATIC_CHROMA;
+
+		match (start_achromatic, end_achromatic) {
+			(true, false) => ([start_lch[0], start_lch[1], end_lch[2]], end_lch),
+			(false, true) => (start_lch, [end_lch[0], end_lch[1], start_lch[2]]),
+			(true, true) => ([start_lch[0], start_lch[1], 0.0], [end_lch[0], end_lch[1], 0.0]),
+			(false, false) => (start_lch, end_lch),
+		}
+	}
+
 	// Create Color from OKLCH
 	pub fn from_oklch(lch: [f32; 3]) -> Self {
 		Self::from_oklab(Self::oklch_to_oklab(lch))
 	}
 
-	pub fn from_string( color_string: &str ) -> Option< Color > {
-		match color_string.parse() as Result<css_color::Rgba, css_color::ParseColorError> {
-			Err( _e ) => None,
-			Ok( css_color ) => {
-//				dbg!(&css_color);
-
-				// :TODO: improve
-				let r = ( 255.0 * css_color.red as f32 ) as u8;
-				let g = ( 255.0 * css_color.green as f32 ) as u8;
-				let b = ( 255.0 * css_color.blue as f32 ) as u8;
-				let a = ( 255.0 * css_color.alpha as f32 ) as u8;
-
-				let color = [ r, g, b, a ].into();
-				Some( color )
-			},
-		}
+	/// Display P3 color with the sRGB transfer function, may end up outside of the sRGB gamut
+	pub fn from_display_p3( rgb: [f32; 3], alpha: f32 ) -> Color {
+		let linear = rgb.map( Self::srgb_to_linear_extended );
+		Self::from_linear( Self::matrix_mul_3x3( &P3_TO_SRGB, linear ), alpha )
 	}
-}
 
-/// Round up to the next power of 2
-fn round_up_to_power_of_2(n: u32) -> u32 {
-	if n == 0 {
-		return 1;
+	/// Display P3 values and alpha, not clamped
+	pub fn to_display_p3( &self ) -> [f32; 4] {
+		let p3 = Self::matrix_mul_3x3( &SRGB_TO_P3, self.rgb_linear() );
+		let p3 = p3.map( Self::linear_to_srgb_extended );
+		[ p3[ 0 ], p3[ 1 ], p3[ 2 ], self.rgba[ 3 ] ]
 	}
-	if n.is_power_of_two() {
-		return n;
+
+	/// Rec.2020 color with the Rec.2020 transfer function, may end up outside of the sRGB gamut
+	pub fn from_rec2020( rgb: [f32; 3], alpha: f32 ) -> Color {
+		let linear = rgb.map( Self::rec2020_to_linear );
+		Self::from_linear( Self::matrix_mul_3x3( &REC2020_TO_SRGB, linear ), alpha )
 	}
-	n.next_power_of_two()
-}
 
-#[derive(Debug)]
-pub struct LowTexPal {
-	filename: String,
-	was_modified: bool,
-	colors: Vec<Color>,
-	min_width: Option<u32>,
-}
+	/// Rec.2020 values and alpha, not clamped
+	pub fn to_rec2020( &self ) -> [f32; 4] {
+		let rec2020 = Self::matrix_mul_3x3( &SRGB_TO_REC2020, self.rgb_linear() );
+		let rec2020 = rec2020.map( Self::linear_to_rec2020 );
+		[ rec2020[ 0 ], rec2020[ 1 ], rec2020[ 2 ], self.rgba[ 3 ] ]
+	}
 
-impl LowTexPal {
+	/// True if the color is within the sRGB gamut, ignoring intensity above 1.0
+	pub fn in_srgb_gamut( &self ) -> bool {
+		let linear = self.rgba_linear();
+		let max = linear[ 0..3 ].iter().fold( 1.0f32, |m, &c| m.max( c ) );
+		linear[ 0..3 ].iter().all( |&c| c / max >= -GAMUT_EPSILON )
+	}
 
-	pub fn new(
-		filename: &str,
-		min_width: Option<u32>,
+	/// Brings the color into the sRGB gamut, keeping intensity above 1.0 if `keep_hdr` is set
+	pub fn to_srgb_gamut( &self, gamut_mapping: GamutMapping, keep_hdr: bool ) -> Color {
+		let linear = self.rgba_linear();
+		let intensity = if keep_hdr {
+			linear[ 0..3 ].iter().fold( 1.0f32, |m, &c| m.max( c ) )
+		} else {
+			1.0
+		};
+		let scaled = [ linear[ 0 ] / intensity, linear[ 1 ] / intensity, linear[ 2 ] / intensity ];
+		if scaled.iter().all( |&c| ( 0.0..=1.0 ).contains( &c ) ) {
+			return *self;
+		}
+
+		let mapped = match gamut_mapping {
+			GamutMapping::Clip => scaled.map( |c| c.clamp( 0.0, 1.0 ) ),
+			GamutMapping::Css => {
+				let rgba = Self::from_oklab_mapped( Color::from_linear( scaled, 1.0 ).to_oklab(), gamut_mapping ).rgba_linear();
+				[ rgba[ 0 ], rgba[ 1 ], rgba[ 2 ] ]
+			},
+		};
+		Color::from_linear( mapped.map( |c| c * intensity ), linear[ 3 ] )
+	}
+
+	fn rgb_linear( &self ) -> [f32; 3] {
+		let linear = self.rgba_linear();
+		[ linear[ 0 ], linear[ 1 ], linear[ 2 ] ]
+	}
+
+	// The sRGB transfer function mirrored for negative values, as used for Display P3 in CSS
+	fn srgb_to_linear_extended( c: f32 ) -> f32 {
+		c.signum() * Self::srgb_to_linear( c.abs() )
+	}
+
+	fn linear_to_srgb_extended( c: f32 ) -> f32 {
+		c.signum() * Self::linear_to_srgb( c.abs() )
+	}
+
+	fn rec2020_to_linear( c: f32 ) -> f32 {
+		if c.abs() < REC2020_BETA * 4.5 {
+			c / 4.5
+		} else {
+			c.signum() * ( ( c.abs() + REC2020_ALPHA - 1.0 ) / REC2020_ALPHA ).powf( 1.0 / 0.45 )
+		}
+	}
+
+	fn linear_to_rec2020( c: f32 ) -> f32 {
+		if c.abs() > REC2020_BETA {
+			c.signum() * ( REC2020_ALPHA * c.abs().powf( 0.45 ) - ( REC2020_ALPHA - 1.0 ) )
+		} else {
+			c * 4.5
+		}
+	}
+
+	pub fn from_string( color_string: &str ) -> Option< Color > {
+		Self::parse( color_string ).ok()
+	}
+
+	/// Parses CSS colors: names, hex, rgb(), hsl(), hwb(), lab(), lch(), oklab(), oklch() and color()
+	pub fn parse( color_string: &str ) -> Result< Color, ColorParseError > {
+		let trimmed = color_string.trim();
+		let lower = trimmed.to_ascii_lowercase();
+
+		if let Some( kelvin ) = Self::parse_kelvin( &lower ) {
+			return Self::from_kelvin( kelvin ).ok_or_else( || ColorParseError::InvalidTemperature( trimmed.to_string() ) );
+		}
+
+		let function = lower.split_once( '(' ).map( |( name, _ )| name.trim() );
+		match function {
+			Some( "oklab" ) | Some( "oklch" ) | Some( "lab" ) | Some( "lch" ) | Some( "hwb" ) | Some( "color" ) => Self::parse_function( trimmed, &lower ),
+			_ => match lower.parse() as Result<css_color::Rgba, css_color::ParseColorError> {
+				Err( _e ) => Err( ColorParseError::UnknownColor( trimmed.to_string() ) ),
+				Ok( css_color ) => {
+//					dbg!(&css_color);
+
+					// Keep full precision, quantization happens on save
+					Ok( Color {
+						rgba: [ css_color.red, css_color.green, css_color.blue, css_color.alpha ],
+					} )
+				},
+			},
+		}
+	}
+
+	// CSS Color 4 functions with space separated components and an optional "/ alpha",
+	// e.g. "oklch(70% 0.15 140 / 50%)" or "color(display-p3 1 0.5 0)"
+	fn parse_function( original: &str, lower: &str ) -> Result< Color, ColorParseError > {
+		let invalid_syntax = || ColorParseError::InvalidSyntax( original.to_string() );
+		let ( name, rest ) = lower.split_once( '(' ).ok_or_else( invalid_syntax )?;
+		let inner = rest.trim_end().strip_suffix( ')' ).ok_or_else( invalid_syntax )?;
+		if inner.contains( '(' ) || inner.contains( ')' ) {
+			return Err( invalid_syntax() );
+		}
+		let name = name.trim();
+
+		let ( components, alpha ) = match inner.split_once( '/' ) {
+			Some( ( components, alpha ) ) => ( components, Some( alpha.trim() ) ),
+			None => ( inner, None ),
+		};
+
+		let mut tokens: Vec< &str > = components.split_whitespace().collect();
+		let space = if name == "color" {
+			if tokens.is_empty() {
+				return Err( invalid_syntax() );
+			}
+			Some( tokens.remove( 0 ) )
+		} else {
+			None
+		};
+
+		// Component kinds, percentages as in CSS Color 4
+		let kinds = match ( name, space ) {
+			( "oklab", _ ) => [ Component::Number( 1.0 ), Component::Number( 0.4 ), Component::Number( 0.4 ) ],
+			( "oklch", _ ) => [ Component::Number( 1.0 ), Component::Number( 0.4 ), Component::Hue ],
+			( "lab", _ ) => [ Component::Number( 100.0 ), Component::Number( 125.0 ), Component::Number( 125.0 ) ],
+			( "lch", _ ) => [ Component::Number( 100.0 ), Component::Number( 150.0 ), Component::Hue ],
+			( "hwb", _ ) => [ Component::Hue, Component::Percentage, Component::Percentage ],
+			( _, Some( "srgb" ) ) | ( _, Some( "srgb-linear" ) ) | ( _, Some( "display-p3" ) ) | ( _, Some( "rec2020" ) ) => [ Component::Number( 1.0 ); 3 ],
+			( _, Some( space ) ) => return Err( ColorParseError::UnknownColorSpace( space.to_string() ) ),
+			_ => return Err( invalid_syntax() ),
+		};
+
+		if tokens.len() != kinds.len() {
+			return Err( ColorParseError::ComponentCount {
+				function: name.to_string(),
+				expected: kinds.len(),
+				found: tokens.len(),
+			} );
+		}
+
+		let mut values = [ 0.0f32; 3 ];
+		for ( i, ( token, kind ) ) in tokens.iter().zip( kinds.iter() ).enumerate() {
+			values[ i ] = Self::parse_component( token, *kind ).ok_or_else( || ColorParseError::InvalidComponent {
+				function: name.to_string(),
+				position: i + 1,
+				value: token.to_string(),
+				expected: match kind {
+					Component::Number( _ ) => "a number or percentage",
+					Component::Hue => "a hue (number or angle)",
+					Component::Percentage => "a percentage",
+				},
+			} )?;
+		}
+		let alpha = match alpha {
+			Some( alpha ) => Self::parse_component( alpha, Component::Number( 1.0 ) )
+				.ok_or_else( || ColorParseError::InvalidComponent {
+					function: name.to_string(),
+					position: 4,
+					value: alpha.to_string(),
+					expected: "a valid alpha (number or percentage)",
+				} )?
+				.clamp( 0.0, 1.0 ),
+			None => 1.0,
+		};
+
+		let [ x, y, z ] = values;
+		Ok( match ( name, space ) {
+			( "oklab", _ ) => Color::from_linear( Self::oklab_to_linear_srgb( [ x, y, z ] ), alpha ),
+			( "oklch", _ ) => Color::from_linear( Self::oklab_to_linear_srgb( Self::oklch_to_oklab( [ x, y, z.to_radians() ] ) ), alpha ),
+			( "lab", _ ) => Color::from_lab( [ x, y, z ] ).with_alpha( alpha ),
+			// Same polar form as OKLCH
+			( "lch", _ ) => Color::from_lab( Self::oklch_to_oklab( [ x, y, z.to_radians() ] ) ).with_alpha( alpha ),
+			( "hwb", _ ) => Color::from_hwb( x, y, z ).with_alpha( alpha ),
+			( _, Some( "srgb-linear" ) ) => Color::from_linear( [ x, y, z ], alpha ),
+			( _, Some( "display-p3" ) ) => Color::from_display_p3( [ x, y, z ], alpha ),
+			( _, Some( "rec2020" ) ) => Color::from_rec2020( [ x, y, z ], alpha ),
+			_ => Color { rgba: [ x, y, z, alpha ] },
+		} )
+	}
+
+	// A number, a percentage, an angle for hues, or none (0.0)
+	pub(crate) fn parse_component( value: &str, kind: Component ) -> Option< f32 > {
+		if value == "none" {
+			return Some( 0.0 );
+		}
+		let number = |v: &str| v.parse::< f32 >().ok().filter( |v| v.is_finite() );
+
+		match kind {
+			Component::Number( reference ) => match value.strip_suffix( '%' ) {
+				Some( percent ) => number( percent ).map( |p| p / 100.0 * reference ),
+				None => number( value ),
+			},
+			Component::Percentage => match value.strip_suffix( '%' ) {
+				Some( percent ) => number( percent ).map( |p| p / 100.0 ),
+				None => number( value ).map( |v| v / 100.0 ),
+			},
+			Component::Hue => {
+				// "grad" has to be checked before "rad"
+				let units = [ ( "deg", 1.0 ), ( "grad", 0.9 ), ( "rad", 180.0 / std::f32::consts::PI ), ( "turn", 360.0 ) ];
+				for ( unit, degrees ) in units {
+					if let Some( angle ) = value.strip_suffix( unit ) {
+						return number( angle ).map( |a| a * degrees );
+					}
+				}
+				number( value )
+			},
+		}
+	}
+
+	/// Color temperature in Kelvin, e.g. "3200K"
+	pub fn parse_kelvin( value: &str ) -> Option< f32 > {
+		let number = value.trim().strip_suffix( ['K', 'k'] )?;
+		number.parse::< f32 >().ok().filter( |k| k.is_finite() )
+	}
+
+	/// Chromaticity of a blackbody at the given temperature as XYZ with Y = 1
+	/// Uses the Planckian locus approximation by Kim et al., valid from 1667K to 25000K.
+	pub fn kelvin_to_xyz( kelvin: f32 ) -> Option< [f32; 3] > {
+		if !( MIN_KELVIN..=MAX_KELVIN ).contains( &kelvin ) {
+			return None;
+		}
+
+		let t = kelvin as f64;
+		let x = if t <= 4000.0 {
+			-0.2661239e9 / ( t * t * t ) - 0.2343589e6 / ( t * t ) + 0.8776956e3 / t + 0.179910
+		} else {
+			-3.0258469e9 / ( t * t * t ) + 2.1070379e6 / ( t * t ) + 0.2226347e3 / t + 0.240390
+		};
+		let y = if t <= 2222.0 {
+			-1.1063814 * x * x * x - 1.34811020 * x * x + 2.18555832 * x - 0.20219683
+		} else if t <= 4000.0 {
+			-0.9549476 * x * x * x - 1.37418593 * x * x + 2.09137015 * x - 0.16748867
+		} else {
+			3.0817580 * x * x * x - 5.87338670 * x * x + 3.75112997 * x - 0.37001483
+		};
+
+		Some( [ ( x / y ) as f32, 1.0, ( ( 1.0 - x - y ) / y ) as f32 ] )
+	}
+
+	/// Blackbody white point at the given temperature, as bright as possible within sRGB
+	pub fn from_kelvin( kelvin: f32 ) -> Option< Color > {
+		let rgb = Self::matrix_mul_3x3( &XYZ_TO_SRGB, Self::kelvin_to_xyz( kelvin )? );
+		let max = rgb.iter().fold( 0.0f32, |m, &c| m.max( c ) );
+		Some( Color::from_linear( rgb.map( |c| ( c / max ).max( 0.0 ) ), 1.0 ) )
+	}
+
+	/// Bradford chromatic adaptation from one white point (XYZ) to another, as a linear sRGB matrix
+	pub fn bradford_matrix( from_white: [f32; 3], to_white: [f32; 3] ) -> [[f32; 3]; 3] {
+		let from = Self::matrix_mul_3x3( &BRADFORD, from_white );
+		let to = Self::matrix_mul_3x3( &BRADFORD, to_white );
+		let scale = [
+			[ to[ 0 ] / from[ 0 ], 0.0, 0.0 ],
+			[ 0.0, to[ 1 ] / from[ 1 ], 0.0 ],
+			[ 0.0, 0.0, to[ 2 ] / from[ 2 ] ],
+		];
+
+		let adaptation = Self::matrix_mul_matrix( &BRADFORD_INV, &Self::matrix_mul_matrix( &scale, &BRADFORD ) );
+		Self::matrix_mul_matrix( &XYZ_TO_SRGB, &Self::matrix_mul_matrix( &adaptation, &SRGB_TO_XYZ ) )
+	}
+
+	/// Applies a matrix to the linear RGB values, alpha is kept
+	pub fn transform_linear( &self, matrix: &[[f32; 3]; 3] ) -> Color {
+		Color::from_linear( Self::matrix_mul_3x3( matrix, self.rgb_linear() ), self.rgba[ 3 ] )
+	}
+
+	/// CIE Lab (D50) color, may end up outside of the sRGB gamut
+	pub fn from_lab( lab: [f32; 3] ) -> Color {
+		let f1 = ( lab[ 0 ] + 16.0 ) / 116.0;
+		let f0 = lab[ 1 ] / 500.0 + f1;
+		let f2 = f1 - lab[ 2 ] / 200.0;
+		let inverse = |f: f32| if f * f * f > LAB_EPSILON { f * f * f } else { ( 116.0 * f - 16.0 ) / LAB_KAPPA };
+		let y = if lab[ 0 ] > LAB_KAPPA * LAB_EPSILON { f1 * f1 * f1 } else { lab[ 0 ] / LAB_KAPPA };
+		let xyz = [ inverse( f0 ) * D50_WHITE[ 0 ], y * D50_WHITE[ 1 ], inverse( f2 ) * D50_WHITE[ 2 ] ];
+
+		Color::from_linear( Self::matrix_mul_3x3( &XYZ_D50_TO_SRGB, xyz ), 1.0 )
+	}
+
+	/// CIE Lab (D50) with L in 0-100
+	pub fn to_lab( &self ) -> [f32; 3] {
+		let xyz = Self::matrix_mul_3x3( &SRGB_TO_XYZ_D50, self.rgb_linear() );
+		let f = |v: f32| if v > LAB_EPSILON { v.cbrt() } else { ( LAB_KAPPA * v + 16.0 ) / 116.0 };
+		let f0 = f( xyz[ 0 ] / D50_WHITE[ 0 ] );
+		let f1 = f( xyz[ 1 ] / D50_WHITE[ 1 ] );
+		let f2 = f( xyz[ 2 ] / D50_WHITE[ 2 ] );
+		[ 116.0 * f1 - 16.0, 500.0 * ( f0 - f1 ), 200.0 * ( f1 - f2 ) ]
+	}
+
+	/// HWB color, hue in degrees, whiteness and blackness in 0-1
+	pub fn from_hwb( hue: f32, whiteness: f32, blackness: f32 ) -> Color {
+		if whiteness + blackness >= 1.0 {
+			let gray = whiteness / ( whiteness + blackness );
+			return Color { rgba: [ gray, gray, gray, 1.0 ] };
+		}
+
+		// Fully saturated hue (hsl with 100% saturation and 50% lightness), then mixed with white and black
+		let hue = hue.rem_euclid( 360.0 );
+		let channel = |n: f32| {
+			let k = ( n + hue / 30.0 ) % 12.0;
+			0.5 - 0.5 * ( k - 3.0 ).min( 9.0 - k ).clamp( -1.0, 1.0 )
+		};
+		let scale = 1.0 - whiteness - blackness;
+		Color {
+			rgba: [
+				channel( 0.0 ) * scale + whiteness,
+				channel( 8.0 ) * scale + whiteness,
+				channel( 4.0 ) * scale + whiteness,
+				1.0,
+			],
+		}
+	}
+}
+
+/// Round up to the next power of 2, saturating at 2^31
+fn round_up_to_power_of_2(n: u32) -> u32 {
+	if n == 0 {
+		return 1;
+	}
+	if n.is_power_of_two() {
+		return n;
+	}
+	n.checked_next_power_of_two().unwrap_or( 1 << 31 )
+}
+
+#[derive(Debug)]
+pub struct LowTexPal {
+	filename: String,
+	was_modified: bool,
+	colors: Vec<Color>,
+	materials: Vec<Option<Material>>,
+	names: Vec<Option<String>>,
+	groups: Vec<Option<String>>,
+	// Entries given an intensity (or loaded brighter than 1.0), only they keep values above 1.0 instead of being gamut mapped
+	hdr: Vec<bool>,
+	material_maps: MaterialMaps,
+	format: Option<TextureFormat>,
+	alpha_scalar: Option<MaterialScalar>,
+	min_width: Option<u32>,
+	cell_size: u32,
+	pure_mip_levels: Option<u32>,
+	gutter: u32,
+	bit_depth: Option<BitDepth>,
+	loaded_bit_depth: BitDepth,
+	encoding: Option<Encoding>,
+	loaded_encoding: Encoding,
+	gamut_mapping: GamutMapping,
+	layout: Layout,
+	// Cell of each entry as loaded or last saved, None for added entries
+	cells: Vec<Option<u32>>,
+	// Cells per row of the loaded or last saved texture
+	saved_size: Option<u32>,
+	// Entries were moved on purpose, e.g. by sort, and a remap table was reported
+	remapped: bool,
+}
+
+impl LowTexPal {
+
+	pub fn new(
+		filename: &str,
+		min_width: Option<u32>,
 	) -> Self {
 		LowTexPal {
 			filename: filename.to_string(),
 			was_modified: false,
 			colors:Vec::new(),
+			materials: Vec::new(),
+			names: Vec::new(),
+			groups: Vec::new(),
+			hdr: Vec::new(),
+			material_maps: MaterialMaps::Separate,
+			format: None,
+			alpha_scalar: None,
 			min_width,
+			cell_size: 1,
+			pure_mip_levels: None,
+			gutter: 0,
+			bit_depth: None,
+			loaded_bit_depth: BitDepth::Eight,
+			encoding: None,
+			loaded_encoding: Encoding::Srgb,
+			gamut_mapping: GamutMapping::Clip,
+			layout: Layout::Compact,
+			cells: Vec::new(),
+			saved_size: None,
+			remapped: false,
+		}
+	}
+
+	/// Overrides the bit depth used by save, by default the bit depth of the loaded file is kept
+	pub fn set_bit_depth( &mut self, bit_depth: BitDepth ) {
+		self.bit_depth = Some( bit_depth );
+	}
+
+	pub fn bit_depth( &self ) -> BitDepth {
+		self.bit_depth.unwrap_or( self.loaded_bit_depth )
+	}
+
+	/// Overrides the encoding, by default the encoding of the loaded file (or sRGB) is kept
+	pub fn set_encoding( &mut self, encoding: Encoding ) {
+		self.encoding = Some( encoding );
+	}
+
+	pub fn encoding( &self ) -> Encoding {
+		self.encoding.unwrap_or( self.loaded_encoding )
+	}
+
+	/// How wide gamut entries are brought into sRGB on save
+	pub fn set_gamut_mapping( &mut self, gamut_mapping: GamutMapping ) {
+		self.gamut_mapping = gamut_mapping;
+	}
+
+	/// Overrides the file format, by default it is picked from the file extension
+	pub fn set_format( &mut self, format: TextureFormat ) {
+		self.format = Some( format );
+	}
+
+	pub fn format( &self ) -> TextureFormat {
+		self.format.unwrap_or_else( || TextureFormat::from_filename( &self.filename ) )
+	}
+
+	pub fn set_material_maps( &mut self, material_maps: MaterialMaps ) {
+		self.material_maps = material_maps;
+	}
+
+	/// Packs a material scalar into the alpha channel instead of writing companion textures
+	/// Needs to be set before load, so alpha is read back as the scalar
+	pub fn set_alpha_scalar( &mut self, alpha_scalar: Option< MaterialScalar > ) {
+		self.alpha_scalar = alpha_scalar;
+	}
+
+	/// Sets the size of each palette cell in pixels (rounded up to a power of 2)
+	/// Needs to be set before load, so each cell is read back as one entry
+	/// Fails for cells larger than MAX_IMAGE_SIZE
+	pub fn set_cell_size( &mut self, cell_size: u32 ) -> bool {
+		if cell_size > MAX_IMAGE_SIZE {
+			return false;
 		}
+		self.cell_size = round_up_to_power_of_2( cell_size.max( 1 ) );
+		true
+	}
+
+	pub fn cell_size( &self ) -> u32 {
+		self.cell_size
+	}
+
+	/// Writes a full mip chain to DDS and KTX2, keeping cells pure down to the given level
+	/// Levels below that are averaged. Cells stay pure at most down to log2(cell size).
+	/// Bilinear filtering only stays within a cell for UVs at least half a pixel of the smallest pure
+	/// level away from its edge, i.e. 2^(level - 1) pixels of the full texture, see set_gutter.
+	pub fn set_pure_mip_levels( &mut self, pure_mip_levels: Option< u32 > ) {
+		self.pure_mip_levels = pure_mip_levels;
+	}
+
+	/// Reserves a border of gutter pixels inside every cell that UVs keep away from, see entry_uv_area
+	/// The gutter halves with each mip level, filtering stays within the cell while it is at least half a pixel.
+	/// Needs the cell size to be set first, fails if the gutter leaves nothing of the cell.
+	pub fn set_gutter( &mut self, gutter: u32 ) -> bool {
+		if gutter.checked_mul( 2 ).is_none_or( |g| g >= self.cell_size ) {
+			return false;
+		}
+		self.gutter = gutter;
+		true
+	}
+
+	pub fn gutter( &self ) -> u32 {
+		self.gutter
+	}
+
+	/// The smallest mip level at which the gutter is still half a pixel, None without a gutter
+	pub fn gutter_mip_level( &self ) -> Option< u32 > {
+		( self.gutter > 0 ).then( || self.gutter.ilog2() + 1 )
+	}
+
+	// Companion texture next to the palette, e.g. pal.png -> pal_roughness.png
+	fn companion_filename( &self, suffix: &str ) -> String {
+		self.companion_path( suffix, "png" )
+	}
+
+	/// How entries are arranged when saving, also needed to find the UVs of entries
+	pub fn set_layout( &mut self, layout: Layout ) {
+		self.layout = layout;
+	}
+
+	pub fn layout( &self ) -> Layout {
+		self.layout
+	}
+
+	/// Switches to another layout and reports where each entry's cell moved
+	/// Switching to the fixed layout freezes the current cells and the texture size, or grows the
+	/// texture to the minimum width keeping the pixel position of every cell
+	pub fn relayout( &mut self, layout: Layout ) -> Vec< RemapEntry > {
+		// A fixed layout only grows here, so its old UVs are those of the saved size
+		let min_width = if self.layout == Layout::Fixed { self.min_width.take() } else { self.min_width };
+		let old_uvs: Vec< [f32; 2] > = ( 1..=self.colors.len() ).map( |i| self.entry_uv( i ).unwrap_or_default() ).collect();
+		let old_layout = self.cell_layout();
+		self.min_width = min_width;
+
+		if let ( Layout::Fixed, Some( ( old_size, cells ) ) ) = ( layout, old_layout ) {
+			let min_size = self.min_width.map_or( 1, |w| round_up_to_power_of_2( w ) / self.cell_size );
+			let size = old_size.max( min_size ).min( MAX_IMAGE_SIZE / self.cell_size );
+			self.cells = cells.iter().map( |&cell| Some( cell / old_size * size + cell % old_size ) ).collect();
+			self.saved_size = Some( size );
+		}
+		self.layout = layout;
+		self.was_modified = true;
+		self.remapped = true;
+
+		old_uvs.iter().enumerate().map( |( i, &old_uv )| RemapEntry {
+			old_index: i + 1,
+			new_index: i + 1,
+			old_uv,
+			new_uv: self.entry_uv( i + 1 ).unwrap_or_default(),
+		} ).collect()
+	}
+
+	fn companion_path( &self, suffix: &str, extension: &str ) -> String {
+		let path = std::path::Path::new( &self.filename );
+		let stem = path.file_stem().map( |s| s.to_string_lossy().into_owned() ).unwrap_or_default();
+		path.with_file_name( format!( "{}_{}.{}", stem, suffix, extension ) ).to_string_lossy().into_owned()
 	}
 
 	pub fn load( &mut self ) {
 		// :TODO: load
-		let img = match image::open( &self.filename ) {
-			Err( _e ) => return,	// :TODO: error handling // Note: This is not even an error, since we might be creating a new image later. Maybe?!
-			Ok( img ) => img,
+		let format = self.format();
+
+		let ( width, pixel_colors ) = match format {
+			TextureFormat::Dds | TextureFormat::Ktx2 => {
+				let texture = match format {
+					TextureFormat::Dds => container::read_dds( &self.filename ),
+					_ => container::read_ktx2( &self.filename ),
+				};
+				match texture {
+					Err( _e ) => return,	// :TODO: error handling, see below
+					Ok( texture ) => ( texture.width, self.container_pixels( &texture ) ),
+				}
+			},
+			_ => {
+				let img = match image::ImageReader::open( &self.filename ).map( |mut r| {
+					r.set_format( format.image_format() );
+					r
+				} ) {
+					Err( _e ) => return,	// :TODO: error handling // Note: This is not even an error, since we might be creating a new image later. Maybe?!
+					Ok( reader ) => match reader.decode() {
+						Err( _e ) => return,
+						Ok( img ) => img,
+					},
+				};
+				( img.width(), self.image_pixels( img, format ) )
+			},
 		};
-//		self.image = Some( img );
-		// :TODO: iterate through pixels to get colors
+		let pixel_colors = self.cell_colors( width, &pixel_colors );
+
+		// Pixel index of each loaded entry, to find its material scalars
+		let mut pixels = Vec::new();
+		if let Some( listed ) = self.load_entry_cells( pixel_colors.len() ) {
+			// Entries that look like unused cells or aren't in cell order were listed when saving
+			self.colors.extend( listed.iter().map( |&p| pixel_colors[ p ] ) );
+			pixels = listed;
+		} else if format == TextureFormat::Hdr || self.alpha_scalar.is_some() {
+			// Radiance HDR has no alpha, so unused pixels are opaque black,
+			// and with a scalar packed into alpha transparent black is a valid entry.
+			// Only the trailing unused pixels can be told apart from entries.
+			let unused = if format == TextureFormat::Hdr { [ 0, 0, 0, 255 ] } else { [ 0, 0, 0, 0 ] };
+			let count = pixel_colors.iter().rposition( |c| c.rgba_u8() != unused ).map_or( 0, |p| p + 1 );
+			self.colors.extend_from_slice( &pixel_colors[ ..count ] );
+			pixels.extend( 0..count );
+		} else if self.layout == Layout::Compact {
+			// Compact palettes have no holes, so transparent black between entries is an entry
+			let count = pixel_colors.iter().rposition( |c| !c.is_empty() ).map_or( 0, |p| p + 1 );
+			self.colors.extend_from_slice( &pixel_colors[ ..count ] );
+			pixels.extend( 0..count );
+		} else {
+			for ( pixel, color ) in pixel_colors.iter().enumerate() {
+//				dbg!(&color);
+				if !color.is_empty() {
+					self.colors.push( *color );
+					pixels.push( pixel );
+				}
+			}
+		}
 
-		let img = img.into_rgba8();
+		self.materials = vec![ None; self.colors.len() ];
+		self.hdr = self.colors.iter().map( |c| c.is_hdr() ).collect();
+		match self.alpha_scalar {
+			Some( scalar ) => self.unpack_alpha_scalar( scalar ),
+			None => self.load_materials( &pixels ),
+		}
+		self.names = self.load_labels( "names", &pixels );
+		self.groups = self.load_labels( "groups", &pixels );
+		self.cells = pixels.iter().map( |&p| Some( p as u32 ) ).collect();
+		self.saved_size = Some( width / self.cell_size );
+
+		self.was_modified = false;
+	}
 
-		for rgba in img.pixels() {
-//			dbg!(&rgba);
-			let color: Color = rgba.into();
-			if !color.is_empty() {
-				self.colors.push( color );
+	// Cell of every entry from the companion text file, one per line in entry order
+	// None if there is none, or if it doesn't match the loaded texture
+	fn load_entry_cells( &self, cell_count: usize ) -> Option< Vec< usize > > {
+		let filename = self.companion_path( "entries", "txt" );
+		let text = std::fs::read_to_string( &filename ).ok()?;
+
+		let mut cells = Vec::new();
+		for line in text.lines().filter( |l| !l.trim().is_empty() ) {
+			// Cells are numbered like the entries of the names and groups files
+			match line.trim().parse::< usize >() {
+				Ok( cell ) if ( 1..=cell_count ).contains( &cell ) && !cells.contains( &( cell - 1 ) ) => cells.push( cell - 1 ),
+				_ => {
+					println!("Warning: Ignoring {}, cell '{}' doesn't match the texture", &filename, line );
+					return None;
+				},
 			}
+		}
+		Some( cells )
+	}
+
+	// Entry names or groups from the companion text file, one "<entry> <label>" per line
+	fn load_labels( &self, suffix: &str, pixels: &[usize] ) -> Vec< Option< String > > {
+		let mut labels = vec![ None; self.colors.len() ];
+
+		let Ok( text ) = std::fs::read_to_string( self.companion_path( suffix, "txt" ) ) else {
+			return labels;
 		};
+		for line in text.lines() {
+			let Some( ( entry, name ) ) = line.trim().split_once( ' ' ) else {
+				continue;
+			};
+			// Entries were saved one per cell, so the entry number is the cell index + 1
+			let index = entry.parse::< usize >().ok().and_then( |entry| pixels.iter().position( |&p| p + 1 == entry ) );
+			match index {
+				Some( index ) if Self::is_valid_name( name.trim() ) => labels[ index ] = Some( name.trim().to_string() ),
+				_ => println!("Warning: Ignoring entry {} '{}'", suffix, line ),
+			}
+		}
+		labels
+	}
 
-		self.was_modified = false;
+	// The color at the center of each cell, row by row
+	fn cell_colors( &self, width: u32, pixel_colors: &[Color] ) -> Vec< Color > {
+		if self.cell_size == 1 {
+			return pixel_colors.to_vec();
+		}
+
+		let grid = width / self.cell_size;
+		let center = self.cell_size / 2;
+		let mut colors = Vec::new();
+		for y in 0..grid {
+			for x in 0..grid {
+				let pixel = ( y * self.cell_size + center ) * width + x * self.cell_size + center;
+				if let Some( color ) = pixel_colors.get( pixel as usize ) {
+					colors.push( *color );
+				}
+			}
+		}
+		colors
+	}
+
+	// All pixels of an image decoded by the image crate, row by row
+	fn image_pixels( &mut self, img: image::DynamicImage, format: TextureFormat ) -> Vec< Color > {
+		if format.is_hdr() {
+			// Linear float images, values above 1.0 are kept
+			img.into_rgba32f()
+				.pixels()
+				.map( |rgba| Color::from_linear( [ rgba[ 0 ], rgba[ 1 ], rgba[ 2 ] ], rgba[ 3 ] ) )
+				.collect()
+		} else {
+			let color_type = img.color();
+			if color_type.bits_per_pixel() / color_type.channel_count() as u16 >= 16 {
+				self.loaded_bit_depth = BitDepth::Sixteen;
+			}
+
+			// Go through 16 bit, so 16 bit sources are not truncated
+			let colors: Vec< Color > = img.into_rgba16().pixels().map( |rgba| rgba.into() ).collect();
+
+			if format == TextureFormat::Png && self.png_encoding() == Encoding::Linear {
+				self.loaded_encoding = Encoding::Linear;
+				colors.iter().map( |c| {
+					let rgba = c.rgba();
+					Color::from_linear( [ rgba[ 0 ], rgba[ 1 ], rgba[ 2 ] ], rgba[ 3 ] )
+				} ).collect()
+			} else {
+				colors
+			}
+		}
+	}
+
+	// Encoding declared in the PNG chunks, a cICP linear transfer or a gAMA of 1.0 without sRGB chunk
+	fn png_encoding( &self ) -> Encoding {
+		let reader = std::fs::File::open( &self.filename )
+			.ok()
+			.and_then( |file| png::Decoder::new( std::io::BufReader::new( file ) ).read_info().ok() );
+		let Some( reader ) = reader else {
+			return Encoding::Srgb;
+		};
+
+		let info = reader.info();
+		let linear = match info.coding_independent_code_points {
+			Some( cicp ) => cicp.transfer_function == CICP_TRANSFER_LINEAR,
+			None => info.srgb.is_none() && info.source_gamma.map( |g| g.into_scaled() ) == Some( PNG_GAMMA_LINEAR ),
+		};
+
+		if linear { Encoding::Linear } else { Encoding::Srgb }
+	}
+
+	// Level 0 of an uncompressed DDS or KTX2 texture, row by row
+	fn container_pixels( &mut self, texture: &TextureData ) -> Vec< Color > {
+		let data = &texture.levels[ 0 ];
+		match texture.format {
+			PixelFormat::Rgba8 { srgb: true } => data.chunks_exact( 4 )
+				.map( |p| [ p[ 0 ], p[ 1 ], p[ 2 ], p[ 3 ] ].into() )
+				.collect(),
+			PixelFormat::Rgba8 { srgb: false } => {
+				self.loaded_encoding = Encoding::Linear;
+				data.chunks_exact( 4 )
+					.map( |p| {
+						let c = |i: usize| p[ i ] as f32 / 255.0;
+						Color::from_linear( [ c( 0 ), c( 1 ), c( 2 ) ], c( 3 ) )
+					} )
+					.collect()
+			},
+			PixelFormat::Rgba16 => {
+				self.loaded_bit_depth = BitDepth::Sixteen;
+				data.chunks_exact( 8 )
+					.map( |p| {
+						let c = |i: usize| u16::from_le_bytes( [ p[ 2 * i ], p[ 2 * i + 1 ] ] ) as f32 / 65535.0;
+						Color::from_linear( [ c( 0 ), c( 1 ), c( 2 ) ], c( 3 ) )
+					} )
+					.collect()
+			},
+			PixelFormat::Rgba32F => data.chunks_exact( 16 )
+				.map( |p| {
+					let c = |i: usize| f32::from_le_bytes( [ p[ 4 * i ], p[ 4 * i + 1 ], p[ 4 * i + 2 ], p[ 4 * i + 3 ] ] );
+					Color::from_linear( [ c( 0 ), c( 1 ), c( 2 ) ], c( 3 ) )
+				} )
+				.collect(),
+		}
+	}
+
+	// Alpha holds the packed scalar, the colors themselves are opaque
+	fn unpack_alpha_scalar( &mut self, scalar: MaterialScalar ) {
+		for ( color, material ) in self.colors.iter_mut().zip( self.materials.iter_mut() ) {
+			let mut m = Material::default();
+			m.set_scalar( scalar, color.alpha() );
+			*material = Some( m );
+			*color = color.with_alpha( 1.0 );
+		}
+	}
+
+	// Read material scalars back from companion textures, if there are any
+	fn load_materials( &mut self, pixels: &[usize] ) {
+		let open = |suffix: &str| image::open( self.companion_filename( suffix ) ).ok().map( |img| img.into_rgba32f() );
+
+		let orm = open( "orm" );
+		let roughness = open( "roughness" );
+		let metallic = open( "metallic" );
+		let emission = open( "emission" );
+
+		if orm.is_none() && roughness.is_none() && metallic.is_none() && emission.is_none() {
+			return;
+		}
+		if orm.is_some() {
+			self.material_maps = MaterialMaps::Orm;
+		}
+
+		// Sample the center of each cell
+		let cell_size = self.cell_size;
+		let sample = |img: &Option< image::Rgba32FImage >, pixel: usize, channel: usize| {
+			img.as_ref().and_then( |img| {
+				let grid = ( img.width() / cell_size ).max( 1 );
+				let x = ( pixel as u32 ) % grid * cell_size + cell_size / 2;
+				let y = ( pixel as u32 ) / grid * cell_size + cell_size / 2;
+				img.get_pixel_checked( x, y ).map( |p| p[ channel ] )
+			} )
+		};
+
+		for ( index, &pixel ) in pixels.iter().enumerate() {
+			let default = Material::default();
+			self.materials[ index ] = Some( Material {
+				roughness: sample( &orm, pixel, 1 ).or( sample( &roughness, pixel, 0 ) ).unwrap_or( default.roughness ),
+				metallic: sample( &orm, pixel, 2 ).or( sample( &metallic, pixel, 0 ) ).unwrap_or( default.metallic ),
+				emission: sample( &emission, pixel, 0 ).unwrap_or( default.emission ),
+			} );
+		}
+	}
+
+	pub fn has_hdr_entries( &self ) -> bool {
+		( 1..=self.colors.len() ).any( |i| self.is_hdr_entry( i ) )
+	}
+
+	/// True if the entry at index (as returned by the add_* methods) was given an intensity that makes it brighter than 1.0
+	/// Other entries brighter than 1.0 are wide gamut colors, which are gamut mapped on save
+	pub fn is_hdr_entry( &self, index: usize ) -> bool {
+		self.hdr.get( index.wrapping_sub( 1 ) ).is_some_and( |&hdr| hdr && self.colors[ index - 1 ].is_hdr() )
 	}
 
 	pub fn save( &mut self ) {
 		// :TODO: save
-		if self.colors.len() == 0 {
+		if self.colors.is_empty() {
 			println!("No colors. Not saving.");
 			return
 		}
 
+		// Number of cells per row and the cell of each entry
+		let Some( ( size, cells ) ) = self.cell_layout() else {
+			if self.layout == Layout::Fixed {
+				println!("Error: The fixed layout can't hold {} entries or change its size, not saving. Use relayout --to fixed with a larger --min-width to grow it", self.colors.len() );
+				return;
+			}
+			println!("Error: {} entries with cells of {} pixels don't fit into an image of at most {}x{} pixels, not saving", self.colors.len(), self.cell_size, MAX_IMAGE_SIZE, MAX_IMAGE_SIZE );
+			return;
+		};
+
+		let moved = self.pending_remap().iter().filter( |e| e.old_uv != e.new_uv ).count();
+		if moved > 0 {
+			println!("Warning: {} entries moved to other UVs, models using this palette need to be remapped. Use --layout fixed to keep UVs", moved );
+		}
+		let image_size = size * self.cell_size;
+
+		if image_size > 1024 {
+			println!("Warning: Resulting image size is {}x{} (larger than 1024x1024)", image_size, image_size);
+		}
+
+		dbg!(&size);
+
+		let format = self.format();
+		// DDS and KTX2 switch to float for HDR entries
+		let keeps_hdr = format.is_hdr() || matches!( format, TextureFormat::Dds | TextureFormat::Ktx2 );
+		// Wide gamut entries are reported when they are mapped below
+		if !keeps_hdr && ( 1..=self.colors.len() ).any( |i| self.is_hdr_entry( i ) && self.colors[ i - 1 ].in_srgb_gamut() ) {
+			println!("Warning: HDR entries are clamped to 1.0, use an .exr, .hdr, .dds or .ktx2 file to keep them");
+		}
+		if matches!( format, TextureFormat::Tga | TextureFormat::Bmp ) && self.bit_depth() == BitDepth::Sixteen {
+			println!("Warning: {:?} only supports 8 bits per channel", format );
+		}
+		if self.pure_mip_levels.is_some() && !matches!( format, TextureFormat::Dds | TextureFormat::Ktx2 ) {
+			println!("Warning: Mip chains are only written to DDS and KTX2");
+		}
+		if let ( Some( pure_mip_levels ), Some( gutter_level ) ) = ( self.pure_mip_levels, self.gutter_mip_level() ) {
+			let pure_mip_levels = pure_mip_levels.min( self.cell_size.trailing_zeros() );
+			if pure_mip_levels > gutter_level {
+				println!("Warning: The gutter of {} pixels keeps filtering within the cells down to mip level {}, below that only UVs at the cell centers stay within them", self.gutter, gutter_level );
+			}
+		}
+
+		// The stored colors, with alpha replaced by the packed scalar if requested
+		let packed: Vec< Color > = match self.alpha_scalar {
+			Some( scalar ) => {
+				if self.colors.iter().any( |c| c.alpha() < 1.0 ) {
+					println!("Warning: Color alpha is replaced by the packed {:?}", scalar );
+				}
+				self.colors.iter().zip( self.materials.iter() )
+					.map( |( c, m )| c.with_alpha( m.unwrap_or_default().scalar( scalar ) ) )
+					.collect()
+			},
+			None => self.colors.clone(),
+		};
+
+		// Wide gamut entries (e.g. Display P3) are mapped into sRGB, only HDR entries may be brighter than 1.0
+		let mut out_of_gamut = Vec::new();
+		let packed: Vec< Color > = packed.iter().enumerate().map( |( i, c )| {
+			let hdr = self.is_hdr_entry( i + 1 );
+			if c.in_srgb_gamut() && ( hdr || !c.is_hdr() ) {
+				return *c;
+			}
+			let mapped = c.to_srgb_gamut( self.gamut_mapping, keeps_hdr && hdr );
+			out_of_gamut.push( OutOfGamut {
+				index: i + 1,
+				delta_e: Color::delta_e_ok( c.to_oklab(), mapped.to_oklab() ),
+			} );
+			mapped
+		} ).collect();
+		if !out_of_gamut.is_empty() {
+			let entries: Vec< String > = out_of_gamut.iter()
+				.map( |oog| format!( "{} (ΔE OK {:.4})", oog.index, oog.delta_e ) )
+				.collect();
+			println!("Warning: Entries outside of sRGB are gamut mapped ({:?}): {}", self.gamut_mapping, entries.join( ", " ) );
+		}
+
+		// 8 and 16 bit formats without an explicit linear format store linear values directly
+		let encoded: Vec< Color > = match ( self.encoding(), format ) {
+			( Encoding::Linear, TextureFormat::Png | TextureFormat::Tga | TextureFormat::Bmp ) => packed.iter()
+				.map( |c| Color { rgba: c.rgba_linear() } )
+				.collect(),
+			_ => packed.clone(),
+		};
+
+		let colors = encoded.iter();
+		let image_format = format.image_format();
+		let result = match ( format, self.bit_depth() ) {
+			( TextureFormat::Dds, _ ) | ( TextureFormat::Ktx2, _ ) => {
+				let texture = self.container_texture( size, &cells, &packed );
+				match format {
+					TextureFormat::Dds => container::write_dds( &self.filename, &texture ),
+					_ => container::write_ktx2( &self.filename, &texture ),
+				}.map_err( image::ImageError::IoError )
+			},
+			( TextureFormat::Exr, _ ) => Self::fill_image( size, self.cell_size, &cells, colors.map( |c| image::Rgba( c.rgba_linear() ) ) ).save_with_format( &self.filename, image_format ),
+			( TextureFormat::Hdr, _ ) => Self::fill_image( size, self.cell_size, &cells, colors.map( |c| {
+				let linear = c.rgba_linear();
+				image::Rgb( [ linear[ 0 ], linear[ 1 ], linear[ 2 ] ] )
+			} ) ).save_with_format( &self.filename, image_format ),
+			( TextureFormat::Png, BitDepth::Sixteen ) => {
+				let img = Self::fill_image( size, self.cell_size, &cells, colors.map( |c| image::Rgba( c.rgba_u16() ) ) );
+				self.save_png( image_size, png::BitDepth::Sixteen, &img.into_raw().iter().flat_map( |v| v.to_be_bytes() ).collect::< Vec< u8 > >() )
+			},
+			( TextureFormat::Png, BitDepth::Eight ) => {
+				let img = Self::fill_image( size, self.cell_size, &cells, colors.map( |c| image::Rgba( c.rgba_u8() ) ) );
+				self.save_png( image_size, png::BitDepth::Eight, &img.into_raw() )
+			},
+			_ => Self::fill_image( size, self.cell_size, &cells, colors.map( |c| image::Rgba( c.rgba_u8() ) ) ).save_with_format( &self.filename, image_format ),
+		};
+		if let Err( e ) = result {
+			println!("Error saving image {}", &e );
+			return
+		}
+
+		if let Err( e ) = self.save_materials( size, &cells ) {
+			println!("Error saving material maps {}", &e );
+			return
+		}
+
+		if let Err( e ) = self.save_labels( "names", &self.names, &cells ) {
+			println!("Error saving entry names {}", &e );
+			return
+		}
+
+		if let Err( e ) = self.save_labels( "groups", &self.groups, &cells ) {
+			println!("Error saving entry groups {}", &e );
+			return
+		}
+
+		if let Err( e ) = self.save_entry_cells( &cells, &packed ) {
+			println!("Error saving entry list {}", &e );
+			return
+		}
+
+		self.cells = cells.into_iter().map( Some ).collect();
+		self.saved_size = Some( size );
+		self.remapped = false;
+
+		self.was_modified = false;
+	}
+
+	// Side length of the saved image in pixels, None if there are too many entries or it gets larger than MAX_IMAGE_SIZE
+	fn image_size( &self ) -> Option< u32 > {
 		// Calculate size based on color count
 		let size_from_colors = match self.colors.len() {
-			1 => 1,
+			0 | 1 => 1,
 			2 => 2, //  <= 4 -> 2x2
 			3 => 2, //  <= 4 -> 2x2
 			4 => 2, //  <= 4 -> 2x2
@@ -313,67 +1979,757 @@ impl LowTexPal {
 			n if n <= 64 => 8,
 			n if n <= 256 => 16,
 			// :TODO: could use round_up_to_power_of_2(sqrt(n))
-			n => panic!("Not handling size for {} entries", n ),
+			_ => return None,
 		};
 
-		// Apply min_width if specified
-		let size = if let Some(min_w) = self.min_width {
+		// Apply cell size and min_width if specified
+		let image_size = if let Some(min_w) = self.min_width {
 			let min_w_rounded = round_up_to_power_of_2(min_w);
-			let final_size = size_from_colors.max(min_w_rounded);
+			( size_from_colors * self.cell_size ).max(min_w_rounded)
+		} else {
+			size_from_colors * self.cell_size
+		};
+		Some( image_size ).filter( |&size| size <= MAX_IMAGE_SIZE )
+	}
 
-			if final_size > 1024 {
-				println!("Warning: Resulting image size is {}x{} (larger than 1024x1024)", final_size, final_size);
+	// Number of cells per row and the cell (row by row) of each entry, None if the entries don't fit
+	fn cell_layout( &self ) -> Option< ( u32, Vec< u32 > ) > {
+		let size = self.image_size()? / self.cell_size;
+		match self.layout {
+			Layout::Compact => Some( ( size, ( 0..self.colors.len() as u32 ).collect() ) ),
+			Layout::Rows => {
+				// Grow the texture until all rows fit
+				let mut size = size;
+				while size <= MAX_ROWS_LAYOUT_SIZE && size * self.cell_size <= MAX_IMAGE_SIZE {
+					if let Some( cells ) = self.pack_rows( size ) {
+						return Some( ( size, cells ) );
+					}
+					size *= 2;
+				}
+				None
+			},
+			Layout::Fixed => {
+				let size = self.fixed_size()?;
+				let cell_count = size * size;
+				let mut occupied = vec![ false; cell_count as usize ];
+				for &cell in self.cells.iter().flatten() {
+					*occupied.get_mut( cell as usize )? = true;
+				}
+				let mut free = ( 0..cell_count ).filter( |&cell| !occupied[ cell as usize ] );
+				let cells = self.cells.iter()
+					.map( |cell| cell.or_else( || free.next() ) )
+					.collect::< Option< Vec< u32 > > >()?;
+				Some( ( size, cells ) )
+			},
+		}
+	}
+
+	// Cells per row of the fixed layout: the size of the loaded texture, or the minimum width for new ones
+	// None if the minimum width asks for a larger texture than the loaded one or than MAX_IMAGE_SIZE
+	fn fixed_size( &self ) -> Option< u32 > {
+		let min_size = self.min_width.map( |w| ( round_up_to_power_of_2( w ) / self.cell_size ).max( 1 ) );
+		let size = match ( self.saved_size, min_size ) {
+			( Some( saved ), Some( min ) ) if min > saved => None,
+			( Some( saved ), _ ) => Some( saved ),
+			( None, Some( min ) ) => Some( min ),
+			// Without a reserved width a new palette would be full after its first entries
+			( None, None ) => Some( ( self.image_size()? / self.cell_size ).max( FIXED_LAYOUT_DEFAULT_SIZE.min( MAX_IMAGE_SIZE / self.cell_size ) ) ),
+		};
+		size.filter( |&size| size <= MAX_IMAGE_SIZE / self.cell_size )
+	}
+
+	// Each group starts on its own row(s), groups shorter than a row are aligned to a power of 2 column,
+	// then ungrouped entries fill the holes. Groups are placed in the order of their first entry.
+	fn pack_rows( &self, size: u32 ) -> Option< Vec< u32 > > {
+		let size = size as usize;
+		let mut occupied = vec![ false; size * size ];
+		let mut cells = vec![ 0; self.colors.len() ];
+
+		let mut placed: Vec< &str > = Vec::new();
+		for group in self.groups.iter().flatten() {
+			if placed.contains( &group.as_str() ) {
+				continue;
+			}
+			placed.push( group );
+			let entries: Vec< usize > = ( 0..self.colors.len() ).filter( |&i| self.groups[ i ].as_ref() == Some( group ) ).collect();
+
+			let start = if entries.len() >= size {
+				// Whole empty rows
+				let rows = entries.len().div_ceil( size );
+				( 0..=size.checked_sub( rows )? )
+					.find( |&row| occupied[ row * size..( row + rows ) * size ].iter().all( |o| !o ) )?
+					* size
+			} else {
+				let width = entries.len().next_power_of_two();
+				( 0..size * size ).step_by( width )
+					.find( |&cell| occupied[ cell..cell + entries.len() ].iter().all( |o| !o ) )?
+			};
+			for ( n, &entry ) in entries.iter().enumerate() {
+				cells[ entry ] = ( start + n ) as u32;
+				occupied[ start + n ] = true;
 			}
+		}
+
+		let mut free = ( 0..size * size ).filter( |&cell| !occupied[ cell ] );
+		for ( entry, group ) in self.groups.iter().enumerate() {
+			if group.is_none() {
+				cells[ entry ] = free.next()? as u32;
+			}
+		}
+
+		Some( cells )
+	}
+
+	/// Normalized texture coordinates of the center of the entry's cell, as laid out by save
+	/// The origin is the top left corner of the image, OBJ and OpenGL style UVs use 1 - v
+	pub fn entry_uv( &self, index: usize ) -> Option< [f32; 2] > {
+		if !( 1..=self.colors.len() ).contains( &index ) {
+			return None;
+		}
+		let ( size, cells ) = self.cell_layout()?;
+		let cell = cells[ index - 1 ];
+		Some( [
+			( ( cell % size ) as f32 + 0.5 ) / size as f32,
+			( ( cell / size ) as f32 + 0.5 ) / size as f32,
+		] )
+	}
+
+	/// Normalized texture coordinates [ u0, v0, u1, v1 ] of the entry's cell without its gutter, as laid out by save
+	/// Filtered UVs within this area only pick up the entry down to gutter_mip_level, without a gutter
+	/// that is only true for the cell center.
+	pub fn entry_uv_area( &self, index: usize ) -> Option< [f32; 4] > {
+		if !( 1..=self.colors.len() ).contains( &index ) {
+			return None;
+		}
+		let ( size, cells ) = self.cell_layout()?;
+		let cell = cells[ index - 1 ];
+		let image_size = ( size * self.cell_size ) as f32;
+		let inner = self.cell_size.saturating_sub( 2 * self.gutter ) as f32;
+		let x = ( cell % size * self.cell_size + self.gutter ) as f32;
+		let y = ( cell / size * self.cell_size + self.gutter ) as f32;
+		Some( [ x / image_size, y / image_size, ( x + inner ) / image_size, ( y + inner ) / image_size ] )
+	}
+
+	/// The count entries closest to color, closest first
+	pub fn nearest_entries( &self, color: &Color, method: DeltaE, count: usize ) -> Vec< NearestEntry > {
+		let mut entries: Vec< NearestEntry > = self.colors.iter().enumerate()
+			.map( |( i, c )| NearestEntry {
+				index: i + 1,
+				delta_e: color.delta_e( c, method ),
+			} )
+			.collect();
+		entries.sort_by( |a, b| a.delta_e.total_cmp( &b.delta_e ) );
+		entries.truncate( count );
+		entries
+	}
 
-			final_size
+	// RGBA PNG with sRGB, gAMA and cICP chunks declaring the encoding
+	fn save_png( &self, image_size: u32, bit_depth: png::BitDepth, data: &[u8] ) -> image::ImageResult< () > {
+		let file = std::fs::File::create( &self.filename ).map_err( image::ImageError::IoError )?;
+		let mut encoder = png::Encoder::new( std::io::BufWriter::new( file ), image_size, image_size );
+		encoder.set_color( png::ColorType::Rgba );
+		encoder.set_depth( bit_depth );
+
+		let transfer = match self.encoding() {
+			Encoding::Srgb => {
+				encoder.set_source_srgb( png::SrgbRenderingIntent::Perceptual );
+				encoder.set_source_gamma( png::ScaledFloat::from_scaled( PNG_GAMMA_SRGB ) );
+				CICP_TRANSFER_SRGB
+			},
+			Encoding::Linear => {
+				encoder.set_source_gamma( png::ScaledFloat::from_scaled( PNG_GAMMA_LINEAR ) );
+				CICP_TRANSFER_LINEAR
+			},
+		};
+
+		let write = || -> Result< (), png::EncodingError > {
+			let mut writer = encoder.write_header()?;
+			writer.write_chunk( png::chunk::cICP, &[ CICP_PRIMARIES_BT709, transfer, 0, 1 ] )?;
+			writer.write_image_data( data )?;
+			writer.finish()
+		};
+		write().map_err( |e| image::ImageError::IoError( e.into() ) )
+	}
+
+	// Uncompressed DDS/KTX2 data: 8 bit sRGB, 16 bit linear, or linear float if there are HDR entries
+	fn container_texture( &self, size: u32, cells: &[u32], colors: &[Color] ) -> TextureData {
+		let format = if self.has_hdr_entries() {
+			PixelFormat::Rgba32F
+		} else if self.bit_depth() == BitDepth::Sixteen {
+			PixelFormat::Rgba16
 		} else {
-			size_from_colors
+			PixelFormat::Rgba8 { srgb: self.encoding() == Encoding::Srgb }
 		};
 
-		dbg!(&size);
+		let levels = self.mip_chain( size, cells, colors )
+			.iter()
+			.map( |level| Self::encode_pixels( format, level ) )
+			.collect();
 
-		let mut imgbuf = image::ImageBuffer::new( size, size );
+		TextureData {
+			width: size * self.cell_size,
+			height: size * self.cell_size,
+			format,
+			levels,
+		}
+	}
 
-		let mut y = 0;
-		let mut x = 0;
+	fn encode_pixels( format: PixelFormat, pixels: &[Color] ) -> Vec< u8 > {
+		match format {
+			PixelFormat::Rgba8 { srgb: true } => pixels.iter().flat_map( |c| c.rgba_u8() ).collect(),
+			PixelFormat::Rgba8 { srgb: false } => pixels.iter().flat_map( |c| c.rgba_linear().map( Color::quantize_u8 ) ).collect(),
+			PixelFormat::Rgba16 => pixels.iter()
+				.flat_map( |c| c.rgba_linear().map( Color::quantize_u16 ) )
+				.flat_map( |v| v.to_le_bytes() )
+				.collect(),
+			PixelFormat::Rgba32F => pixels.iter()
+				.flat_map( |c| c.rgba_linear() )
+				.flat_map( |v| v.to_le_bytes() )
+				.collect(),
+		}
+	}
 
-		for color in &self.colors {
+	// All pixels of the cell layout with the given cell size, row by row
+	fn cell_pixels( size: u32, cell_size: u32, cells: &[u32], colors: &[Color] ) -> Vec< Color > {
+		Self::fill_image( size, cell_size, cells, colors.iter().map( |c| image::Rgba( c.rgba() ) ) )
+			.pixels()
+			.map( |p| Color { rgba: p.0 } )
+			.collect()
+	}
 
-			if x >= size && y >= size {
-				panic!("Tried to write to many pixels to image");	// should never trigger				
-			}
-			let rgba = color.rgba_u8();
+	// Mip levels down to 1x1, level 0 first
+	// Cells stay pure while they are at least one pixel and within the requested pure levels,
+	// each level below that averages 2x2 pixels of the previous one.
+	// Cells are solid, so their gutter is written with the cell color and shrinks along with the cell.
+	fn mip_chain( &self, size: u32, cells: &[u32], colors: &[Color] ) -> Vec< Vec< Color > > {
+		let mut levels = vec![ Self::cell_pixels( size, self.cell_size, cells, colors ) ];
 
-			let p = imgbuf.get_pixel_mut( x, y );
+		let Some( pure_mip_levels ) = self.pure_mip_levels else {
+			return levels;
+		};
 
-			*p = image::Rgba( rgba );
+		let max_pure = self.cell_size.trailing_zeros();
+		if pure_mip_levels > max_pure {
+			println!("Warning: Cells of {} pixels only stay pure down to mip level {}", self.cell_size, max_pure );
+		}
+
+		let image_size = size * self.cell_size;
+		for level in 1..=image_size.trailing_zeros() {
+			let next = if level <= pure_mip_levels.min( max_pure ) {
+				Self::cell_pixels( size, self.cell_size >> level, cells, colors )
+			} else {
+				Self::downsample( &levels[ levels.len() - 1 ], image_size >> ( level - 1 ) )
+			};
+			levels.push( next );
+		}
+
+		levels
+	}
+
+	// Average 2x2 pixels in linear light, weighted by alpha so transparent pixels don't darken the result
+	fn downsample( pixels: &[Color], size: u32 ) -> Vec< Color > {
+		let half = size / 2;
+		let mut result = Vec::with_capacity( ( half * half ) as usize );
+
+		for y in 0..half {
+			for x in 0..half {
+				let mut sum = [ 0.0f32; 4 ];
+				for ( dx, dy ) in [ ( 0, 0 ), ( 1, 0 ), ( 0, 1 ), ( 1, 1 ) ] {
+					let linear = pixels[ ( ( 2 * y + dy ) * size + 2 * x + dx ) as usize ].rgba_linear();
+					for c in 0..3 {
+						sum[ c ] += linear[ c ] * linear[ 3 ];
+					}
+					sum[ 3 ] += linear[ 3 ];
+				}
 
-			x += 1;
-			if x >= size {
-				y += 1;
-				x = 0;
+				let color = if sum[ 3 ] > 0.0 {
+					Color::from_linear( [ sum[ 0 ] / sum[ 3 ], sum[ 1 ] / sum[ 3 ], sum[ 2 ] / sum[ 3 ] ], sum[ 3 ] / 4.0 )
+				} else {
+					Color { rgba: [ 0.0; 4 ] }
+				};
+				result.push( color );
 			}
 		}
-		match imgbuf.save( &self.filename ) {
-			Err( e ) => {
-				println!("Error saving image {}", &e );
-				return
+
+		result
+	}
+
+	// Companion textures with the same layout as the palette, only if any entry has a material
+	// and no scalar is packed into alpha
+	fn save_materials( &self, size: u32, cells: &[u32] ) -> image::ImageResult< () > {
+		if self.alpha_scalar.is_some() || self.materials.iter().all( |m| m.is_none() ) {
+			return Ok( () );
+		}
+
+		let materials = || self.materials.iter().map( |m| m.unwrap_or_default() );
+		let scalar = |v: f32| image::Luma( [ Color::quantize_u8( v ) ] );
+
+		match self.material_maps {
+			MaterialMaps::Separate => {
+				Self::fill_image( size, self.cell_size, cells, materials().map( |m| scalar( m.roughness ) ) ).save( self.companion_filename( "roughness" ) )?;
+				Self::fill_image( size, self.cell_size, cells, materials().map( |m| scalar( m.metallic ) ) ).save( self.companion_filename( "metallic" ) )?;
+			},
+			MaterialMaps::Orm => {
+				Self::fill_image( size, self.cell_size, cells, materials().map( |m| image::Rgb( [
+					255,	// no occlusion
+					Color::quantize_u8( m.roughness ),
+					Color::quantize_u8( m.metallic ),
+				] ) ) ).save( self.companion_filename( "orm" ) )?;
 			},
-			_ => {},
 		}
-		self.was_modified = false;
+		Self::fill_image( size, self.cell_size, cells, materials().map( |m| scalar( m.emission ) ) ).save( self.companion_filename( "emission" ) )
+	}
+
+	// Only written if any entry has a label, entries are numbered by cell like they are loaded
+	fn save_labels( &self, suffix: &str, labels: &[Option<String>], cells: &[u32] ) -> std::io::Result< () > {
+		if labels.iter().all( |n| n.is_none() ) {
+			return Ok( () );
+		}
+
+		let text: String = labels.iter().zip( cells.iter() )
+			.filter_map( |( label, cell )| label.as_ref().map( |label| format!( "{} {}\n", cell + 1, label ) ) )
+			.collect();
+		std::fs::write( self.companion_path( suffix, "txt" ), text )
+	}
+
+	// Only written if the entries can't be told from the texture alone: if loading would drop entries stored like
+	// unused cells, e.g. the transparent end of a fade-out, or if the cells aren't filled in entry order (rows and
+	// fixed layouts), which loading would renumber. Otherwise an outdated list is removed.
+	fn save_entry_cells( &self, cells: &[u32], stored: &[Color] ) -> std::io::Result< () > {
+		let filename = self.companion_path( "entries", "txt" );
+		let looks_unused = |c: &Color| match ( self.format(), self.alpha_scalar ) {
+			// Radiance HDR has no alpha
+			( TextureFormat::Hdr, _ ) => c.rgba_u8()[ 0..3 ] == [ 0, 0, 0 ],
+			_ => c.rgba_u8() == [ 0, 0, 0, 0 ],
+		};
+		// Loading only drops them at the end, except for the rows and fixed layouts, see load
+		let dropped = if self.format() == TextureFormat::Hdr || self.alpha_scalar.is_some() || self.layout == Layout::Compact {
+			stored.last().is_some_and( looks_unused )
+		} else {
+			stored.iter().any( looks_unused )
+		};
+		let in_order = cells.iter().enumerate().all( |( i, &cell )| cell == i as u32 );
+		if in_order && !dropped {
+			return match std::fs::remove_file( &filename ) {
+				Err( e ) if e.kind() != std::io::ErrorKind::NotFound => Err( e ),
+				_ => Ok( () ),
+			};
+		}
+
+		let text: String = cells.iter().map( |cell| format!( "{}\n", cell + 1 ) ).collect();
+		std::fs::write( filename, text )
+	}
+
+	// Write each pixel into its cell (row by row) of a grid of size x size cells, unused cells stay zero
+	fn fill_image< P: image::Pixel >(
+		size: u32,
+		cell_size: u32,
+		cells: &[u32],
+		pixels: impl Iterator< Item = P >,
+	) -> image::ImageBuffer< P, Vec< P::Subpixel > > {
+		let mut imgbuf = image::ImageBuffer::new( size * cell_size, size * cell_size );
+
+		for ( &cell, pixel ) in cells.iter().zip( pixels ) {
+			let x = cell % size;
+			let y = cell / size;
+
+			if y >= size {
+				panic!("Tried to write to many pixels to image");	// should never trigger
+			}
+
+			for cy in 0..cell_size {
+				for cx in 0..cell_size {
+					let p = imgbuf.get_pixel_mut( x * cell_size + cx, y * cell_size + cy );
+					*p = pixel;
+				}
+			}
+		}
+
+		imgbuf
+	}
+
+	pub fn colors( &self ) -> &[Color] {
+		&self.colors
 	}
 
 	pub fn was_modified( &self ) -> bool {
 		self.was_modified
 	}
 
-	fn add_color( &mut self, color: &Color ) -> usize {
+	pub(crate) fn add_color( &mut self, color: &Color ) -> usize {
 		self.was_modified = true;
 		self.colors.push( *color );
+		self.materials.push( None );
+		self.names.push( None );
+		self.groups.push( None );
+		self.hdr.push( false );
+		self.cells.push( None );
 		self.colors.len()
 	}
 
+	pub fn names( &self ) -> &[Option<String>] {
+		&self.names
+	}
+
+	/// Names the entry at index (as returned by the add_* methods), so it can be referred to as @name
+	/// Fails for invalid names and names used by another entry
+	pub fn set_name( &mut self, index: usize, name: &str ) -> bool {
+		if !Self::is_valid_name( name ) || self.entry_by_name( name ).is_some_and( |i| i != index ) {
+			return false;
+		}
+		match self.names.get_mut( index.wrapping_sub( 1 ) ) {
+			Some( n ) => {
+				*n = Some( name.to_string() );
+				self.was_modified = true;
+				true
+			},
+			None => false,
+		}
+	}
+
+	pub fn groups( &self ) -> &[Option<String>] {
+		&self.groups
+	}
+
+	/// Puts the entry at index (as returned by the add_* methods) into a group, e.g. to keep a ramp together when sorting
+	/// Group names follow the rules of entry names, but are shared by all entries of the group
+	pub fn set_group( &mut self, index: usize, group: &str ) -> bool {
+		if !Self::is_valid_name( group ) {
+			return false;
+		}
+		match self.groups.get_mut( index.wrapping_sub( 1 ) ) {
+			Some( g ) => {
+				*g = Some( group.to_string() );
+				self.was_modified = true;
+				true
+			},
+			None => false,
+		}
+	}
+
+	// The first "gradient-N" no entry is grouped in yet
+	fn new_gradient_group( &self ) -> String {
+		( 1.. ).map( |n| format!( "gradient-{}", n ) )
+			.find( |g| !self.groups.iter().any( |e| e.as_deref() == Some( g.as_str() ) ) )
+			.unwrap_or_default()
+	}
+
+	/// Shifts all entries from one lighting temperature to another (Bradford chromatic adaptation)
+	/// Returns false if a temperature is outside of the supported range
+	pub fn white_balance( &mut self, from_kelvin: f32, to_kelvin: f32 ) -> bool {
+		let ( Some( from ), Some( to ) ) = ( Color::kelvin_to_xyz( from_kelvin ), Color::kelvin_to_xyz( to_kelvin ) ) else {
+			return false;
+		};
+
+		let matrix = Color::bradford_matrix( from, to );
+		for color in self.colors.iter_mut() {
+			*color = color.transform_linear( &matrix );
+		}
+		self.was_modified = true;
+		true
+	}
+
+	/// Merges entries within threshold of an earlier entry into it and compacts the palette
+	/// Entries are only merged if alpha and material match, a merged name is kept if the earlier entry has none
+	pub fn dedupe( &mut self, method: DeltaE, threshold: f32 ) -> Vec< RemapEntry > {
+		let mut kept: Vec< usize > = Vec::new();
+		let mut mapping = Vec::with_capacity( self.colors.len() );
+
+		for i in 0..self.colors.len() {
+			match self.closest_duplicate( kept.iter().copied(), &self.colors[ i ], self.materials[ i ], method, threshold ) {
+				Some( k ) => {
+					if self.names[ k - 1 ].is_none() {
+						self.names[ k - 1 ] = self.names[ i ].take();
+					}
+					mapping.push( mapping[ k - 1 ] );
+				},
+				None => {
+					kept.push( i + 1 );
+					mapping.push( kept.len() );
+				},
+			}
+		}
+
+		self.rearrange( &kept, &mapping, false )
+	}
+
+	/// The entry closest to color within threshold, with the same alpha and material, e.g. to reuse instead of adding color
+	pub fn find_duplicate( &self, color: &Color, material: Option< Material >, method: DeltaE, threshold: f32 ) -> Option< usize > {
+		self.closest_duplicate( 1..=self.colors.len(), color, material, method, threshold )
+	}
+
+	fn closest_duplicate(
+		&self,
+		candidates: impl Iterator< Item = usize >,
+		color: &Color,
+		material: Option< Material >,
+		method: DeltaE,
+		threshold: f32,
+	) -> Option< usize > {
+		candidates
+			.filter( |&k| {
+				let other = &self.colors[ k - 1 ];
+				Color::quantize_u8( other.alpha() ) == Color::quantize_u8( color.alpha() )
+					&& self.materials[ k - 1 ].unwrap_or_default() == material.unwrap_or_default()
+			} )
+			.map( |k| ( k, self.colors[ k - 1 ].delta_e( color, method ) ) )
+			.filter( |( _, delta_e )| *delta_e <= threshold )
+			.min_by( |a, b| a.1.total_cmp( &b.1 ) )
+			.map( |( k, _ )| k )
+	}
+
+	/// Clusters the entries in OKLab (and alpha) down to count and keeps the entry closest to each cluster's centroid
+	/// Clusters are merged bottom-up, always the pair adding the least variance (Ward's method)
+	/// Entries with different materials are only merged once no clusters with the same material are left,
+	/// they take the material of the kept entry
+	pub fn reduce( &mut self, count: usize ) -> Vec< RemapEntry > {
+		let points: Vec< [f32; 4] > = self.colors.iter().map( |c| {
+			let lab = c.to_oklab();
+			[ lab[ 0 ], lab[ 1 ], lab[ 2 ], c.alpha() ]
+		} ).collect();
+		let distance = |a: &[f32; 4], b: &[f32; 4]| a.iter().zip( b.iter() ).map( |( a, b )| ( a - b ) * ( a - b ) ).sum::< f32 >();
+		let material = |i: usize| self.materials[ i ].unwrap_or_default();
+
+		// Members (0-based) and centroid of each cluster
+		let mut clusters: Vec< ( Vec< usize >, [f32; 4] ) > = points.iter().enumerate().map( |( i, p )| ( vec![ i ], *p ) ).collect();
+		while clusters.len() > count.max( 1 ) {
+			// Clusters only mix materials after a merge that did, so their first entries tell
+			let mut best = ( 0, 1, true, f32::INFINITY );
+			for a in 0..clusters.len() {
+				for b in a + 1..clusters.len() {
+					let ( na, nb ) = ( clusters[ a ].0.len() as f32, clusters[ b ].0.len() as f32 );
+					let cost = na * nb / ( na + nb ) * distance( &clusters[ a ].1, &clusters[ b ].1 );
+					let mixed = material( clusters[ a ].0[ 0 ] ) != material( clusters[ b ].0[ 0 ] );
+					if ( best.2 && !mixed ) || ( mixed == best.2 && cost < best.3 ) {
+						best = ( a, b, mixed, cost );
+					}
+				}
+			}
+
+			let ( a, b, _, _ ) = best;
+			let ( members, centroid ) = clusters.remove( b );
+			let ( na, nb ) = ( clusters[ a ].0.len() as f32, members.len() as f32 );
+			for ( c, m ) in clusters[ a ].1.iter_mut().zip( centroid.iter() ) {
+				*c = ( *c * na + m * nb ) / ( na + nb );
+			}
+			clusters[ a ].0.extend( members );
+		}
+
+		// Clusters stay in the order of their first entry
+		let mut kept = Vec::with_capacity( clusters.len() );
+		let mut mapping = vec![ 0; self.colors.len() ];
+		for ( n, ( members, centroid ) ) in clusters.iter().enumerate() {
+			let representative = *members.iter()
+				.min_by( |&&a, &&b| distance( &points[ a ], centroid ).total_cmp( &distance( &points[ b ], centroid ) ) )
+				.unwrap_or( &members[ 0 ] );
+			if self.names[ representative ].is_none() {
+				if let Some( named ) = members.iter().find( |&&m| self.names[ m ].is_some() ) {
+					self.names[ representative ] = self.names[ *named ].take();
+				}
+			}
+			kept.push( representative + 1 );
+			for &m in members {
+				mapping[ m ] = n + 1;
+			}
+		}
+
+		self.rearrange( &kept, &mapping, false )
+	}
+
+	/// Reorders the entries, with per_group entries are only sorted within their group, keeping the cells the group used
+	/// Ungrouped entries are sorted among each other
+	pub fn sort( &mut self, key: SortKey, per_group: bool ) -> Vec< RemapEntry > {
+		let mut order: Vec< usize > = ( 0..self.colors.len() ).collect();
+
+		if per_group {
+			let mut done: Vec< Option< &String > > = Vec::new();
+			for group in self.groups.iter().map( |g| g.as_ref() ) {
+				if done.contains( &group ) {
+					continue;
+				}
+				done.push( group );
+				let slots: Vec< usize > = ( 0..self.colors.len() ).filter( |&i| self.groups[ i ].as_ref() == group ).collect();
+				for ( slot, entry ) in slots.iter().zip( self.sorted( &slots, key ) ) {
+					order[ *slot ] = entry;
+				}
+			}
+		} else {
+			order = self.sorted( &order, key );
+		}
+
+		let kept: Vec< usize > = order.iter().map( |&i| i + 1 ).collect();
+		let mut mapping = vec![ 0; self.colors.len() ];
+		for ( n, &i ) in order.iter().enumerate() {
+			mapping[ i ] = n + 1;
+		}
+		self.rearrange( &kept, &mapping, true )
+	}
+
+	// The given entries (0-based) in sorted order
+	fn sorted( &self, entries: &[usize], key: SortKey ) -> Vec< usize > {
+		let lch: Vec< [f32; 3] > = entries.iter().map( |&i| {
+			let mut lch = self.colors[ i ].to_oklch();
+			lch[ 2 ] = lch[ 2 ].rem_euclid( std::f32::consts::TAU );
+			lch
+		} ).collect();
+		let gray = |lch: &[f32; 3]| lch[ 1 ] < ACHROMATIC_CHROMA;
+
+		// Stable sort by a tuple of keys
+		let keys: Vec< ( f32, f32, f32 ) > = lch.iter().zip( entries.iter() ).map( |( lch, &i )| match key {
+			SortKey::Hue => if gray( lch ) { ( 0.0, lch[ 0 ], 0.0 ) } else { ( 1.0, lch[ 2 ], lch[ 0 ] ) },
+			SortKey::Lightness => ( lch[ 0 ], 0.0, 0.0 ),
+			SortKey::Chroma => ( lch[ 1 ], 0.0, 0.0 ),
+			SortKey::Oklch => if gray( lch ) {
+				( 0.0, lch[ 0 ], 0.0 )
+			} else {
+				( 1.0 + ( lch[ 2 ].to_degrees() / 30.0 ).floor(), lch[ 0 ], 0.0 )
+			},
+			SortKey::Luminance => {
+				let rgb = self.colors[ i ].rgb_linear();
+				( 0.2126 * rgb[ 0 ] + 0.7152 * rgb[ 1 ] + 0.0722 * rgb[ 2 ], 0.0, 0.0 )
+			},
+			SortKey::Path => ( 0.0, 0.0, 0.0 ),
+		} ).collect();
+
+		let mut positions: Vec< usize > = ( 0..entries.len() ).collect();
+		if key == SortKey::Path {
+			let labs: Vec< [f32; 3] > = entries.iter().map( |&i| self.colors[ i ].to_oklab() ).collect();
+			positions = Self::perceptual_path( &labs );
+		} else {
+			positions.sort_by( |&a, &b| {
+				let ( ka, kb ) = ( keys[ a ], keys[ b ] );
+				ka.0.total_cmp( &kb.0 ).then( ka.1.total_cmp( &kb.1 ) ).then( ka.2.total_cmp( &kb.2 ) )
+			} );
+		}
+		positions.iter().map( |&p| entries[ p ] ).collect()
+	}
+
+	// Open path through all colors starting at the darkest, built nearest neighbour first and then
+	// improved by reversing sections (2-opt) as long as that shortens it
+	fn perceptual_path( labs: &[[f32; 3]] ) -> Vec< usize > {
+		if labs.is_empty() {
+			return Vec::new();
+		}
+		let distance = |a: usize, b: usize| Color::delta_e_ok( labs[ a ], labs[ b ] );
+
+		let start = ( 0..labs.len() ).min_by( |&a, &b| labs[ a ][ 0 ].total_cmp( &labs[ b ][ 0 ] ) ).unwrap_or( 0 );
+		let mut path = vec![ start ];
+		let mut left: Vec< usize > = ( 0..labs.len() ).filter( |&i| i != start ).collect();
+		while !left.is_empty() {
+			let last = path[ path.len() - 1 ];
+			let ( n, _ ) = left.iter().enumerate()
+				.min_by( |a, b| distance( last, *a.1 ).total_cmp( &distance( last, *b.1 ) ) )
+				.unwrap_or( ( 0, &0 ) );
+			path.push( left.remove( n ) );
+		}
+
+		// Reversing path[i..=j] replaces the edges (i-1, i) and (j, j+1), the start stays in place
+		let mut improved = true;
+		while improved {
+			improved = false;
+			for i in 1..path.len() {
+				for j in i + 1..path.len() {
+					let before = distance( path[ i - 1 ], path[ i ] ) + path.get( j + 1 ).map_or( 0.0, |&n| distance( path[ j ], n ) );
+					let after = distance( path[ i - 1 ], path[ j ] ) + path.get( j + 1 ).map_or( 0.0, |&n| distance( path[ i ], n ) );
+					if after < before - 1e-6 {
+						path[ i..=j ].reverse();
+						improved = true;
+					}
+				}
+			}
+		}
+		path
+	}
+
+	// Replaces the entries by the old entries listed in kept (1-based), mapping holds the new index of every old entry
+	// Kept entries take their cell along, with keep_slots the n-th entry takes the n-th old cell instead (fixed layout)
+	fn rearrange( &mut self, kept: &[usize], mapping: &[usize], keep_slots: bool ) -> Vec< RemapEntry > {
+		let old_uvs: Vec< [f32; 2] > = ( 1..=self.colors.len() ).map( |i| self.entry_uv( i ).unwrap_or_default() ).collect();
+
+		if let Some( ( _, cells ) ) = self.cell_layout() {
+			self.cells = cells.into_iter().map( Some ).collect();
+		}
+		self.cells = match keep_slots {
+			true => self.cells[ ..kept.len() ].to_vec(),
+			false => kept.iter().map( |&k| self.cells[ k - 1 ] ).collect(),
+		};
+		self.remapped = true;
+
+		self.colors = kept.iter().map( |&k| self.colors[ k - 1 ] ).collect();
+		self.materials = kept.iter().map( |&k| self.materials[ k - 1 ] ).collect();
+		self.names = kept.iter().map( |&k| self.names[ k - 1 ].take() ).collect();
+		self.groups = kept.iter().map( |&k| self.groups[ k - 1 ].take() ).collect();
+		self.hdr = kept.iter().map( |&k| self.hdr[ k - 1 ] ).collect();
+		self.was_modified = true;
+
+		mapping.iter().enumerate().map( |( i, &new_index )| RemapEntry {
+			old_index: i + 1,
+			new_index,
+			old_uv: old_uvs[ i ],
+			new_uv: self.entry_uv( new_index ).unwrap_or_default(),
+		} ).collect()
+	}
+
+	/// Default filename of the remap table written when entries are rearranged
+	pub fn remap_filename( &self ) -> String {
+		self.companion_path( "remap", "csv" )
+	}
+
+	/// Where the loaded entries move when saving, e.g. because the texture grows
+	/// Empty if none of them moves or the change already returned a remap, like sort or relayout
+	pub fn pending_remap( &self ) -> Vec< RemapEntry > {
+		let ( false, Some( saved_size ), Some( ( size, cells ) ) ) = ( self.remapped, self.saved_size, self.cell_layout() ) else {
+			return Vec::new();
+		};
+		let cell_uv = |cell: u32, size: u32| [ cell % size, cell / size ].map( |c| ( c as f32 + 0.5 ) / size as f32 );
+		let remap: Vec< RemapEntry > = self.cells.iter().zip( cells.iter() ).enumerate()
+			.filter_map( |( i, ( old, &new ) )| old.map( |old| RemapEntry {
+				old_index: i + 1,
+				new_index: i + 1,
+				old_uv: cell_uv( old, saved_size ),
+				new_uv: cell_uv( new, size ),
+			} ) )
+			.collect();
+		if remap.iter().all( |e| e.old_uv == e.new_uv ) {
+			return Vec::new();
+		}
+		remap
+	}
+
+	/// Index (as returned by the add_* methods) of an entry reference like @5, @last or @name
+	pub fn entry_index( &self, reference: &str ) -> Option< usize > {
+		expression::resolve_reference( reference, self.colors.len(), &self.names )
+	}
+
+	/// Index (as returned by the add_* methods) of the entry with the given name
+	pub fn entry_by_name( &self, name: &str ) -> Option< usize > {
+		self.names.iter().position( |n| n.as_deref() == Some( name ) ).map( |i| i + 1 )
+	}
+
+	// Letters, digits, '-' and '_', but not only digits and not "last", which are references themselves
+	fn is_valid_name( name: &str ) -> bool {
+		!name.is_empty()
+			&& name.chars().all( |c| c.is_alphanumeric() || c == '-' || c == '_' )
+			&& !name.chars().all( |c| c.is_ascii_digit() )
+			&& name != "last"
+	}
+
+	pub fn materials( &self ) -> &[Option<Material>] {
+		&self.materials
+	}
+
+	/// Sets the material scalars of the entry at index (as returned by the add_* methods)
+	pub fn set_material( &mut self, index: usize, material: Material ) -> bool {
+		match self.materials.get_mut( index.wrapping_sub( 1 ) ) {
+			Some( m ) => {
+				*m = Some( material );
+				self.was_modified = true;
+				true
+			},
+			None => false,
+		}
+	}
+
 	pub fn add_color_rgb( &mut self, r: u8, g: u8, b: u8 ) -> usize {
 		let a = 0xff;
 
@@ -381,84 +2737,157 @@ impl LowTexPal {
 		self.add_color( &color )
 	}
 
+	/// Parses a color expression, which may refer to entries of this palette
+	pub fn parse_color( &self, expression: &str ) -> Result< Color, ColorParseError > {
+		expression::evaluate_named( expression, &self.colors, &self.names )
+	}
+
 	pub fn add_color_string( &mut self, color_string: &str ) -> Option< usize > {
-		match Color::from_string( &color_string ) {
+		match self.parse_color( color_string ).ok() {
 			None => {
 						dbg!("No Match");
 						None
 			},
 			Some( color ) => {
-				return Some( self.add_color( &color ) )				
+				Some( self.add_color( &color ) )
 			}
 		}
 	}
 
+	/// Adds a color with its linear RGB scaled by intensity, e.g. 4.0 for an emissive entry
+	/// Any intensity other than 1.0 makes values above 1.0 an HDR entry instead of out of gamut
+	pub fn add_color_string_intensity( &mut self, color_string: &str, intensity: f32 ) -> Result< usize, ColorParseError > {
+		let color = self.parse_color( color_string )?.with_intensity( intensity );
+		let index = self.add_color( &color );
+		self.hdr[ index - 1 ] = intensity != 1.0;
+		Ok( index )
+	}
+
 	pub fn add_gradient_strings( &mut self, start_color_string: &str, end_color_string: &str, steps: u32 ) -> Option< Vec< usize > > {
 		// Call new method with RGB colorspace for backward compatibility
 		self.add_gradient_colorspace(start_color_string, end_color_string, steps, "rgb")
 	}
 
 	pub fn add_gradient_colorspace( &mut self, start_color_string: &str, end_color_string: &str, steps: u32, colorspace: &str ) -> Option< Vec< usize > > {
-		match ( Color::from_string( &start_color_string ), Color::from_string( &end_color_string ) ) {
-			( Some( start_color) , Some( end_color ) ) => {
-				let mut indices = Vec::new();
-
-				match colorspace.to_lowercase().as_str() {
-					"oklab" => {
-						// Interpolate in OKLab space
-						let start_lab = start_color.to_oklab();
-						let end_lab = end_color.to_oklab();
-
-						for i in 0..steps {
-							let t = i as f32 / (steps - 1) as f32;
-							let interpolated_lab = [
-								start_lab[0] + t * (end_lab[0] - start_lab[0]),
-								start_lab[1] + t * (end_lab[1] - start_lab[1]),
-								start_lab[2] + t * (end_lab[2] - start_lab[2]),
-							];
-							let color = Color::from_oklab(interpolated_lab);
-							indices.push( self.add_color( &color ) );
-						}
-					},
-					"oklch" => {
-						// Interpolate in OKLCH space
-						let start_lch = start_color.to_oklch();
-						let end_lch = end_color.to_oklch();
-
-						// Handle hue interpolation (shortest path around color wheel)
-						let mut hue_diff = end_lch[2] - start_lch[2];
-						if hue_diff > std::f32::consts::PI {
-							hue_diff -= 2.0 * std::f32::consts::PI;
-						} else if hue_diff < -std::f32::consts::PI {
-							hue_diff += 2.0 * std::f32::consts::PI;
-						}
-
-						for i in 0..steps {
-							let t = i as f32 / (steps - 1) as f32;
-							let interpolated_lch = [
-								start_lch[0] + t * (end_lch[0] - start_lch[0]),
-								start_lch[1] + t * (end_lch[1] - start_lch[1]),
-								start_lch[2] + t * hue_diff,
-							];
-							let color = Color::from_oklch(interpolated_lch);
-							indices.push( self.add_color( &color ) );
-						}
-					},
-					_ => {
-						// Default: RGB interpolation (existing behavior)
-						let delta = ( end_color - start_color ) / ( steps - 1 );
-						let mut color = start_color;
-						for _s in 0..steps {
-							indices.push( self.add_color( &color ) );
-							color += delta;
-						}
-					}
+		// Per-channel clipping and straight alpha for backward compatibility
+		self.add_gradient_with_options( start_color_string, end_color_string, steps, colorspace, &GradientOptions::default() )
+			.map( |( indices, _out_of_gamut )| indices )
+			.ok()
+	}
+
+	// Interpolate three color components and alpha
+	// With premultiplied alpha all components except the hue are weighted by alpha before mixing
+	pub(crate) fn interpolate(
+		start: [f32; 3],
+		end: [f32; 3],
+		start_alpha: f32,
+		end_alpha: f32,
+		t: f32,
+		premultiplied: bool,
+		hue_channel: Option< usize >,
+	) -> ( [f32; 3], f32 ) {
+		let alpha = start_alpha + t * ( end_alpha - start_alpha );
+		let mut components = [ 0.0; 3 ];
+
+		for c in 0..3 {
+			let straight = start[ c ] + t * ( end[ c ] - start[ c ] );
+			components[ c ] = if premultiplied && hue_channel != Some( c ) && alpha > 0.0 {
+				let s = start[ c ] * start_alpha;
+				let e = end[ c ] * end_alpha;
+				( s + t * ( e - s ) ) / alpha
+			} else {
+				// Fully transparent results keep the straight interpolated color
+				straight
+			};
+		}
+
+		( components, alpha )
+	}
+
+	/// Adds a gradient and reports the generated entries that were outside of the sRGB gamut
+	pub fn add_gradient_with_options(
+		&mut self,
+		start_color_string: &str,
+		end_color_string: &str,
+		steps: u32,
+		colorspace: &str,
+		options: &GradientOptions,
+	) -> Result< ( Vec< usize >, Vec< OutOfGamut > ), ColorParseError > {
+		let start_color = self.parse_color( start_color_string )?;
+		let end_color = self.parse_color( end_color_string )?;
+
+		let colorspace = colorspace.to_lowercase();
+		let ( start, end, hue_channel ) = match colorspace.as_str() {
+			// Interpolate in OKLab space
+			"oklab" => ( start_color.to_oklab(), end_color.to_oklab(), None ),
+			"oklch" => {
+				// Interpolate in OKLCH space
+				let ( start_lch, mut end_lch ) = Color::fix_missing_hues( start_color.to_oklch(), end_color.to_oklch() );
+
+				// Handle hue interpolation (shortest path around color wheel)
+				let mut hue_diff = end_lch[2] - start_lch[2];
+				if hue_diff > std::f32::consts::PI {
+					hue_diff -= 2.0 * std::f32::consts::PI;
+				} else if hue_diff < -std::f32::consts::PI {
+					hue_diff += 2.0 * std::f32::consts::PI;
 				}
+				end_lch[2] = start_lch[2] + hue_diff;
 
-				Some( indices )
+				( start_lch, end_lch, Some( 2 ) )
 			},
-			_ => None,
+			_ => {
+				// Default: RGB interpolation
+				let s = start_color.rgba();
+				let e = end_color.rgba();
+				( [ s[0], s[1], s[2] ], [ e[0], e[1], e[2] ], None )
+			},
+		};
+
+		let mut indices = Vec::new();
+		let mut out_of_gamut = Vec::new();
+
+		for i in 0..steps {
+			// Endpoints are stored exactly, without a round trip through the interpolation colorspace
+			if i == 0 || i + 1 == steps {
+				let color = if i == 0 { start_color } else { end_color };
+				indices.push( self.add_color( &color ) );
+				continue;
+			}
+
+			let t = i as f32 / (steps - 1) as f32;
+			let ( components, alpha ) = Self::interpolate(
+				start, end,
+				start_color.alpha(), end_color.alpha(),
+				t, options.premultiplied_alpha, hue_channel,
+			);
+
+			let lab = match colorspace.as_str() {
+				"oklab" => components,
+				"oklch" => Color::oklch_to_oklab( components ),
+				_ => {
+					// RGB interpolation stays in gamut
+					let color = Color{ rgba: [ components[0], components[1], components[2], alpha ] };
+					indices.push( self.add_color( &color ) );
+					continue;
+				},
+			};
+
+			let color = Color::from_oklab_mapped( lab, options.gamut_mapping ).with_alpha( alpha );
+			let index = self.add_color( &color );
+			if !Color::oklab_in_gamut( lab ) {
+				let delta_e = Color::delta_e_ok( color.to_oklab(), lab );
+				out_of_gamut.push( OutOfGamut{ index, delta_e } );
+			}
+			indices.push( index );
 		}
+
+		// Gradients stay together when the palette is sorted or laid out
+		let group = self.new_gradient_group();
+		for &index in &indices {
+			self.groups[ index - 1 ] = Some( group.clone() );
+		}
+
+		Ok( ( indices, out_of_gamut ) )
 	}
 
 }
@@ -1,6 +1,6 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 
-use lowtexpal::LowTexPal;
+use lowtexpal::{model, query, remap, BitDepth, Color, DeltaE, Encoding, GamutMapping, GradientOptions, Layout, LowTexPal, Material, MaterialMaps, MaterialScalar, RemapEntry, SortKey, TextureFormat, MAX_IMAGE_SIZE};
 
 #[derive(Parser)]
 #[command(name = "lowtexpal")]
@@ -12,14 +12,87 @@ struct Cli {
 	#[arg(short = 'f', long, value_name = "FILE")]
 	file: Option<String>,
 
+	/// Set the file format (png, tga, bmp, dds, ktx2, exr, hdr), defaults to the file extension
+	#[arg(long, value_name = "FORMAT")]
+	format: Option<String>,
+
 	/// Set minimum width for the output image (will be rounded up to power of 2)
 	#[arg(long, value_name = "MIN_WIDTH")]
 	min_width: Option<u32>,
 
+	/// Set the size of each palette cell in pixels (will be rounded up to power of 2)
+	#[arg(long, value_name = "CELL_SIZE")]
+	cell_size: Option<u32>,
+
+	/// Write a mip chain to DDS and KTX2, keeping cells pure down to this mip level
+	#[arg(long, value_name = "LEVELS")]
+	pure_mips: Option<u32>,
+
+	/// Reserve a border of this many pixels inside each cell that UVs keep away from, find reports the safe UV area
+	#[arg(long, value_name = "PIXELS")]
+	gutter: Option<u32>,
+
+	/// Set bits per channel of the output image (8, 16), defaults to the bit depth of the loaded file
+	#[arg(long, value_name = "BIT_DEPTH")]
+	bit_depth: Option<u32>,
+
+	/// Set the encoding of the output image (srgb, linear), defaults to the encoding of the loaded file
+	#[arg(long, value_name = "ENCODING")]
+	encoding: Option<String>,
+
+	/// How wide gamut entries (e.g. Display P3) are mapped into sRGB on save (clip, css)
+	#[arg(long, value_name = "GAMUT_MAPPING")]
+	output_gamut_mapping: Option<String>,
+
+	/// How material scalars are written next to the palette (separate, orm)
+	#[arg(long, value_name = "MATERIAL_MAPS")]
+	material_maps: Option<String>,
+
+	/// Pack a material scalar into the alpha channel instead of writing material maps (roughness, metallic, emission)
+	#[arg(long, value_name = "SCALAR")]
+	alpha_scalar: Option<String>,
+
+	/// How entries are arranged in the texture (compact, rows, fixed), has to match the layout of the loaded file
+	#[arg(long, value_name = "LAYOUT")]
+	layout: Option<String>,
+
+	/// Where commands that rearrange entries write the remap table, defaults to <name>_remap.csv
+	#[arg(long, value_name = "FILE")]
+	remap_table: Option<String>,
+
 	#[command(subcommand)]
 	command: Option<Commands>,
 }
 
+#[derive(Args)]
+struct MaterialArgs {
+	/// Set the roughness of the added entries (0-1)
+	#[arg(long, value_name = "ROUGHNESS")]
+	roughness: Option<f32>,
+
+	/// Set the metallic value of the added entries (0-1)
+	#[arg(long, value_name = "METALLIC")]
+	metallic: Option<f32>,
+
+	/// Set the emission strength of the added entries (0-1)
+	#[arg(long, value_name = "EMISSION")]
+	emission: Option<f32>,
+}
+
+impl MaterialArgs {
+	fn material( &self ) -> Option< Material > {
+		if self.roughness.is_none() && self.metallic.is_none() && self.emission.is_none() {
+			return None;
+		}
+		let default = Material::default();
+		Some( Material {
+			roughness: self.roughness.unwrap_or( default.roughness ),
+			metallic: self.metallic.unwrap_or( default.metallic ),
+			emission: self.emission.unwrap_or( default.emission ),
+		} )
+	}
+}
+
 #[derive(Subcommand)]
 enum Commands {
 	/// Add a single color to the palette
@@ -28,6 +101,21 @@ enum Commands {
 		#[arg(short = 'c', long, value_name = "COLOR")]
 		color: Option<String>,
 
+		/// Scale the linear color, values above 1 create HDR entries (saved to .exr or .hdr)
+		#[arg(long, value_name = "INTENSITY", default_value = "1.0")]
+		intensity: f32,
+
+		/// Name the added entry, so it can be referred to as @name
+		#[arg(long, value_name = "NAME")]
+		name: Option<String>,
+
+		/// Put the added entry into a group, which is kept together by sort --per-group
+		#[arg(long, value_name = "GROUP")]
+		group: Option<String>,
+
+		#[command(flatten)]
+		material: MaterialArgs,
+
 		/// Force the color to be added even if it already exists
 		#[arg(long)]
 		force: bool,
@@ -51,10 +139,339 @@ enum Commands {
 		#[arg(long, value_name = "COLORSPACE", default_value = "rgb")]
 		colorspace: String,
 
+		/// How out of gamut colors are mapped into sRGB (clip, css)
+		#[arg(long, value_name = "GAMUT_MAPPING", default_value = "clip")]
+		gamut_mapping: String,
+
+		/// Interpolate premultiplied alpha instead of straight alpha
+		#[arg(long)]
+		premultiplied: bool,
+
+		/// Name the group of the added entries, defaults to gradient-N
+		#[arg(long, value_name = "GROUP")]
+		group: Option<String>,
+
+		#[command(flatten)]
+		material: MaterialArgs,
+
 		/// Force the colors to be added even if they already exist
 		#[arg(long)]
 		force: bool,
 	},
+
+	/// Shift the whole palette from one lighting temperature to another
+	WhiteBalance {
+		/// Temperature the palette was made for, e.g. 6500K
+		#[arg(long, value_name = "KELVIN")]
+		from: String,
+
+		/// Temperature of the new lighting, e.g. 3200K
+		#[arg(long, value_name = "KELVIN")]
+		to: String,
+	},
+
+	/// Name an existing palette entry, so it can be referred to as @name
+	Name {
+		/// The entry to be named (@5, @last or @name)
+		#[arg(short = 'e', long, value_name = "ENTRY")]
+		entry: String,
+
+		/// The new name
+		#[arg(short = 'n', long, value_name = "NAME")]
+		name: String,
+	},
+
+	/// Find the palette entries closest to a color, or map a CSV/JSON list of colors to entries
+	Find {
+		/// The color to look up
+		#[arg(short = 'c', long, value_name = "COLOR")]
+		color: Option<String>,
+
+		/// Number of entries to print for a single color
+		#[arg(long, value_name = "COUNT", default_value = "3")]
+		count: usize,
+
+		/// Color difference used to rank entries (ok, cie76, cie94, ciede2000)
+		#[arg(long, value_name = "DELTA_E", default_value = "ok")]
+		delta_e: String,
+
+		/// A .csv or .json file with colors to map to their nearest entries
+		#[arg(short = 'i', long, value_name = "FILE")]
+		input: Option<String>,
+
+		/// Write the mappings to this file instead of printing them
+		#[arg(short = 'o', long, value_name = "FILE")]
+		output: Option<String>,
+	},
+
+	/// Merge entries that are visually identical and write a remap table
+	Dedupe {
+		/// Largest color difference of entries to be merged
+		#[arg(long, value_name = "THRESHOLD", default_value = "1.5")]
+		threshold: f32,
+
+		/// Color difference used for the threshold (ok, cie76, cie94, ciede2000)
+		#[arg(long, value_name = "DELTA_E", default_value = "ciede2000")]
+		delta_e: String,
+	},
+
+	/// Reduce the palette to a number of entries by clustering similar colors and write a remap table
+	Reduce {
+		/// Number of entries to keep, e.g. 256 for a 16x16 texture
+		#[arg(short = 'n', long, value_name = "COUNT")]
+		count: usize,
+	},
+
+	/// Reorder the palette entries and write a remap table
+	Sort {
+		/// Sort key (hue, lightness, chroma, oklch, luminance, path)
+		#[arg(long, value_name = "KEY")]
+		by: String,
+
+		/// Only sort entries within their group, groups keep their cells
+		#[arg(long)]
+		per_group: bool,
+	},
+
+	/// Arrange the entries with another layout and write a remap table
+	Relayout {
+		/// The new layout (compact, rows, fixed), fixed grows to --min-width keeping pixel positions
+		#[arg(long, value_name = "LAYOUT")]
+		to: String,
+	},
+
+	/// Rewrite the UVs of an OBJ model to where its palette cells moved, using a remap table
+	RemapModel {
+		/// The model to be rewritten
+		#[arg(short = 'i', long, value_name = "FILE")]
+		input: String,
+
+		/// Where to write the rewritten model, defaults to overwriting the input
+		#[arg(short = 'o', long, value_name = "FILE")]
+		output: Option<String>,
+	},
+
+	/// Add the flat material colors (Kd) of an OBJ model to the palette and write a model textured with it
+	BakeObj {
+		/// The model, its materials are read from the .mtl files it references
+		#[arg(short = 'i', long, value_name = "FILE")]
+		input: String,
+
+		/// Where to write the baked model, an .mtl file with the same name is written next to it
+		#[arg(short = 'o', long, value_name = "FILE")]
+		output: String,
+
+		/// Encoding of the Kd values (srgb, linear), Blender writes linear values
+		#[arg(long, value_name = "ENCODING", default_value = "linear")]
+		kd_encoding: String,
+
+		/// Reuse entries within this color difference instead of adding a new one
+		#[arg(long, value_name = "THRESHOLD", default_value = "1.5")]
+		threshold: f32,
+
+		/// Color difference used for the threshold (ok, cie76, cie94, ciede2000)
+		#[arg(long, value_name = "DELTA_E", default_value = "ciede2000")]
+		delta_e: String,
+	},
+}
+
+// Report how far each added entry moved when stored with the output bit depth
+fn print_quantization( lowtexpal: &LowTexPal, indices: &[usize] ) {
+	for &index in indices {
+		let color = &lowtexpal.colors()[ index - 1 ];
+		// HDR entries are stored as floats, only clamped when saving to a regular format
+		if lowtexpal.is_hdr_entry( index ) {
+			println!("Entry {}: linear {:?} (HDR)", index, color.rgba_linear() );
+			continue;
+		}
+		// Wide gamut entries are reported with their gamut mapping on save
+		if color.is_hdr() || !color.in_srgb_gamut() {
+			println!("Entry {}: linear {:?} (outside of sRGB)", index, color.rgba_linear() );
+			continue;
+		}
+		match lowtexpal.bit_depth() {
+			BitDepth::Eight => println!("Entry {}: {:?} (quantization ΔE OK {:.5})", index, color.rgba_u8(), color.quantization_error( BitDepth::Eight ) ),
+			BitDepth::Sixteen => println!("Entry {}: {:?} (quantization ΔE OK {:.7})", index, color.rgba_u16(), color.quantization_error( BitDepth::Sixteen ) ),
+		}
+	}
+}
+
+fn set_materials( lowtexpal: &mut LowTexPal, indices: &[usize], material: &MaterialArgs ) {
+	if let Some( material ) = material.material() {
+		for &index in indices {
+			lowtexpal.set_material( index, material );
+		}
+	}
+}
+
+fn set_group( lowtexpal: &mut LowTexPal, indices: &[usize], group: &str ) {
+	if indices.iter().all( |&i| lowtexpal.set_group( i, group ) ) {
+		println!("Grouped {:?} as {}", indices, group );
+	} else {
+		println!("Couldn't group {:?} as {}, groups need to be made of letters, digits, '-' and '_'", indices, group );
+	}
+}
+
+fn set_name( lowtexpal: &mut LowTexPal, index: usize, name: &str ) {
+	if lowtexpal.set_name( index, name ) {
+		println!("Named entry {} {}", index, name );
+	} else {
+		println!("Couldn't name entry {} {}, names need to be unique and made of letters, digits, '-' and '_'", index, name );
+	}
+}
+
+fn write_remap_table( lowtexpal: &LowTexPal, filename: Option< &str >, remap: &[RemapEntry] ) {
+	for entry in remap.iter().filter( |e| e.old_index != e.new_index ) {
+		println!("Entry {} -> {}", entry.old_index, entry.new_index );
+	}
+	let filename = filename.map_or_else( || lowtexpal.remap_filename(), |f| f.to_string() );
+	if std::path::Path::new( &filename ).exists() {
+		println!("Warning: Overwriting remap table {} of an earlier change, models not remapped with it yet can't be remapped anymore. Use --remap-table to keep it", &filename );
+	}
+	match remap::write_table( &filename, remap ) {
+		Ok( () ) => println!("Wrote remap table {}", &filename ),
+		Err( e ) => println!("Error writing remap table {}", e ),
+	}
+}
+
+fn remap_model( table: &str, input: &str, output: &str ) {
+	let remap = match remap::read_table( table ) {
+		Ok( remap ) => remap,
+		Err( e ) => {
+			println!("Couldn't read remap table {}: {}", table, e );
+			return;
+		},
+	};
+	let obj = match std::fs::read_to_string( input ) {
+		Ok( obj ) => obj,
+		Err( e ) => {
+			println!("Couldn't read {}: {}", input, e );
+			return;
+		},
+	};
+	let Some( result ) = model::remap_obj( &obj, &remap ) else {
+		println!("Remap table {} has no valid entries", table );
+		return;
+	};
+	if result.unmapped > 0 {
+		println!("Warning: {} texture coordinates point at cells without an entry and were kept", result.unmapped );
+	}
+	match std::fs::write( output, result.obj ) {
+		Ok( () ) => println!("Remapped {} texture coordinates into {}", result.remapped, output ),
+		Err( e ) => println!("Couldn't write {}: {}", output, e ),
+	}
+}
+
+fn bake_obj( lowtexpal: &mut LowTexPal, palette: &str, input: &str, output: &str, kd_encoding: Encoding, method: DeltaE, threshold: f32 ) {
+	let obj = match std::fs::read_to_string( input ) {
+		Ok( obj ) => obj,
+		Err( e ) => {
+			println!("Couldn't read {}: {}", input, e );
+			return;
+		},
+	};
+
+	// Material libraries are relative to the model
+	let directory = std::path::Path::new( input ).parent().unwrap_or( std::path::Path::new( "" ) );
+	let mut materials: Vec< model::MtlMaterial > = Vec::new();
+	for line in obj.lines().filter( |l| l.starts_with( "mtllib" ) ) {
+		for library in line.split_whitespace().skip( 1 ) {
+			match std::fs::read_to_string( directory.join( library ) ) {
+				Ok( mtl ) => materials.extend( model::parse_mtl( &mtl, kd_encoding ) ),
+				Err( e ) => println!("Warning: Couldn't read material library {}: {}", library, e ),
+			}
+		}
+	}
+	for material in materials.iter().filter( |m| m.clamped ) {
+		println!("Warning: Material {} has a Kd or d outside of 0-1, clamped it", material.name );
+	}
+
+	let output_path = std::path::Path::new( output );
+	let mtl_path = output_path.with_extension( "mtl" );
+	let mtl_filename = mtl_path.file_name().map( |f| f.to_string_lossy().into_owned() ).unwrap_or_default();
+	// The texture is referenced relative to the .mtl file if they are in the same directory
+	let palette_path = std::path::Path::new( palette );
+	let texture = if palette_path.parent() == output_path.parent() {
+		palette_path.file_name().map( |f| f.to_string_lossy().into_owned() ).unwrap_or_default()
+	} else {
+		std::env::current_dir().map( |d| d.join( palette_path ) ).unwrap_or_else( |_| palette_path.to_path_buf() ).to_string_lossy().into_owned()
+	};
+
+	let count = lowtexpal.colors().len();
+	let Some( baked ) = model::bake_obj( lowtexpal, &obj, &materials, &mtl_filename, &texture, method, threshold ) else {
+		println!("Couldn't bake {}, the entries don't fit into the palette layout", input );
+		return;
+	};
+	for ( material, index ) in &baked.entries {
+		println!("Material {} -> entry {}", material, index );
+	}
+	for material in &baked.skipped {
+		println!("Material {} has no flat color, kept it with its texture coordinates", material );
+	}
+	println!("Added {} entries, reused {}", lowtexpal.colors().len() - count, baked.entries.len() + count - lowtexpal.colors().len() );
+
+	if let Err( e ) = std::fs::write( output, &baked.obj ).and_then( |_| std::fs::write( &mtl_path, &baked.mtl ) ) {
+		println!("Couldn't write {}: {}", output, e );
+		return;
+	}
+	println!("Wrote {} and {}", output, mtl_path.to_string_lossy() );
+}
+
+fn find_color( lowtexpal: &LowTexPal, color: &str, count: usize, method: DeltaE ) {
+	let query = match lowtexpal.parse_color( color ) {
+		Ok( query ) => query,
+		Err( e ) => {
+			println!("Couldn't find {}: {}", color, e );
+			return;
+		},
+	};
+	let nearest = lowtexpal.nearest_entries( &query, method, count );
+	if nearest.is_empty() {
+		println!("No entries");
+	}
+	for entry in nearest {
+		let name = match &lowtexpal.names()[ entry.index - 1 ] {
+			Some( name ) => format!( " @{}", name ),
+			None => String::new(),
+		};
+		match lowtexpal.entry_uv( entry.index ) {
+			Some( uv ) => println!("Entry {}{}: {:?} uv {} {} (ΔE {:?} {:.4})", entry.index, name, lowtexpal.colors()[ entry.index - 1 ].rgba_u8(), uv[ 0 ], uv[ 1 ], method, entry.delta_e ),
+			None => println!("Entry {}{}: {:?} (ΔE {:?} {:.4})", entry.index, name, lowtexpal.colors()[ entry.index - 1 ].rgba_u8(), method, entry.delta_e ),
+		}
+		if let ( Some( level ), Some( area ) ) = ( lowtexpal.gutter_mip_level(), lowtexpal.entry_uv_area( entry.index ) ) {
+			println!("  safe uv area {} {} - {} {} (down to mip level {})", area[ 0 ], area[ 1 ], area[ 2 ], area[ 3 ], level );
+		}
+	}
+}
+
+// Maps every color of a CSV or JSON file, depending on the extension
+fn find_batch( lowtexpal: &LowTexPal, input: &str, output: Option< &str >, method: DeltaE ) {
+	let text = match std::fs::read_to_string( input ) {
+		Ok( text ) => text,
+		Err( e ) => {
+			println!("Couldn't read {}: {}", input, e );
+			return;
+		},
+	};
+	let mapped = if input.to_lowercase().ends_with( ".json" ) {
+		query::map_json( lowtexpal, &text, method ).map_err( |e| e.to_string() )
+	} else {
+		query::map_csv( lowtexpal, &text, method ).map_err( |e| e.to_string() )
+	};
+	let mapped = match mapped {
+		Ok( mapped ) => mapped,
+		Err( e ) => {
+			println!("Couldn't parse {}: {}", input, e );
+			return;
+		},
+	};
+	match output {
+		Some( output ) => match std::fs::write( output, mapped ) {
+			Ok( () ) => println!("Wrote mappings to {}", output ),
+			Err( e ) => println!("Couldn't write {}: {}", output, e ),
+		},
+		None => print!("{}", mapped ),
+	}
 }
 
 fn main() {
@@ -64,47 +481,274 @@ fn main() {
 
 //	dbg!(&file);
 
+	if let Some( min_width ) = cli.min_width.filter( |&w| w > MAX_IMAGE_SIZE ) {
+		println!("Unsupported minimum width {}, images can be at most {} pixels wide", min_width, MAX_IMAGE_SIZE );
+		return;
+	}
+
 	let mut lowtexpal = LowTexPal::new( &file, cli.min_width );
 
 //	dbg!(&lowtexpal);
 
+	if let Some( format ) = &cli.format {
+		match TextureFormat::from_name( format ) {
+			Some( format ) => lowtexpal.set_format( format ),
+			None => {
+				println!("Unknown format {}", format );
+				return;
+			},
+		}
+	}
+
+	// Needs to be known before loading, each cell is read back as one entry
+	if let Some( cell_size ) = cli.cell_size {
+		if !lowtexpal.set_cell_size( cell_size ) {
+			println!("Unsupported cell size {}, cells can be at most {} pixels wide", cell_size, MAX_IMAGE_SIZE );
+			return;
+		}
+	}
+	lowtexpal.set_pure_mip_levels( cli.pure_mips );
+	if let Some( gutter ) = cli.gutter {
+		if !lowtexpal.set_gutter( gutter ) {
+			println!("Unsupported gutter {}, it needs to leave some of the {} pixel cells", gutter, lowtexpal.cell_size() );
+			return;
+		}
+	}
+
+	// Needs to be known before loading, alpha is read back as the scalar
+	if let Some( alpha_scalar ) = &cli.alpha_scalar {
+		match MaterialScalar::from_name( alpha_scalar ) {
+			Some( scalar ) => lowtexpal.set_alpha_scalar( Some( scalar ) ),
+			None => {
+				println!("Unknown material scalar {}", alpha_scalar );
+				return;
+			},
+		}
+	}
+
+	// Needs to be known before loading, compact palettes have no unused cells between entries
+	if let Some( layout ) = &cli.layout {
+		match Layout::from_name( layout ) {
+			Some( layout ) => lowtexpal.set_layout( layout ),
+			None => {
+				println!("Unknown layout {}", layout );
+				return;
+			},
+		}
+	}
+
 	lowtexpal.load(); // :TODO: error handling
 
+	if let Some( bits ) = cli.bit_depth {
+		match BitDepth::from_bits( bits ) {
+			Some( bit_depth ) => lowtexpal.set_bit_depth( bit_depth ),
+			None => {
+				println!("Unsupported bit depth {}", bits );
+				return;
+			},
+		}
+	}
+
+	if let Some( encoding ) = &cli.encoding {
+		match Encoding::from_name( encoding ) {
+			Some( encoding ) => lowtexpal.set_encoding( encoding ),
+			None => {
+				println!("Unknown encoding {}", encoding );
+				return;
+			},
+		}
+	}
+
+	if let Some( gamut_mapping ) = &cli.output_gamut_mapping {
+		match GamutMapping::from_name( gamut_mapping ) {
+			Some( gamut_mapping ) => lowtexpal.set_gamut_mapping( gamut_mapping ),
+			None => {
+				println!("Unknown gamut mapping {}", gamut_mapping );
+				return;
+			},
+		}
+	}
+
+	if let Some( material_maps ) = &cli.material_maps {
+		match MaterialMaps::from_name( material_maps ) {
+			Some( material_maps ) => lowtexpal.set_material_maps( material_maps ),
+			None => {
+				println!("Unknown material maps {}", material_maps );
+				return;
+			},
+		}
+	}
+
 //	dbg!(&lowtexpal);
 
 	// :TODO: handle sub commmands
 
 	match &cli.command {
-		Some(Commands::AddColor { color, force: _ }) => {
-			if let Some(color) = color {
-				dbg!(&color);
-				if !color.is_empty() {
-					match lowtexpal.add_color_string( &color ) {
-						Some( i ) => println!("Added {} at {}", &color, i ),
-						None => println!("Couldn't add {}", &color ),
-					}
+		Some(Commands::AddColor { color: Some( color ), intensity, name, group, material, force: _ }) => {
+			dbg!(&color, &intensity);
+			if !( intensity.is_finite() && *intensity > 0.0 ) {
+				println!("Invalid intensity {}, it needs to be greater than 0", intensity );
+				return;
+			}
+			if !color.is_empty() {
+				match lowtexpal.add_color_string_intensity( color, *intensity ) {
+					Ok( i ) => {
+						println!("Added {} at {}", &color, i );
+						print_quantization( &lowtexpal, &[ i ] );
+						set_materials( &mut lowtexpal, &[ i ], material );
+						if let Some( name ) = name {
+							set_name( &mut lowtexpal, i, name );
+						}
+						if let Some( group ) = group {
+							set_group( &mut lowtexpal, &[ i ], group );
+						}
+					},
+					Err( e ) => println!("Couldn't add {}: {}", &color, e ),
 				}
 			}
 		}
-		Some(Commands::AddGradient { start_color, end_color, steps, colorspace, force: _ }) => {
+		Some(Commands::AddGradient { start_color, end_color, steps, colorspace, gamut_mapping, premultiplied, group, material, force: _ }) => {
 			if let (Some(start_color), Some(end_color), Some(steps)) = (start_color, end_color, steps) {
-				dbg!(&start_color, &end_color, &steps, &colorspace);
+				dbg!(&start_color, &end_color, &steps, &colorspace, &gamut_mapping, &premultiplied);
+				let Some( gamut_mapping ) = GamutMapping::from_name( gamut_mapping ) else {
+					println!("Unknown gamut mapping {}", &gamut_mapping );
+					return;
+				};
+				let options = GradientOptions {
+					gamut_mapping,
+					premultiplied_alpha: *premultiplied,
+				};
 				if !start_color.is_empty() && !end_color.is_empty() && *steps != 0 {
-					match lowtexpal.add_gradient_colorspace( &start_color, &end_color, *steps, colorspace ) {
-						Some( i ) => println!("Added {} - {} ({}) at {:#?}", &start_color, &end_color, colorspace, &i ),
-						None => println!("Couldn't add {} - {} with {} steps", &start_color, &end_color, steps ),
+					match lowtexpal.add_gradient_with_options( start_color, end_color, *steps, colorspace, &options ) {
+						Ok( ( i, out_of_gamut ) ) => {
+							println!("Added {} - {} ({}) at {:#?}", &start_color, &end_color, colorspace, &i );
+							print_quantization( &lowtexpal, &i );
+							set_materials( &mut lowtexpal, &i, material );
+							if let Some( group ) = group {
+								set_group( &mut lowtexpal, &i, group );
+							}
+							for oog in out_of_gamut {
+								println!("Out of gamut: entry {} (ΔE OK {:.4})", oog.index, oog.delta_e );
+							}
+						},
+						Err( e ) => println!("Couldn't add {} - {}: {}", &start_color, &end_color, e ),
 					}
 				}
 			}
 		}
-		None => {
-			// No subcommand provided
+		Some(Commands::WhiteBalance { from, to }) => {
+			// The K is optional here
+			let kelvin = |value: &str| Color::parse_kelvin( value ).or_else( || value.trim().parse::< f32 >().ok() );
+			match ( kelvin( from ), kelvin( to ) ) {
+				( Some( from_kelvin ), Some( to_kelvin ) ) if lowtexpal.white_balance( from_kelvin, to_kelvin ) => {
+					println!("White balanced {} entries from {}K to {}K", lowtexpal.colors().len(), from_kelvin, to_kelvin );
+				},
+				_ => println!("Couldn't white balance from {} to {}, temperatures need to be within 1667K-25000K", &from, &to ),
+			}
+		}
+		Some(Commands::Name { entry, name }) => {
+			match lowtexpal.entry_index( entry ) {
+				Some( i ) => set_name( &mut lowtexpal, i, name ),
+				None => println!("No entry {}", &entry ),
+			}
+		}
+		Some(Commands::Find { color, count, delta_e, input, output }) => {
+			let Some( method ) = DeltaE::from_name( delta_e ) else {
+				println!("Unknown color difference {}", &delta_e );
+				return;
+			};
+			if let Some( color ) = color {
+				find_color( &lowtexpal, color, *count, method );
+			}
+			if let Some( input ) = input {
+				find_batch( &lowtexpal, input, output.as_deref(), method );
+			}
+		}
+		Some(Commands::Dedupe { threshold, delta_e }) => {
+			let Some( method ) = DeltaE::from_name( delta_e ) else {
+				println!("Unknown color difference {}", &delta_e );
+				return;
+			};
+			let count = lowtexpal.colors().len();
+			let remap = lowtexpal.dedupe( method, *threshold );
+			println!("Merged {} of {} entries", count - lowtexpal.colors().len(), count );
+			write_remap_table( &lowtexpal, cli.remap_table.as_deref(), &remap );
+		}
+		Some(Commands::Reduce { count }) => {
+			if *count == 0 || *count >= lowtexpal.colors().len() {
+				println!("Palette has {} entries, nothing to reduce to {}", lowtexpal.colors().len(), count );
+				return;
+			}
+			let materials = lowtexpal.materials().to_vec();
+			let remap = lowtexpal.reduce( *count );
+			for ( i, color ) in lowtexpal.colors().iter().enumerate() {
+				let merged = remap.iter().filter( |e| e.new_index == i + 1 ).count();
+				println!("Entry {}: {:?} (cluster of {})", i + 1, color.rgba_u8(), merged );
+			}
+			for entry in &remap {
+				let old = materials[ entry.old_index - 1 ].unwrap_or_default();
+				let new = lowtexpal.materials()[ entry.new_index - 1 ].unwrap_or_default();
+				if old != new {
+					println!("Warning: Entry {} -> {} changed its material from {:?} to {:?}", entry.old_index, entry.new_index, old, new );
+				}
+			}
+			write_remap_table( &lowtexpal, cli.remap_table.as_deref(), &remap );
+		}
+		Some(Commands::Sort { by, per_group }) => {
+			let Some( key ) = SortKey::from_name( by ) else {
+				println!("Unknown sort key {}", &by );
+				return;
+			};
+			let remap = lowtexpal.sort( key, *per_group );
+			println!("Sorted {} entries by {}", lowtexpal.colors().len(), &by );
+			write_remap_table( &lowtexpal, cli.remap_table.as_deref(), &remap );
+		}
+		Some(Commands::Relayout { to }) => {
+			let Some( layout ) = Layout::from_name( to ) else {
+				println!("Unknown layout {}", &to );
+				return;
+			};
+			let remap = lowtexpal.relayout( layout );
+			let moved = remap.iter().filter( |e| e.old_uv != e.new_uv ).count();
+			println!("Moved {} of {} entries to the {} layout, use --layout {} from now on", moved, remap.len(), &to, &to );
+			write_remap_table( &lowtexpal, cli.remap_table.as_deref(), &remap );
+		}
+		Some(Commands::RemapModel { input, output }) => {
+			let table = cli.remap_table.clone().unwrap_or_else( || lowtexpal.remap_filename() );
+			remap_model( &table, input, output.as_deref().unwrap_or( input ) );
+		}
+		Some(Commands::BakeObj { input, output, kd_encoding, threshold, delta_e }) => {
+			let Some( method ) = DeltaE::from_name( delta_e ) else {
+				println!("Unknown color difference {}", &delta_e );
+				return;
+			};
+			let Some( kd_encoding ) = Encoding::from_name( kd_encoding ) else {
+				println!("Unknown encoding {}", &kd_encoding );
+				return;
+			};
+			if file.is_empty() {
+				println!("Baking needs a palette file (-f)");
+				return;
+			}
+			bake_obj( &mut lowtexpal, &file, input, output, kd_encoding, method, *threshold );
+		}
+		_ => {
+			// No (complete) subcommand provided
 		}
 	}
 
 //	dbg!(&lowtexpal);
 
 	if lowtexpal.was_modified() {
+		// Saving can move loaded entries, e.g. when the texture grows
+		let remap = lowtexpal.pending_remap();
 		lowtexpal.save();
+		if lowtexpal.was_modified() {
+			// Not saved, e.g. a fixed layout without free cells
+			std::process::exit( 1 );
+		}
+		if !remap.is_empty() {
+			write_remap_table( &lowtexpal, cli.remap_table.as_deref(), &remap );
+		}
 	}
 }
@@ -0,0 +1,287 @@
+// Wavefront OBJ models textured with the palette
+// Models are remapped when the palette changes, or baked from flat material colors onto it.
+// OBJ texture coordinates have their origin in the bottom left corner, palette UVs in the top left.
+
+use crate::lowtexpal::{Color, DeltaE, Encoding, LowTexPal};
+use crate::remap::{self, RemapEntry};
+
+/// A rewritten model
+#[derive(Debug,Clone)]
+pub struct ObjRemap {
+	pub obj: String,
+	/// Texture coordinates moved to the new cell center
+	pub remapped: usize,
+	/// Texture coordinates pointing at cells without an entry, kept as they were
+	pub unmapped: usize,
+}
+
+/// Rewrites every texture coordinate (vt) to the center of the cell its old cell moved to
+/// Everything else is kept as is, None if the remap table is empty
+pub fn remap_obj( obj: &str, remap: &[RemapEntry] ) -> Option< ObjRemap > {
+	let size = remap::old_grid_size( remap )?;
+	let mut result = ObjRemap {
+		obj: String::with_capacity( obj.len() ),
+		remapped: 0,
+		unmapped: 0,
+	};
+
+	for line in obj.split_inclusive( '\n' ) {
+		let content = line.trim_end_matches( ['\r', '\n'] );
+		let ending = &line[ content.len().. ];
+		let mut fields = content.split_whitespace();
+		if fields.next() != Some( "vt" ) {
+			result.obj.push_str( line );
+			continue;
+		}
+
+		let values: Vec< &str > = fields.collect();
+		let uv = match ( values.first().and_then( |u| u.parse::< f32 >().ok() ), values.get( 1 ).map_or( Some( 0.0 ), |v| v.parse::< f32 >().ok() ) ) {
+			( Some( u ), Some( v ) ) => [ u, 1.0 - v ],
+			_ => {
+				result.obj.push_str( line );
+				continue;
+			},
+		};
+
+		match remap::remap_uv( remap, size, uv ) {
+			Some( new_uv ) => {
+				result.remapped += 1;
+				let rest: Vec< &str > = values.iter().skip( 2 ).copied().collect();
+				let mut vt = format!( "vt {} {}", new_uv[ 0 ], 1.0 - new_uv[ 1 ] );
+				for value in rest {
+					vt.push( ' ' );
+					vt.push_str( value );
+				}
+				result.obj.push_str( &vt );
+				result.obj.push_str( ending );
+			},
+			None => {
+				result.unmapped += 1;
+				result.obj.push_str( line );
+			},
+		}
+	}
+
+	Some( result )
+}
+
+/// A material of an MTL file
+#[derive(Debug,Clone)]
+pub struct MtlMaterial {
+	pub name: String,
+	/// The flat color, None for materials with a diffuse texture (map_Kd) or without Kd
+	pub color: Option< Color >,
+	/// Kd or d were outside of 0-1 and clamped
+	pub clamped: bool,
+	/// The newmtl block as it was, to copy materials that aren't baked
+	pub definition: String,
+}
+
+/// The materials of an MTL file, Kd is read with the given encoding and the dissolve (d) becomes alpha
+pub fn parse_mtl( mtl: &str, kd_encoding: Encoding ) -> Vec< MtlMaterial > {
+	let mut materials: Vec< MtlMaterial > = Vec::new();
+	let mut kd: Option< [f32; 3] > = None;
+	let mut dissolve = 1.0;
+	let mut textured = false;
+
+	// Sets the color of the last material once all its statements were read
+	let finish = |materials: &mut Vec< MtlMaterial >, kd: Option< [f32; 3] >, dissolve: f32, textured: bool| {
+		if let Some( material ) = materials.last_mut() {
+			let clamp = |c: f32| c.clamp( 0.0, 1.0 );
+			let clamped = kd.is_some_and( |rgb| rgb.iter().any( |&c| c != clamp( c ) ) ) || dissolve != clamp( dissolve );
+			let ( kd, dissolve ) = ( kd.map( |rgb| rgb.map( clamp ) ), clamp( dissolve ) );
+			material.clamped = clamped && kd.is_some() && !textured;
+			material.color = match ( kd, textured, kd_encoding ) {
+				( Some( rgb ), false, Encoding::Srgb ) => Some( Color::from_srgb( rgb, dissolve ) ),
+				( Some( rgb ), false, Encoding::Linear ) => Some( Color::from_linear( rgb, dissolve ) ),
+				_ => None,
+			};
+		}
+	};
+
+	for line in mtl.lines() {
+		let mut fields = line.split_whitespace();
+		let keyword = fields.next().unwrap_or_default();
+		let values: Vec< f32 > = fields.clone().filter_map( |f| f.parse().ok() ).collect();
+		match keyword {
+			"newmtl" => {
+				finish( &mut materials, kd, dissolve, textured );
+				materials.push( MtlMaterial {
+					name: fields.collect::< Vec< &str > >().join( " " ),
+					color: None,
+					clamped: false,
+					definition: String::new(),
+				} );
+				kd = None;
+				dissolve = 1.0;
+				textured = false;
+			},
+			"Kd" if values.len() >= 3 => kd = Some( [ values[ 0 ], values[ 1 ], values[ 2 ] ] ),
+			"d" if !values.is_empty() => dissolve = values[ 0 ],
+			"map_Kd" => textured = true,
+			_ => {},
+		}
+		if let Some( material ) = materials.last_mut() {
+			material.definition.push_str( line );
+			material.definition.push( '\n' );
+		}
+	}
+	finish( &mut materials, kd, dissolve, textured );
+
+	materials
+}
+
+/// A model baked onto the palette
+#[derive(Debug,Clone)]
+pub struct ObjBake {
+	pub obj: String,
+	pub mtl: String,
+	/// Each flat colored material and the palette entry it uses
+	pub entries: Vec< ( String, usize ) >,
+	/// Materials used by faces that have no flat color, they are kept with their texture coordinates
+	pub skipped: Vec< String >,
+}
+
+/// Adds the colors of all materials used by the model to the palette, reusing entries within threshold,
+/// and rewrites the model so every face of a flat colored material has the UV of its cell center and uses the palette texture
+/// Other materials keep their faces and texture coordinates, their definitions are copied into the new MTL
+/// None if the entries don't fit into the palette's layout
+pub fn bake_obj(
+	lowtexpal: &mut LowTexPal,
+	obj: &str,
+	materials: &[MtlMaterial],
+	mtl_filename: &str,
+	texture_filename: &str,
+	method: DeltaE,
+	threshold: f32,
+) -> Option< ObjBake > {
+	let mut entries: Vec< ( String, usize ) > = Vec::new();
+	let mut skipped: Vec< String > = Vec::new();
+
+	for line in obj.lines() {
+		let mut fields = line.split_whitespace();
+		if fields.next() != Some( "usemtl" ) {
+			continue;
+		}
+		let name = fields.collect::< Vec< &str > >().join( " " );
+		if entries.iter().any( |( n, _ )| *n == name ) || skipped.contains( &name ) {
+			continue;
+		}
+		match materials.iter().find( |m| m.name == name ).and_then( |m| m.color.as_ref() ) {
+			Some( color ) => {
+				let index = match lowtexpal.find_duplicate( color, None, method, threshold ) {
+					Some( index ) => index,
+					None => {
+						let index = lowtexpal.add_color( color );
+						// Material names make handy entry names, if they are valid and unused
+						lowtexpal.set_name( index, &name );
+						index
+					},
+				};
+				entries.push( ( name, index ) );
+			},
+			None => skipped.push( name ),
+		}
+	}
+
+	// One texture coordinate per used entry, after all entries were added so the layout is final
+	let mut uvs: Vec< usize > = Vec::new();
+	let mut vt = String::new();
+	let mut entry_vt: Vec< usize > = Vec::new();
+	for ( _, index ) in &entries {
+		let position = match uvs.iter().position( |i| i == index ) {
+			Some( position ) => position,
+			None => {
+				let uv = lowtexpal.entry_uv( *index )?;
+				vt.push_str( &format!( "vt {} {}\n", uv[ 0 ], 1.0 - uv[ 1 ] ) );
+				uvs.push( *index );
+				uvs.len() - 1
+			},
+		};
+		entry_vt.push( position + 1 );
+	}
+
+	// The palette coordinates go in front of the first face, after the texture coordinates read so far,
+	// the ones that follow later (e.g. of the next object) move back by as many
+	let is_vt = |line: &&str| line.split_whitespace().next() == Some( "vt" );
+	let is_element = |line: &&str| matches!( line.split_whitespace().next(), Some( "f" | "l" ) );
+	let vt_before = obj.lines().take_while( |l| !is_element( l ) ).filter( is_vt ).count();
+	let moved_vt = |index: usize| if index > vt_before { index + uvs.len() } else { index };
+
+	let material_name = std::path::Path::new( texture_filename )
+		.file_stem()
+		.map_or_else( || "palette".to_string(), |s| s.to_string_lossy().into_owned() );
+
+	let mut result = String::with_capacity( obj.len() + vt.len() );
+	// Palette texture coordinate of the current material, None for other materials
+	let mut current: Option< usize > = None;
+	let mut vt_read = 0;
+	let mut vt_written = false;
+	for line in obj.lines() {
+		let mut fields = line.split_whitespace();
+		match fields.next() {
+			Some( "vt" ) => vt_read += 1,
+			Some( "mtllib" ) => {
+				result.push_str( &format!( "mtllib {}\n", mtl_filename ) );
+				continue;
+			},
+			Some( "usemtl" ) => {
+				let name = fields.collect::< Vec< &str > >().join( " " );
+				current = entries.iter().position( |( n, _ )| *n == name ).map( |e| entry_vt[ e ] + vt_before );
+				if current.is_some() {
+					result.push_str( &format!( "usemtl {}\n", material_name ) );
+					continue;
+				}
+			},
+			Some( keyword @ ( "f" | "l" ) ) => {
+				if !vt_written {
+					result.push_str( &vt );
+					vt_written = true;
+				}
+				let vertices: Vec< String > = fields.map( |vertex| {
+					let mut parts = vertex.split( '/' );
+					let v = parts.next().unwrap_or_default();
+					let t = parts.next().filter( |t| !t.is_empty() );
+					let vn = parts.next().filter( |vn| !vn.is_empty() );
+					// Other materials keep their coordinates, relative ones made absolute
+					let t = current.map( |t| t.to_string() ).or_else( || t.map( |t| match t.parse::< i64 >() {
+						Ok( t ) if t < 0 => moved_vt( ( vt_read as i64 + 1 + t ).max( 0 ) as usize ).to_string(),
+						Ok( t ) => moved_vt( t as usize ).to_string(),
+						Err( _ ) => t.to_string(),
+					} ) );
+					match ( t, vn ) {
+						( Some( t ), Some( vn ) ) => format!( "{}/{}/{}", v, t, vn ),
+						( Some( t ), None ) => format!( "{}/{}", v, t ),
+						( None, Some( vn ) ) => format!( "{}//{}", v, vn ),
+						( None, None ) => v.to_string(),
+					}
+				} ).collect();
+				result.push_str( &format!( "{} {}\n", keyword, vertices.join( " " ) ) );
+				continue;
+			},
+			_ => {},
+		}
+		result.push_str( line );
+		result.push( '\n' );
+	}
+	if !vt_written {
+		result.push_str( &vt );
+	}
+
+	let mut mtl = format!(
+		"# Flat colors baked into {}\nnewmtl {}\nKa 1 1 1\nKd 1 1 1\nKs 0 0 0\nd 1\nillum 1\nmap_Kd {}\n",
+		texture_filename, material_name, texture_filename,
+	);
+	for material in materials.iter().filter( |m| skipped.contains( &m.name ) ) {
+		mtl.push( '\n' );
+		mtl.push_str( material.definition.trim_end() );
+		mtl.push( '\n' );
+	}
+
+	Some( ObjBake {
+		obj: result,
+		mtl,
+		entries,
+		skipped,
+	} )
+}
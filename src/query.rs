@@ -0,0 +1,97 @@
+// Batch queries: maps a CSV or JSON list of colors to the nearest palette entries
+// CSV rows keep all their columns and get index, u, v and delta_e appended, the color is read
+// from the "color" column if there is a header, else from the first column.
+// JSON is an array of color strings or of objects with a "color" key, every item becomes an
+// object with the added keys.
+// Colors that can't be parsed (or an empty palette) leave the added fields empty/null.
+
+use crate::lowtexpal::{DeltaE, LowTexPal};
+
+/// The nearest entry for a color expression, with the UV of its cell center
+#[derive(Debug,Copy,Clone)]
+pub struct Mapping {
+	pub index: usize,
+	pub uv: [f32; 2],
+	pub delta_e: f32,
+}
+
+/// The nearest entry for a color expression, which may refer to entries itself
+pub fn map_color( lowtexpal: &LowTexPal, color: &str, method: DeltaE ) -> Option< Mapping > {
+	let color = lowtexpal.parse_color( color ).ok()?;
+	let nearest = lowtexpal.nearest_entries( &color, method, 1 ).into_iter().next()?;
+	Some( Mapping {
+		index: nearest.index,
+		uv: lowtexpal.entry_uv( nearest.index )?,
+		delta_e: nearest.delta_e,
+	} )
+}
+
+pub fn map_csv( lowtexpal: &LowTexPal, input: &str, method: DeltaE ) -> Result< String, csv::Error > {
+	let mut reader = csv::ReaderBuilder::new()
+		.has_headers( false )
+		.flexible( true )
+		.trim( csv::Trim::All )
+		.from_reader( input.as_bytes() );
+	let mut writer = csv::WriterBuilder::new()
+		.flexible( true )
+		.from_writer( Vec::new() );
+
+	let mut column = 0;
+	for ( row, record ) in reader.records().enumerate() {
+		let record = record?;
+		let mut fields: Vec< String > = record.iter().map( |f| f.to_string() ).collect();
+
+		if row == 0 {
+			if let Some( c ) = fields.iter().position( |f| f.eq_ignore_ascii_case( "color" ) ) {
+				column = c;
+				fields.extend( [ "index", "u", "v", "delta_e" ].iter().map( |f| f.to_string() ) );
+				writer.write_record( &fields )?;
+				continue;
+			}
+		}
+
+		match fields.get( column ).and_then( |color| map_color( lowtexpal, color, method ) ) {
+			Some( m ) => fields.extend( [
+				m.index.to_string(),
+				m.uv[ 0 ].to_string(),
+				m.uv[ 1 ].to_string(),
+				m.delta_e.to_string(),
+			] ),
+			None => fields.extend( vec![ String::new(); 4 ] ),
+		}
+		writer.write_record( &fields )?;
+	}
+
+	let data = writer.into_inner().map_err( |e| csv::Error::from( e.into_error() ) )?;
+	Ok( String::from_utf8_lossy( &data ).into_owned() )
+}
+
+pub fn map_json( lowtexpal: &LowTexPal, input: &str, method: DeltaE ) -> Result< String, serde_json::Error > {
+	use serde_json::{json, Value};
+
+	let items: Vec< Value > = serde_json::from_str( input )?;
+	let mapped: Vec< Value > = items.into_iter().map( |item| {
+		let mut object = match item {
+			Value::Object( object ) => object,
+			color => {
+				let mut object = serde_json::Map::new();
+				object.insert( "color".to_string(), color );
+				object
+			},
+		};
+		let mapping = object.get( "color" )
+			.and_then( |c| c.as_str() )
+			.and_then( |color| map_color( lowtexpal, color, method ) );
+		let ( index, u, v, delta_e ) = match mapping {
+			Some( m ) => ( json!( m.index ), json!( m.uv[ 0 ] ), json!( m.uv[ 1 ] ), json!( m.delta_e ) ),
+			None => ( Value::Null, Value::Null, Value::Null, Value::Null ),
+		};
+		object.insert( "index".to_string(), index );
+		object.insert( "u".to_string(), u );
+		object.insert( "v".to_string(), v );
+		object.insert( "delta_e".to_string(), delta_e );
+		Value::Object( object )
+	} ).collect();
+
+	serde_json::to_string_pretty( &mapped )
+}
@@ -0,0 +1,86 @@
+// Remap tables: where each entry moved when the palette was rearranged, so models can follow
+// Written as CSV with one row per old entry: old_index,new_index,old_u,old_v,new_u,new_v
+// UVs are cell centers with the origin in the top left corner of the image.
+
+/// Where an entry (and its cell) moved to
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct RemapEntry {
+	/// Index of the entry before the change, as returned by the add_* methods
+	pub old_index: usize,
+	/// Index of the entry it became, e.g. the one it was merged into
+	pub new_index: usize,
+	pub old_uv: [f32; 2],
+	pub new_uv: [f32; 2],
+}
+
+pub fn write_table( filename: &str, remap: &[RemapEntry] ) -> Result< (), csv::Error > {
+	let mut writer = csv::Writer::from_path( filename )?;
+	writer.write_record( [ "old_index", "new_index", "old_u", "old_v", "new_u", "new_v" ] )?;
+	for entry in remap {
+		writer.write_record( [
+			entry.old_index.to_string(),
+			entry.new_index.to_string(),
+			entry.old_uv[ 0 ].to_string(),
+			entry.old_uv[ 1 ].to_string(),
+			entry.new_uv[ 0 ].to_string(),
+			entry.new_uv[ 1 ].to_string(),
+		] )?;
+	}
+	writer.flush()?;
+	Ok( () )
+}
+
+pub fn read_table( filename: &str ) -> Result< Vec< RemapEntry >, csv::Error > {
+	let mut reader = csv::ReaderBuilder::new()
+		.trim( csv::Trim::All )
+		.from_path( filename )?;
+
+	let mut remap = Vec::new();
+	for record in reader.records() {
+		let record = record?;
+		let field = |i: usize| record.get( i ).unwrap_or_default();
+		let number = |i: usize| field( i ).parse::< f32 >().ok();
+		let ( Ok( old_index ), Ok( new_index ), Some( old_u ), Some( old_v ), Some( new_u ), Some( new_v ) ) = (
+			field( 0 ).parse::< usize >(),
+			field( 1 ).parse::< usize >(),
+			number( 2 ),
+			number( 3 ),
+			number( 4 ),
+			number( 5 ),
+		) else {
+			return Err( std::io::Error::new( std::io::ErrorKind::InvalidData, format!( "Invalid remap row {:?}", record ) ).into() );
+		};
+		remap.push( RemapEntry {
+			old_index,
+			new_index,
+			old_uv: [ old_u, old_v ],
+			new_uv: [ new_u, new_v ],
+		} );
+	}
+	Ok( remap )
+}
+
+/// Cells per row of the old layout: the smallest power of 2 for which all old UVs are cell centers
+pub fn old_grid_size( remap: &[RemapEntry] ) -> Option< u32 > {
+	if remap.is_empty() {
+		return None;
+	}
+	( 0..=12 ).map( |p| 1u32 << p ).find( |&size| {
+		remap.iter().flat_map( |e| e.old_uv ).all( |c| {
+			let cell = c * size as f32 - 0.5;
+			( cell - cell.round() ).abs() < 0.01
+		} )
+	} )
+}
+
+/// The new UV for a UV anywhere in an old cell, the origin is the top left corner and UVs outside of 0-1 wrap
+/// None if no entry was in that cell
+pub fn remap_uv( remap: &[RemapEntry], size: u32, uv: [f32; 2] ) -> Option< [f32; 2] > {
+	let cell = uv.map( |c| {
+		let c = if ( 0.0..=1.0 ).contains( &c ) { c } else { c.rem_euclid( 1.0 ) };
+		( ( c * size as f32 ) as u32 ).min( size - 1 )
+	} );
+	remap.iter()
+		.find( |e| e.old_uv.map( |c| ( c * size as f32 ) as u32 ) == cell )
+		.map( |e| e.new_uv )
+}
@@ -1,4 +1,9 @@
-use super::lowtexpal::{Color, LowTexPal};
+use super::container::{self, PixelFormat};
+use super::expression;
+use super::model;
+use super::query;
+use super::remap::{self, RemapEntry};
+use super::lowtexpal::{BitDepth, Color, ColorParseError, DeltaE, Encoding, GamutMapping, GradientOptions, Layout, LowTexPal, Material, MaterialMaps, MaterialScalar, SortKey, TextureFormat, MAX_IMAGE_SIZE};
 
 // Helper function for float comparison with tolerance
 fn assert_f32_near(a: f32, b: f32, tolerance: f32) {
@@ -147,13 +152,13 @@ fn test_parse_invalid_returns_none() {
 
 #[test]
 fn test_lowtexpal_new() {
-	let ltp = LowTexPal::new("test.png");
+	let ltp = LowTexPal::new("test.png", None);
 	assert!(!ltp.was_modified());
 }
 
 #[test]
 fn test_add_color_rgb() {
-	let mut ltp = LowTexPal::new("test.png");
+	let mut ltp = LowTexPal::new("test.png", None);
 	assert!(!ltp.was_modified());
 
 	ltp.add_color_rgb(255, 0, 0);
@@ -162,7 +167,7 @@ fn test_add_color_rgb() {
 
 #[test]
 fn test_add_color_string_hex() {
-	let mut ltp = LowTexPal::new("test.png");
+	let mut ltp = LowTexPal::new("test.png", None);
 	let result = ltp.add_color_string("#ff0000");
 	assert!(result.is_some());
 	assert_eq!(result.unwrap(), 1);
@@ -170,21 +175,21 @@ fn test_add_color_string_hex() {
 
 #[test]
 fn test_add_color_string_named() {
-	let mut ltp = LowTexPal::new("test.png");
+	let mut ltp = LowTexPal::new("test.png", None);
 	let result = ltp.add_color_string("lime");
 	assert!(result.is_some());
 }
 
 #[test]
 fn test_add_color_string_invalid() {
-	let mut ltp = LowTexPal::new("test.png");
+	let mut ltp = LowTexPal::new("test.png", None);
 	let result = ltp.add_color_string("invalid");
 	assert!(result.is_none());
 }
 
 #[test]
 fn test_add_gradient_strings() {
-	let mut ltp = LowTexPal::new("test.png");
+	let mut ltp = LowTexPal::new("test.png", None);
 	let result = ltp.add_gradient_strings("black", "white", 4);
 	assert!(result.is_some());
 
@@ -194,7 +199,7 @@ fn test_add_gradient_strings() {
 
 #[test]
 fn test_modification_tracking() {
-	let mut ltp = LowTexPal::new("test.png");
+	let mut ltp = LowTexPal::new("test.png", None);
 	assert!(!ltp.was_modified());
 
 	ltp.add_color_rgb(255, 0, 0);
@@ -255,7 +260,7 @@ fn test_oklch_round_trip() {
 
 #[test]
 fn test_oklab_gradient() {
-	let mut ltp = LowTexPal::new("test.png");
+	let mut ltp = LowTexPal::new("test.png", None);
 	let result = ltp.add_gradient_colorspace("red", "lime", 8, "oklab");
 	assert!(result.is_some());
 
@@ -265,7 +270,7 @@ fn test_oklab_gradient() {
 
 #[test]
 fn test_oklch_gradient() {
-	let mut ltp = LowTexPal::new("test.png");
+	let mut ltp = LowTexPal::new("test.png", None);
 	let result = ltp.add_gradient_colorspace("red", "blue", 8, "oklch");
 	assert!(result.is_some());
 
@@ -275,13 +280,2330 @@ fn test_oklch_gradient() {
 
 #[test]
 fn test_rgb_gradient_backward_compat() {
-	let mut ltp = LowTexPal::new("test.png");
+	let mut ltp = LowTexPal::new("test.png", None);
 	// Old method should still work
 	let result1 = ltp.add_gradient_strings("black", "white", 4);
 	assert!(result1.is_some());
 
 	// New method with RGB should give same result
-	let mut ltp2 = LowTexPal::new("test2.png");
+	let mut ltp2 = LowTexPal::new("test2.png", None);
 	let result2 = ltp2.add_gradient_colorspace("black", "white", 4, "rgb");
 	assert!(result2.is_some());
 }
+
+// ===== Achromatic Hue Tests =====
+
+#[test]
+fn test_grays_are_achromatic() {
+	for v in [0u8, 1, 64, 128, 200, 254, 255] {
+		let gray = Color::from([v, v, v, 255]);
+		assert!(gray.is_achromatic(), "gray {} should be achromatic", v);
+	}
+	assert!(!Color::from_string("#808081").unwrap().is_achromatic());
+	assert!(!Color::from_string("red").unwrap().is_achromatic());
+}
+
+#[test]
+fn test_fix_missing_hues_adopts_other_hue() {
+	let white = Color::from_string("white").unwrap().to_oklch();
+	let blue = Color::from_string("blue").unwrap().to_oklch();
+
+	let (start, end) = Color::fix_missing_hues(white, blue);
+	assert_f32_near(start[2], blue[2], 0.0001);
+	assert_f32_near(end[2], blue[2], 0.0001);
+	assert_f32_near(start[0], white[0], 0.0001);
+
+	let (start, end) = Color::fix_missing_hues(blue, white);
+	assert_f32_near(start[2], blue[2], 0.0001);
+	assert_f32_near(end[2], blue[2], 0.0001);
+}
+
+#[test]
+fn test_fix_missing_hues_both_achromatic() {
+	let black = Color::from_string("black").unwrap().to_oklch();
+	let gray = Color::from_string("gray").unwrap().to_oklch();
+
+	let (start, end) = Color::fix_missing_hues(black, gray);
+	assert_eq!(start[2], end[2]);
+}
+
+#[test]
+fn test_oklch_gradient_white_to_blue_keeps_hue() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	ltp.add_gradient_colorspace("white", "blue", 8, "oklch").unwrap();
+
+	let blue_hue = Color::from_string("blue").unwrap().to_oklch()[2];
+	// Per-channel clamping of out-of-gamut midpoints shifts the hue slightly
+	for color in &ltp.colors()[1..] {
+		assert_f32_near(color.to_oklch()[2], blue_hue, 0.1);
+	}
+}
+
+#[test]
+fn test_oklch_gradient_gray_to_red_keeps_hue() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	ltp.add_gradient_colorspace("gray", "red", 6, "oklch").unwrap();
+
+	let red_hue = Color::from_string("red").unwrap().to_oklch()[2];
+	for color in &ltp.colors()[1..] {
+		assert_f32_near(color.to_oklch()[2], red_hue, 0.1);
+	}
+}
+
+#[test]
+fn test_oklch_gradient_gray_to_gray_stays_gray() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	ltp.add_gradient_colorspace("black", "white", 5, "oklch").unwrap();
+
+	for color in ltp.colors() {
+		let rgba = color.rgba_u8();
+		assert!((rgba[0] as i16 - rgba[1] as i16).abs() <= 1, "not gray: {:?}", rgba);
+		assert!((rgba[1] as i16 - rgba[2] as i16).abs() <= 1, "not gray: {:?}", rgba);
+	}
+}
+
+// ===== Gamut Mapping Tests =====
+
+#[test]
+fn test_gamut_mapping_from_name() {
+	assert_eq!(GamutMapping::from_name("clip"), Some(GamutMapping::Clip));
+	assert_eq!(GamutMapping::from_name("CSS"), Some(GamutMapping::Css));
+	assert_eq!(GamutMapping::from_name("nope"), None);
+}
+
+#[test]
+fn test_oklab_in_gamut() {
+	assert!(Color::oklab_in_gamut(Color::from_string("red").unwrap().to_oklab()));
+	assert!(Color::oklab_in_gamut(Color::from_string("white").unwrap().to_oklab()));
+	assert!(!Color::oklab_in_gamut(Color::oklch_to_oklab([0.9, 0.3, -1.8])));
+}
+
+#[test]
+fn test_css_mapping_keeps_in_gamut_colors() {
+	let lab = Color::from_string("#884422").unwrap().to_oklab();
+	let clipped = Color::from_oklab_mapped(lab, GamutMapping::Clip);
+	let mapped = Color::from_oklab_mapped(lab, GamutMapping::Css);
+	assert_eq!(clipped.rgba_u8(), mapped.rgba_u8());
+}
+
+#[test]
+fn test_css_mapping_preserves_hue_and_lightness() {
+	// Vivid light blue, far outside of sRGB
+	let lch = [0.7, 0.3, -1.8];
+	let lab = Color::oklch_to_oklab(lch);
+
+	let clipped = Color::from_oklab_mapped(lab, GamutMapping::Clip).to_oklch();
+	let mapped = Color::from_oklab_mapped(lab, GamutMapping::Css).to_oklch();
+
+	assert_f32_near(mapped[0], lch[0], 0.02);
+	assert_f32_near(mapped[2], lch[2], 0.06);
+	assert!(mapped[1] < lch[1]);
+
+	let clipped_error = (clipped[0] - lch[0]).abs() + (clipped[2] - lch[2]).abs();
+	let mapped_error = (mapped[0] - lch[0]).abs() + (mapped[2] - lch[2]).abs();
+	assert!(mapped_error < clipped_error);
+}
+
+#[test]
+fn test_css_mapping_extreme_lightness() {
+	let white = Color::from_oklab_mapped([1.2, 0.1, 0.1], GamutMapping::Css);
+	assert_eq!(white.rgba_u8()[0..3], [255, 255, 255]);
+
+	let black = Color::from_oklab_mapped([-0.1, 0.1, 0.1], GamutMapping::Css);
+	assert_eq!(black.rgba_u8()[0..3], [0, 0, 0]);
+}
+
+fn css_mapping() -> GradientOptions {
+	GradientOptions {
+		gamut_mapping: GamutMapping::Css,
+		..GradientOptions::default()
+	}
+}
+
+#[test]
+fn test_gradient_reports_out_of_gamut_entries() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	let (indices, out_of_gamut) = ltp
+		.add_gradient_with_options("yellow", "blue", 8, "oklch", &css_mapping())
+		.unwrap();
+	assert_eq!(indices.len(), 8);
+	assert!(!out_of_gamut.is_empty());
+
+	for oog in &out_of_gamut {
+		assert!(indices.contains(&oog.index));
+		assert!(oog.delta_e > 0.0);
+		// The endpoints are sRGB colors and never out of gamut
+		assert_ne!(oog.index, indices[0]);
+		assert_ne!(oog.index, indices[7]);
+	}
+}
+
+#[test]
+fn test_rgb_gradient_never_out_of_gamut() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	let (_indices, out_of_gamut) = ltp
+		.add_gradient_with_options("yellow", "blue", 8, "rgb", &css_mapping())
+		.unwrap();
+	assert!(out_of_gamut.is_empty());
+}
+
+// ===== Alpha Interpolation Tests =====
+
+#[test]
+fn test_gradient_interpolates_alpha_in_all_colorspaces() {
+	for colorspace in ["rgb", "oklab", "oklch"] {
+		let mut ltp = LowTexPal::new("test.png", None);
+		ltp.add_gradient_colorspace("#ff000000", "#ff0000ff", 5, colorspace).unwrap();
+
+		let alphas: Vec<f32> = ltp.colors().iter().map(|c| c.alpha()).collect();
+		for (i, alpha) in alphas.iter().enumerate() {
+			assert_f32_near(*alpha, i as f32 / 4.0, 0.01);
+		}
+	}
+}
+
+#[test]
+fn test_gradient_keeps_opaque_alpha() {
+	for colorspace in ["rgb", "oklab", "oklch"] {
+		let mut ltp = LowTexPal::new("test.png", None);
+		ltp.add_gradient_colorspace("red", "blue", 4, colorspace).unwrap();
+		for color in ltp.colors() {
+			assert_eq!(color.rgba_u8()[3], 255);
+		}
+	}
+}
+
+#[test]
+fn test_premultiplied_gradient_ignores_transparent_color() {
+	// Fading green out to transparent red: premultiplied, the red never shows
+	// (OKLCH still interpolates the hue, which is never premultiplied)
+	let options = GradientOptions {
+		premultiplied_alpha: true,
+		..GradientOptions::default()
+	};
+
+	for colorspace in ["rgb", "oklab"] {
+		let mut ltp = LowTexPal::new("test.png", None);
+		ltp.add_gradient_with_options("lime", "#ff000000", 5, colorspace, &options).unwrap();
+
+		let lime = Color::from_string("lime").unwrap().rgba_u8();
+		for color in &ltp.colors()[..4] {
+			let rgba = color.rgba_u8();
+			for c in 0..3 {
+				assert!((rgba[c] as i16 - lime[c] as i16).abs() <= 2, "{}: {:?}", colorspace, rgba);
+			}
+		}
+		assert_eq!(ltp.colors()[4].rgba_u8()[3], 0);
+	}
+}
+
+#[test]
+fn test_straight_gradient_mixes_transparent_color() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	ltp.add_gradient_colorspace("lime", "#ff000000", 3, "rgb").unwrap();
+
+	let middle = ltp.colors()[1].rgba_u8();
+	assert!(middle[0] > 100);
+	assert!(middle[1] > 100);
+}
+
+#[test]
+fn test_fade_out_to_transparent_round_trip() {
+	let filename = temp_png("fade_out");
+	let entries = filename.replace(".png", "_entries.txt");
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.add_gradient_with_options("red", "transparent", 4, "rgb", &GradientOptions::default()).unwrap();
+	assert!(ltp.colors()[3].is_empty());
+	ltp.save();
+	assert!(std::path::Path::new(&entries).exists());
+
+	// The transparent end of the ramp is the last entry and keeps its cell, so later entries don't take it
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.load();
+	assert_eq!(loaded.colors().len(), 4);
+	assert!(loaded.colors()[3].is_empty());
+	let blue = loaded.add_color_string("blue").unwrap();
+	assert_eq!(blue, 5);
+	assert!(loaded.set_name(blue, "sky"));
+	loaded.save();
+
+	// Between other entries it is kept without the list, so the outdated list is removed
+	assert!(!std::path::Path::new(&entries).exists());
+	let mut reloaded = LowTexPal::new(&filename, None);
+	reloaded.load();
+	std::fs::remove_file(&filename).unwrap();
+	std::fs::remove_file(filename.replace(".png", "_names.txt")).unwrap();
+	std::fs::remove_file(filename.replace(".png", "_groups.txt")).unwrap();
+
+	assert_eq!(reloaded.colors().len(), 5);
+	assert!(reloaded.colors()[3].is_empty());
+	assert_eq!(reloaded.colors()[4].rgba_u8(), [0, 0, 255, 255]);
+	assert_eq!(reloaded.names()[4].as_deref(), Some("sky"));
+	assert_eq!(reloaded.entry_uv(5), loaded.entry_uv(5));
+}
+
+// ===== Quantization Tests =====
+
+#[test]
+fn test_rgba_u8_rounds_to_nearest() {
+	let color = Color::from_string("rgb(99.9%, 0.1%, 50.1%)").unwrap();
+	assert_eq!(color.rgba_u8(), [255, 0, 128, 255]);
+
+	let almost_white = Color::from_oklab(Color::from_string("white").unwrap().to_oklab());
+	assert_eq!(almost_white.rgba_u8(), [255, 255, 255, 255]);
+}
+
+#[test]
+fn test_from_string_keeps_precision() {
+	let color = Color::from_string("rgb(50%, 25%, 100%)").unwrap();
+	assert_f32_near(color.rgba()[0], 0.5, 0.0001);
+	assert_f32_near(color.rgba()[1], 0.25, 0.0001);
+	assert_eq!(color.rgba_u8(), [128, 64, 255, 255]);
+}
+
+#[test]
+fn test_gradient_endpoints_are_exact() {
+	for colorspace in ["rgb", "oklab", "oklch"] {
+		for steps in [2, 3, 7, 112] {
+			let mut ltp = LowTexPal::new("test.png", None);
+			ltp.add_gradient_colorspace("skyblue", "white", steps, colorspace).unwrap();
+
+			let colors = ltp.colors();
+			assert_eq!(colors[0].rgba_u8(), [135, 206, 235, 255], "{} {}", colorspace, steps);
+			assert_eq!(colors[colors.len() - 1].rgba_u8(), [255, 255, 255, 255], "{} {}", colorspace, steps);
+		}
+	}
+}
+
+#[test]
+fn test_quantization_error() {
+	assert_eq!(Color::from([12u8, 34, 56, 255]).quantization_error(BitDepth::Eight), 0.0);
+
+	let color = Color::from_string("rgb(50%, 50%, 50%)").unwrap();
+	let error = color.quantization_error(BitDepth::Eight);
+	assert!(error > 0.0);
+	assert!(error < 0.005);
+	assert!(color.quantization_error(BitDepth::Sixteen) < error);
+}
+
+// ===== 16-bit Tests =====
+
+fn temp_file(name: &str, extension: &str) -> String {
+	let path = std::env::temp_dir().join(format!("lowtexpal_test_{}_{}.{}", std::process::id(), name, extension));
+	path.to_string_lossy().into_owned()
+}
+
+fn temp_png(name: &str) -> String {
+	temp_file(name, "png")
+}
+
+#[test]
+fn test_color_from_u16() {
+	let color = Color::from(&image::Rgba([65535u16, 0, 32768, 65535]));
+	assert_eq!(color.rgba_u16(), [65535, 0, 32768, 65535]);
+	assert_eq!(color.rgba_u8(), [255, 0, 128, 255]);
+}
+
+#[test]
+fn test_bit_depth_from_bits() {
+	assert_eq!(BitDepth::from_bits(8), Some(BitDepth::Eight));
+	assert_eq!(BitDepth::from_bits(16), Some(BitDepth::Sixteen));
+	assert_eq!(BitDepth::from_bits(32), None);
+}
+
+#[test]
+fn test_save_load_16bit_keeps_precision() {
+	let filename = temp_png("16bit");
+
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.set_bit_depth(BitDepth::Sixteen);
+	ltp.add_gradient_colorspace("black", "red", 112, "rgb").unwrap();
+	let expected: Vec<[u16; 4]> = ltp.colors().iter().map(|c| c.rgba_u16()).collect();
+	ltp.save();
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.load();
+	std::fs::remove_file(&filename).unwrap();
+	std::fs::remove_file(filename.replace(".png", "_groups.txt")).unwrap();
+
+	assert_eq!(loaded.bit_depth(), BitDepth::Sixteen);
+	let actual: Vec<[u16; 4]> = loaded.colors().iter().map(|c| c.rgba_u16()).collect();
+	assert_eq!(actual, expected);
+}
+
+#[test]
+fn test_save_load_8bit_default() {
+	let filename = temp_png("8bit");
+
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.add_color_string("#884422").unwrap();
+	ltp.save();
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.load();
+	std::fs::remove_file(&filename).unwrap();
+
+	assert_eq!(loaded.bit_depth(), BitDepth::Eight);
+	assert_eq!(loaded.colors()[0].rgba_u8(), [0x88, 0x44, 0x22, 0xff]);
+}
+
+// ===== HDR Tests =====
+
+#[test]
+fn test_texture_format_from_filename() {
+	assert_eq!(TextureFormat::from_filename("pal.png"), TextureFormat::Png);
+	assert_eq!(TextureFormat::from_filename("pal.EXR"), TextureFormat::Exr);
+	assert_eq!(TextureFormat::from_filename("some/dir/pal.hdr"), TextureFormat::Hdr);
+	assert_eq!(TextureFormat::from_filename("pal"), TextureFormat::Png);
+}
+
+#[test]
+fn test_color_linear_round_trip() {
+	let color = Color::from_string("#884422").unwrap();
+	let linear = color.rgba_linear();
+	let back = Color::from_linear([linear[0], linear[1], linear[2]], linear[3]);
+	assert_eq!(back.rgba_u8(), [0x88, 0x44, 0x22, 0xff]);
+}
+
+#[test]
+fn test_color_with_intensity() {
+	let orange = Color::from_string("orange").unwrap();
+	assert!(!orange.is_hdr());
+
+	let bright = orange.with_intensity(4.0);
+	assert!(bright.is_hdr());
+	let linear = orange.rgba_linear();
+	let bright_linear = bright.rgba_linear();
+	for c in 0..3 {
+		assert_f32_near(bright_linear[c], linear[c] * 4.0, 0.001);
+	}
+	assert_f32_near(bright_linear[3], 1.0, 0.0001);
+
+	// Clamped in regular textures
+	assert_eq!(bright.rgba_u8()[0], 255);
+}
+
+#[test]
+fn test_add_color_string_intensity() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	ltp.add_color_string_intensity("red", 1.0).unwrap();
+	assert!(!ltp.has_hdr_entries());
+	ltp.add_color_string_intensity("orange", 4.0).unwrap();
+	assert!(ltp.has_hdr_entries());
+	assert_eq!(ltp.add_color_string_intensity("invalid", 4.0), Err(ColorParseError::UnknownColor("invalid".to_string())));
+}
+
+fn hdr_round_trip(extension: &str) {
+	let filename = temp_file("hdr", extension);
+
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.add_color_string("black").unwrap();
+	ltp.add_color_string_intensity("orange", 4.0).unwrap();
+	ltp.add_color_string("#336699").unwrap();
+	ltp.save();
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.load();
+	std::fs::remove_file(&filename).unwrap();
+
+	assert_eq!(loaded.colors().len(), 3);
+	assert!(loaded.has_hdr_entries());
+	for (expected, actual) in ltp.colors().iter().zip(loaded.colors()) {
+		let expected = expected.rgba_linear();
+		let actual = actual.rgba_linear();
+		// RGBE shares the exponent, so the precision depends on the brightest channel
+		let brightest = expected[0].max(expected[1]).max(expected[2]);
+		for c in 0..4 {
+			assert_f32_near(actual[c], expected[c], brightest * 0.01 + 0.001);
+		}
+	}
+}
+
+#[test]
+fn test_save_load_exr() {
+	hdr_round_trip("exr");
+}
+
+#[test]
+fn test_save_load_radiance_hdr() {
+	hdr_round_trip("hdr");
+}
+
+// ===== Material Map Tests =====
+
+fn material_round_trip(name: &str, material_maps: MaterialMaps, suffixes: &[&str]) {
+	let filename = temp_png(name);
+
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.set_material_maps(material_maps);
+	ltp.add_color_string("red").unwrap();
+	let gold = ltp.add_color_string("gold").unwrap();
+	ltp.add_color_string("lime").unwrap();
+	assert!(ltp.set_material(gold, Material { roughness: 0.2, metallic: 1.0, emission: 0.0 }));
+	assert!(ltp.set_material(3, Material { roughness: 0.6, metallic: 0.0, emission: 1.0 }));
+	ltp.save();
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.load();
+
+	std::fs::remove_file(&filename).unwrap();
+	for suffix in suffixes {
+		let companion = filename.replace(".png", &format!("_{}.png", suffix));
+		let img = image::open(&companion).unwrap();
+		assert_eq!(img.width(), 2);
+		std::fs::remove_file(&companion).unwrap();
+	}
+
+	let materials = loaded.materials();
+	assert_eq!(materials.len(), 3);
+	assert_eq!(materials[0], Some(Material::default()));
+	let gold = materials[1].unwrap();
+	assert_f32_near(gold.roughness, 0.2, 0.003);
+	assert_f32_near(gold.metallic, 1.0, 0.003);
+	let lime = materials[2].unwrap();
+	assert_f32_near(lime.roughness, 0.6, 0.003);
+	assert_f32_near(lime.emission, 1.0, 0.003);
+}
+
+#[test]
+fn test_material_maps_separate() {
+	material_round_trip("separate", MaterialMaps::Separate, &["roughness", "metallic", "emission"]);
+}
+
+#[test]
+fn test_material_maps_orm() {
+	material_round_trip("orm", MaterialMaps::Orm, &["orm", "emission"]);
+}
+
+#[test]
+fn test_no_material_maps_without_materials() {
+	let filename = temp_png("no_materials");
+
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.add_color_string("red").unwrap();
+	ltp.save();
+
+	std::fs::remove_file(&filename).unwrap();
+	assert!(!std::path::Path::new(&filename.replace(".png", "_roughness.png")).exists());
+	assert!(ltp.materials().iter().all(|m| m.is_none()));
+}
+
+#[test]
+fn test_set_material_invalid_index() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	ltp.add_color_string("red").unwrap();
+	assert!(!ltp.set_material(0, Material::default()));
+	assert!(!ltp.set_material(2, Material::default()));
+	assert!(ltp.set_material(1, Material::default()));
+}
+
+#[test]
+fn test_material_maps_from_name() {
+	assert_eq!(MaterialMaps::from_name("separate"), Some(MaterialMaps::Separate));
+	assert_eq!(MaterialMaps::from_name("ORM"), Some(MaterialMaps::Orm));
+	assert_eq!(MaterialMaps::from_name("packed"), None);
+}
+
+// ===== Alpha Packing Tests =====
+
+#[test]
+fn test_material_scalar_from_name() {
+	assert_eq!(MaterialScalar::from_name("roughness"), Some(MaterialScalar::Roughness));
+	assert_eq!(MaterialScalar::from_name("Metallic"), Some(MaterialScalar::Metallic));
+	assert_eq!(MaterialScalar::from_name("emission"), Some(MaterialScalar::Emission));
+	assert_eq!(MaterialScalar::from_name("alpha"), None);
+}
+
+#[test]
+fn test_alpha_scalar_round_trip() {
+	let filename = temp_png("alpha_scalar");
+
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.set_alpha_scalar(Some(MaterialScalar::Roughness));
+	let black = ltp.add_color_string("black").unwrap();
+	ltp.add_color_string("#884422").unwrap();
+	ltp.add_color_string("white").unwrap();
+	ltp.set_material(black, Material { roughness: 0.0, ..Material::default() });
+	ltp.set_material(2, Material { roughness: 0.4, ..Material::default() });
+	ltp.save();
+
+	// Stored in the alpha channel, RGB unchanged
+	let img = image::open(&filename).unwrap().into_rgba8();
+	assert_eq!(img.get_pixel(0, 0).0, [0, 0, 0, 0]);
+	assert_eq!(img.get_pixel(1, 0).0, [0x88, 0x44, 0x22, 102]);
+	assert_eq!(img.get_pixel(0, 1).0, [255, 255, 255, 255]);
+	assert!(!std::path::Path::new(&filename.replace(".png", "_roughness.png")).exists());
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.set_alpha_scalar(Some(MaterialScalar::Roughness));
+	loaded.load();
+	std::fs::remove_file(&filename).unwrap();
+
+	// Transparent black with roughness 0 is still an entry
+	assert_eq!(loaded.colors().len(), 3);
+	for color in loaded.colors() {
+		assert_eq!(color.alpha(), 1.0);
+	}
+	assert_eq!(loaded.colors()[1].rgba_u8(), [0x88, 0x44, 0x22, 0xff]);
+
+	let roughness: Vec<f32> = loaded.materials().iter().map(|m| m.unwrap().roughness).collect();
+	assert_f32_near(roughness[0], 0.0, 0.003);
+	assert_f32_near(roughness[1], 0.4, 0.003);
+	assert_f32_near(roughness[2], 1.0, 0.003);
+}
+
+// ===== Texture Format Tests =====
+
+#[test]
+fn test_texture_format_from_name() {
+	assert_eq!(TextureFormat::from_name("TGA"), Some(TextureFormat::Tga));
+	assert_eq!(TextureFormat::from_name("ktx2"), Some(TextureFormat::Ktx2));
+	assert_eq!(TextureFormat::from_name("jpg"), None);
+	assert_eq!(TextureFormat::from_filename("pal.dds"), TextureFormat::Dds);
+	assert_eq!(TextureFormat::from_filename("pal.bmp"), TextureFormat::Bmp);
+}
+
+fn format_round_trip(extension: &str, bit_depth: BitDepth) -> LowTexPal {
+	let filename = temp_file(&format!("format_{:?}", bit_depth), extension);
+
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.set_bit_depth(bit_depth);
+	ltp.add_color_string("#884422").unwrap();
+	ltp.add_color_string("#12345680").unwrap();
+	ltp.add_color_string("white").unwrap();
+	ltp.save();
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.load();
+	std::fs::remove_file(&filename).unwrap();
+
+	let expected: Vec<[u8; 4]> = ltp.colors().iter().map(|c| c.rgba_u8()).collect();
+	let actual: Vec<[u8; 4]> = loaded.colors().iter().map(|c| c.rgba_u8()).collect();
+	assert_eq!(actual, expected, "{}", extension);
+	loaded
+}
+
+#[test]
+fn test_save_load_all_formats() {
+	for extension in ["png", "tga", "bmp", "dds", "ktx2"] {
+		format_round_trip(extension, BitDepth::Eight);
+	}
+}
+
+#[test]
+fn test_save_load_16bit_containers() {
+	for extension in ["dds", "ktx2"] {
+		let loaded = format_round_trip(extension, BitDepth::Sixteen);
+		assert_eq!(loaded.bit_depth(), BitDepth::Sixteen);
+	}
+}
+
+#[test]
+fn test_save_load_hdr_containers() {
+	for extension in ["dds", "ktx2"] {
+		let filename = temp_file("hdr_container", extension);
+
+		let mut ltp = LowTexPal::new(&filename, None);
+		ltp.add_color_string_intensity("orange", 4.0).unwrap();
+		ltp.save();
+
+		let mut loaded = LowTexPal::new(&filename, None);
+		loaded.load();
+		std::fs::remove_file(&filename).unwrap();
+
+		assert!(loaded.has_hdr_entries());
+		let expected = ltp.colors()[0].rgba_linear();
+		let actual = loaded.colors()[0].rgba_linear();
+		for c in 0..4 {
+			assert_f32_near(actual[c], expected[c], 0.0001);
+		}
+	}
+}
+
+#[test]
+fn test_format_override() {
+	let filename = temp_file("override", "palette");
+
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.set_format(TextureFormat::Tga);
+	ltp.add_color_string("red").unwrap();
+	ltp.save();
+
+	let tga = image::load(
+		std::io::BufReader::new(std::fs::File::open(&filename).unwrap()),
+		image::ImageFormat::Tga,
+	);
+	assert!(tga.is_ok());
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.set_format(TextureFormat::Tga);
+	loaded.load();
+	std::fs::remove_file(&filename).unwrap();
+	assert_eq!(loaded.colors()[0].rgba_u8(), [255, 0, 0, 255]);
+}
+
+#[test]
+fn test_container_format_tags() {
+	let filename = temp_file("tags", "dds");
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.add_color_string("red").unwrap();
+	ltp.save();
+	let dds = container::read_dds(&filename).unwrap();
+	std::fs::remove_file(&filename).unwrap();
+	assert_eq!(dds.format, PixelFormat::Rgba8 { srgb: true });
+
+	let filename = temp_file("tags", "ktx2");
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.set_bit_depth(BitDepth::Sixteen);
+	ltp.add_color_string("red").unwrap();
+	ltp.save();
+	let bytes = std::fs::read(&filename).unwrap();
+	let ktx2 = container::read_ktx2(&filename).unwrap();
+	std::fs::remove_file(&filename).unwrap();
+	assert_eq!(ktx2.format, PixelFormat::Rgba16);
+	// VK_FORMAT_R16G16B16A16_UNORM
+	assert_eq!(u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]), 91);
+}
+
+#[test]
+fn test_container_mip_chain_round_trip() {
+	let texture = container::TextureData {
+		width: 4,
+		height: 4,
+		format: PixelFormat::Rgba8 { srgb: false },
+		levels: vec![vec![1; 64], vec![2; 16], vec![3; 4]],
+	};
+
+	for extension in ["dds", "ktx2"] {
+		let filename = temp_file("mips", extension);
+		match extension {
+			"dds" => container::write_dds(&filename, &texture).unwrap(),
+			_ => container::write_ktx2(&filename, &texture).unwrap(),
+		}
+		let loaded = match extension {
+			"dds" => container::read_dds(&filename).unwrap(),
+			_ => container::read_ktx2(&filename).unwrap(),
+		};
+		std::fs::remove_file(&filename).unwrap();
+
+		assert_eq!(loaded.format, texture.format);
+		assert_eq!(loaded.levels, texture.levels);
+	}
+}
+
+#[test]
+fn test_container_rejects_wrong_level_size() {
+	let texture = container::TextureData {
+		width: 2,
+		height: 2,
+		format: PixelFormat::Rgba8 { srgb: true },
+		levels: vec![vec![0; 15]],
+	};
+	assert!(container::write_dds(&temp_file("invalid", "dds"), &texture).is_err());
+}
+
+#[test]
+fn test_container_rejects_hostile_headers() {
+	let texture = container::TextureData {
+		width: 4,
+		height: 4,
+		format: PixelFormat::Rgba8 { srgb: true },
+		levels: vec![vec![0; 64], vec![0; 16], vec![0; 4]],
+	};
+	let patched = |extension: &str, offset: usize, value: &[u8]| {
+		let filename = temp_file("hostile", extension);
+		match extension {
+			"dds" => container::write_dds(&filename, &texture).unwrap(),
+			_ => container::write_ktx2(&filename, &texture).unwrap(),
+		}
+		let mut bytes = std::fs::read(&filename).unwrap();
+		bytes[offset..offset + value.len()].copy_from_slice(value);
+		// Plenty of data so only the header can be at fault
+		bytes.resize(bytes.len() + 4096, 0);
+		std::fs::write(&filename, &bytes).unwrap();
+		let result = match extension {
+			"dds" => container::read_dds(&filename),
+			_ => container::read_ktx2(&filename),
+		};
+		std::fs::remove_file(&filename).unwrap();
+		result
+	};
+
+	// DDS mip count and size
+	assert!(patched("dds", 28, &40u32.to_le_bytes()).is_err());
+	assert!(patched("dds", 12, &u32::MAX.to_le_bytes()).is_err());
+	// KTX2 level count, first level offset and length
+	assert!(patched("ktx2", 40, &40u32.to_le_bytes()).is_err());
+	assert!(patched("ktx2", 80, &u64::MAX.to_le_bytes()).is_err());
+	assert!(patched("ktx2", 88, &(u64::MAX - 8).to_le_bytes()).is_err());
+
+	// Truncated files
+	for extension in ["dds", "ktx2"] {
+		let filename = temp_file("truncated", extension);
+		match extension {
+			"dds" => container::write_dds(&filename, &texture).unwrap(),
+			_ => container::write_ktx2(&filename, &texture).unwrap(),
+		}
+		let bytes = std::fs::read(&filename).unwrap();
+		std::fs::write(&filename, &bytes[..100]).unwrap();
+		let result = match extension {
+			"dds" => container::read_dds(&filename),
+			_ => container::read_ktx2(&filename),
+		};
+		std::fs::remove_file(&filename).unwrap();
+		assert!(result.is_err());
+	}
+}
+
+// ===== Cell Size / Mip Chain Tests =====
+
+#[test]
+fn test_cell_size_round_trip() {
+	let filename = temp_png("cells");
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.set_cell_size(3); // rounded up to 4
+	assert_eq!(ltp.cell_size(), 4);
+	ltp.add_color_string("red").unwrap();
+	ltp.add_color_string("lime").unwrap();
+	let index = ltp.add_color_string("blue").unwrap();
+	ltp.set_material(index, Material { roughness: 0.2, metallic: 1.0, emission: 0.0 });
+	ltp.save();
+
+	let img = image::open(&filename).unwrap();
+	assert_eq!((img.width(), img.height()), (8, 8));
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.set_cell_size(4);
+	loaded.load();
+	for suffix in ["roughness", "metallic", "emission"] {
+		std::fs::remove_file(filename.replace(".png", &format!("_{}.png", suffix))).unwrap();
+	}
+	std::fs::remove_file(&filename).unwrap();
+
+	assert_eq!(loaded.colors().len(), 3);
+	assert_eq!(loaded.colors()[1].rgba_u8(), [0, 255, 0, 255]);
+	let material = loaded.materials()[2].unwrap();
+	assert_f32_near(material.roughness, 0.2, 0.01);
+	assert_f32_near(material.metallic, 1.0, 0.01);
+}
+
+#[test]
+fn test_cell_size_limit() {
+	let filename = temp_png("huge_cells");
+	let mut ltp = LowTexPal::new(&filename, None);
+	assert!(!ltp.set_cell_size(3_000_000_000));
+	assert!(!ltp.set_cell_size(MAX_IMAGE_SIZE + 1));
+	assert_eq!(ltp.cell_size(), 1);
+	assert!(ltp.set_cell_size(MAX_IMAGE_SIZE));
+
+	// Two entries need a 2x2 grid, which would be twice the largest image
+	ltp.add_color_string("red").unwrap();
+	ltp.add_color_string("lime").unwrap();
+	assert_eq!(ltp.entry_uv(1), None);
+	ltp.save();
+	assert!(ltp.was_modified());
+	assert!(!std::path::Path::new(&filename).exists());
+
+	let mut wide = LowTexPal::new(&filename, Some(u32::MAX));
+	wide.add_color_string("red").unwrap();
+	assert_eq!(wide.entry_uv(1), None);
+	wide.set_layout(Layout::Fixed);
+	assert_eq!(wide.entry_uv(1), None);
+}
+
+#[test]
+fn test_pure_mip_chain() {
+	let palette = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255], [255, 255, 255, 255]];
+
+	for extension in ["dds", "ktx2"] {
+		let filename = temp_file("pure_mips", extension);
+		let mut ltp = LowTexPal::new(&filename, None);
+		ltp.set_cell_size(4);
+		ltp.set_pure_mip_levels(Some(2));
+		for color in ["red", "lime", "blue", "white"] {
+			ltp.add_color_string(color).unwrap();
+		}
+		ltp.save();
+
+		let texture = match extension {
+			"dds" => container::read_dds(&filename).unwrap(),
+			_ => container::read_ktx2(&filename).unwrap(),
+		};
+		let mut loaded = LowTexPal::new(&filename, None);
+		loaded.set_cell_size(4);
+		loaded.load();
+		std::fs::remove_file(&filename).unwrap();
+
+		// 8x8, 4x4, 2x2, 1x1
+		assert_eq!(texture.levels.len(), 4);
+		for level in &texture.levels[..3] {
+			for pixel in level.chunks_exact(4) {
+				assert!(palette.contains(&[pixel[0], pixel[1], pixel[2], pixel[3]]), "{:?}", pixel);
+			}
+		}
+		// Every pure level is solid per cell, edge pixels included, so a cell is its own padding
+		for (level, data) in texture.levels[..3].iter().enumerate() {
+			let width = 8 >> level;
+			let cell = 4 >> level;
+			for (pixel, rgba) in data.chunks_exact(4).enumerate() {
+				let (x, y) = (pixel % width, pixel / width);
+				assert_eq!(rgba, palette[y / cell * 2 + x / cell], "level {} pixel {}", level, pixel);
+			}
+		}
+		// Cells are 1 pixel at level 2, so the 2x2 level is exactly the palette
+		assert_eq!(texture.levels[2].chunks_exact(4).count(), 4);
+		let average = &texture.levels[3];
+		assert!(!palette.contains(&[average[0], average[1], average[2], average[3]]));
+		// Averaged in linear light: (1 + 0 + 0 + 1) / 4 = 0.5 linear
+		assert_eq!(average[0], 188);
+
+		assert_eq!(loaded.colors().len(), 4);
+		assert_eq!(loaded.colors()[2].rgba_u8(), palette[2]);
+	}
+}
+
+#[test]
+fn test_gutter_safe_uv_area() {
+	let mut ltp = LowTexPal::new("test.dds", None);
+	assert!(!ltp.set_gutter(1));
+	ltp.set_cell_size(8);
+	assert!(!ltp.set_gutter(4));
+	assert!(!ltp.set_gutter(u32::MAX));
+	assert_eq!(ltp.gutter_mip_level(), None);
+	assert!(ltp.set_gutter(2));
+	assert_eq!(ltp.gutter(), 2);
+	// 2 pixels at level 0, 1 at level 1, half a pixel at level 2
+	assert_eq!(ltp.gutter_mip_level(), Some(2));
+
+	ltp.add_color_string("red").unwrap();
+	assert_eq!(ltp.entry_uv_area(1), Some([0.25, 0.25, 0.75, 0.75]));
+	assert_eq!(ltp.entry_uv_area(2), None);
+
+	// 16x16 with two cells in the top row
+	ltp.add_color_string("lime").unwrap();
+	assert_eq!(ltp.entry_uv_area(2), Some([0.625, 0.125, 0.875, 0.375]));
+	let uv = ltp.entry_uv(2).unwrap();
+	let area = ltp.entry_uv_area(2).unwrap();
+	assert!(area[0] < uv[0] && uv[0] < area[2] && area[1] < uv[1] && uv[1] < area[3]);
+
+	assert!(ltp.set_gutter(1));
+	assert_eq!(ltp.gutter_mip_level(), Some(1));
+	assert!(ltp.set_gutter(3));
+	assert_eq!(ltp.gutter_mip_level(), Some(2));
+}
+
+#[test]
+fn test_mip_chain_averages_below_pure_levels() {
+	let filename = temp_file("averaged_mips", "dds");
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.set_cell_size(4);
+	ltp.set_pure_mip_levels(Some(0));
+	ltp.add_color_string("red").unwrap();
+	ltp.add_color_string("lime").unwrap();
+	ltp.save();
+
+	let texture = container::read_dds(&filename).unwrap();
+	std::fs::remove_file(&filename).unwrap();
+
+	// 8x8 with red and green in the top row, unused cells below
+	assert_eq!(texture.levels.len(), 4);
+	// Level 1 still has pure cells, since averaging only happens within one cell
+	assert_eq!(&texture.levels[1][..4], &[255, 0, 0, 255]);
+	// Level 3 mixes red, green and the transparent unused cells
+	let average = &texture.levels[3];
+	assert_eq!(average[0], average[1]);
+	assert_eq!(average[2], 0);
+	assert_eq!(average[3], 128);
+}
+
+#[test]
+fn test_no_mip_chain_by_default() {
+	let filename = temp_file("no_mips", "ktx2");
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.set_cell_size(4);
+	ltp.add_color_string("red").unwrap();
+	ltp.save();
+
+	let texture = container::read_ktx2(&filename).unwrap();
+	std::fs::remove_file(&filename).unwrap();
+	assert_eq!(texture.levels.len(), 1);
+	assert_eq!((texture.width, texture.height), (4, 4));
+}
+
+// ===== Encoding Tests =====
+
+#[test]
+fn test_encoding_from_name() {
+	assert_eq!(Encoding::from_name("srgb"), Some(Encoding::Srgb));
+	assert_eq!(Encoding::from_name("Linear"), Some(Encoding::Linear));
+	assert_eq!(Encoding::from_name("gamma"), None);
+}
+
+// Chunk types and data of a PNG file, in file order
+fn png_chunks(filename: &str) -> Vec<(String, Vec<u8>)> {
+	let bytes = std::fs::read(filename).unwrap();
+	let mut chunks = Vec::new();
+	let mut offset = 8;
+	while offset + 8 <= bytes.len() {
+		let length = u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as usize;
+		let name = String::from_utf8_lossy(&bytes[offset + 4..offset + 8]).to_string();
+		chunks.push((name, bytes[offset + 8..offset + 8 + length].to_vec()));
+		offset += 12 + length;
+	}
+	chunks
+}
+
+fn chunk<'a>(chunks: &'a [(String, Vec<u8>)], name: &str) -> Option<&'a [u8]> {
+	chunks.iter().find(|(n, _)| n == name).map(|(_, data)| data.as_slice())
+}
+
+#[test]
+fn test_png_declares_srgb() {
+	let filename = temp_png("declare_srgb");
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.add_color_string("red").unwrap();
+	ltp.save();
+	let chunks = png_chunks(&filename);
+	std::fs::remove_file(&filename).unwrap();
+
+	assert_eq!(chunk(&chunks, "sRGB"), Some(&[0][..]));
+	assert_eq!(chunk(&chunks, "gAMA"), Some(&45455u32.to_be_bytes()[..]));
+	assert_eq!(chunk(&chunks, "cICP"), Some(&[1, 13, 0, 1][..]));
+	// Metadata has to come before the image data
+	let position = |name: &str| chunks.iter().position(|(n, _)| n == name).unwrap();
+	assert!(position("cICP") < position("IDAT"));
+}
+
+#[test]
+fn test_png_linear_round_trip() {
+	for bit_depth in [BitDepth::Eight, BitDepth::Sixteen] {
+		let filename = temp_png("linear");
+		let mut ltp = LowTexPal::new(&filename, None);
+		ltp.set_bit_depth(bit_depth);
+		ltp.set_encoding(Encoding::Linear);
+		ltp.add_color_string("#c08040").unwrap();
+		ltp.save();
+
+		let chunks = png_chunks(&filename);
+		assert_eq!(chunk(&chunks, "sRGB"), None);
+		assert_eq!(chunk(&chunks, "gAMA"), Some(&100000u32.to_be_bytes()[..]));
+		assert_eq!(chunk(&chunks, "cICP"), Some(&[1, 8, 0, 1][..]));
+
+		// Stored values are linear
+		let raw = image::open(&filename).unwrap().into_rgba8();
+		assert_eq!(raw.get_pixel(0, 0).0, [134, 55, 13, 255]);
+
+		let mut loaded = LowTexPal::new(&filename, None);
+		loaded.load();
+		std::fs::remove_file(&filename).unwrap();
+
+		assert_eq!(loaded.encoding(), Encoding::Linear);
+		let rgba = loaded.colors()[0].rgba();
+		assert_f32_near(rgba[0], 192.0 / 255.0, 0.005);
+		assert_f32_near(rgba[1], 128.0 / 255.0, 0.005);
+		assert_f32_near(rgba[2], 64.0 / 255.0, 0.01);
+	}
+}
+
+#[test]
+fn test_container_linear_encoding() {
+	let filename = temp_file("linear", "ktx2");
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.set_encoding(Encoding::Linear);
+	ltp.add_color_string("#c08040").unwrap();
+	ltp.save();
+
+	let texture = container::read_ktx2(&filename).unwrap();
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.load();
+	std::fs::remove_file(&filename).unwrap();
+
+	assert_eq!(texture.format, PixelFormat::Rgba8 { srgb: false });
+	assert_eq!(&texture.levels[0][..4], &[134, 55, 13, 255]);
+	assert_eq!(loaded.encoding(), Encoding::Linear);
+}
+
+// ===== Wide Gamut Tests =====
+
+#[test]
+fn test_parse_display_p3() {
+	let color = Color::from_string("color(display-p3 1 0 0)").unwrap();
+	let linear = color.rgba_linear();
+	assert_f32_near(linear[0], 1.2249, 0.001);
+	assert_f32_near(linear[1], -0.0421, 0.001);
+	assert_f32_near(linear[2], -0.0196, 0.001);
+	assert!(!color.in_srgb_gamut());
+
+	let p3 = color.to_display_p3();
+	assert_f32_near(p3[0], 1.0, 0.001);
+	assert_f32_near(p3[1], 0.0, 0.001);
+	assert_f32_near(p3[2], 0.0, 0.001);
+}
+
+#[test]
+fn test_srgb_in_display_p3() {
+	// CSS Color 4: rgb(255 0 0) is color(display-p3 0.9175 0.2003 0.1386)
+	let p3 = Color::from_string("red").unwrap().to_display_p3();
+	assert_f32_near(p3[0], 0.9175, 0.001);
+	assert_f32_near(p3[1], 0.2003, 0.001);
+	assert_f32_near(p3[2], 0.1386, 0.001);
+
+	let back = Color::from_display_p3([p3[0], p3[1], p3[2]], 1.0);
+	assert!(back.in_srgb_gamut());
+	assert_eq!(back.rgba_u8(), [255, 0, 0, 255]);
+}
+
+#[test]
+fn test_rec2020_round_trip() {
+	let color = Color::from_string("#c08040").unwrap();
+	let rec2020 = color.to_rec2020();
+	let back = Color::from_rec2020([rec2020[0], rec2020[1], rec2020[2]], rec2020[3]);
+	for c in 0..4 {
+		assert_f32_near(back.rgba()[c], color.rgba()[c], 0.0005);
+	}
+
+	let green = Color::from_string("color(rec2020 0 1 0)").unwrap();
+	assert!(!green.in_srgb_gamut());
+	assert!(green.rgba_linear()[0] < 0.0);
+}
+
+#[test]
+fn test_parse_color_function_variants() {
+	let color = Color::from_string("color(srgb 100% 50% none / 25%)").unwrap();
+	assert_eq!(color.rgba(), [1.0, 0.5, 0.0, 0.25]);
+
+	let color = Color::from_string("COLOR(srgb-linear 0.5 0.5 0.5)").unwrap();
+	assert_f32_near(color.rgba()[0], 0.7354, 0.001);
+
+	assert!(Color::from_string("color(display-p3 1 0)").is_none());
+	assert!(Color::from_string("color(display-p3 1 0 0 0)").is_none());
+	assert!(Color::from_string("color(prophoto-rgb 1 0 0)").is_none());
+	assert!(Color::from_string("color(display-p3 1 x 0)").is_none());
+}
+
+#[test]
+fn test_to_srgb_gamut() {
+	let p3_red = Color::from_display_p3([1.0, 0.0, 0.0], 0.5);
+
+	let clipped = p3_red.to_srgb_gamut(GamutMapping::Clip, false);
+	assert_eq!(clipped.rgba_u8(), [255, 0, 0, 128]);
+
+	let mapped = p3_red.to_srgb_gamut(GamutMapping::Css, false);
+	assert!(mapped.in_srgb_gamut());
+	assert!(mapped.rgba_linear()[0..3].iter().all(|&c| (0.0..=1.0).contains(&c)));
+	assert_f32_near(mapped.alpha(), 0.5, 0.0001);
+
+	// HDR intensity is kept, only the chromaticity is mapped
+	let bright = Color::from_display_p3([1.0, 0.0, 0.0], 1.0).with_intensity(4.0);
+	let mapped = bright.to_srgb_gamut(GamutMapping::Clip, true);
+	assert!(mapped.in_srgb_gamut());
+	assert!(mapped.is_hdr());
+	assert!(mapped.rgba_linear()[0] > 4.0);
+
+	// In gamut colors are left alone
+	let red = Color::from_string("red").unwrap();
+	assert_eq!(red.to_srgb_gamut(GamutMapping::Css, false).rgba(), red.rgba());
+}
+
+#[test]
+fn test_save_maps_wide_gamut_entries() {
+	for (gamut_mapping, expected) in [(GamutMapping::Clip, Some([255, 0, 0, 255])), (GamutMapping::Css, None)] {
+		let filename = temp_png("wide_gamut");
+		let mut ltp = LowTexPal::new(&filename, None);
+		ltp.set_gamut_mapping(gamut_mapping);
+		ltp.add_color_string("color(display-p3 1 0 0)").unwrap();
+		ltp.add_color_string("color(display-p3 0 1 0)").unwrap();
+		ltp.save();
+
+		let mut loaded = LowTexPal::new(&filename, None);
+		loaded.load();
+		std::fs::remove_file(&filename).unwrap();
+
+		assert_eq!(loaded.colors().len(), 2);
+		if let Some(expected) = expected {
+			assert_eq!(loaded.colors()[0].rgba_u8(), expected);
+		}
+		// Either way the mapped green keeps roughly its lightness
+		let lab = Color::from_display_p3([0.0, 1.0, 0.0], 1.0).to_oklab();
+		let stored = loaded.colors()[1].to_oklab();
+		assert!((stored[0] - lab[0]).abs() < 0.06, "{:?} {:?}", stored, lab);
+	}
+}
+
+#[test]
+fn test_save_maps_bright_wide_gamut_entries() {
+	// Outside of sRGB only by being brighter than 1.0 in red, without an intensity that is not HDR
+	let color = "color(display-p3 1 0.7 0.7)";
+	let p3 = Color::from_string(color).unwrap();
+	assert!(p3.is_hdr());
+	assert!(p3.in_srgb_gamut());
+
+	let mut clipped = [0; 4];
+	for (gamut_mapping, extension) in [(GamutMapping::Clip, "png"), (GamutMapping::Css, "png"), (GamutMapping::Css, "exr")] {
+		let filename = temp_file("bright_wide_gamut", extension);
+		let mut ltp = LowTexPal::new(&filename, None);
+		ltp.set_gamut_mapping(gamut_mapping);
+		let index = ltp.add_color_string_intensity(color, 1.0).unwrap();
+		assert!(!ltp.is_hdr_entry(index));
+		assert!(!ltp.has_hdr_entries());
+		ltp.save();
+
+		let mut loaded = LowTexPal::new(&filename, None);
+		loaded.load();
+		std::fs::remove_file(&filename).unwrap();
+
+		let stored = loaded.colors()[0];
+		assert!(!loaded.has_hdr_entries());
+		assert!(stored.rgba_linear()[0..3].iter().all(|&c| c <= 1.0 + 0.001), "{:?}", stored.rgba_linear());
+		match gamut_mapping {
+			GamutMapping::Clip => clipped = stored.rgba_u8(),
+			// CSS gamut mapping keeps the hue, which clipping the red channel shifts
+			GamutMapping::Css => assert_ne!(stored.rgba_u8(), clipped),
+		}
+	}
+
+	// With an intensity the same color is an HDR entry and keeps its brightness in float formats
+	let filename = temp_file("bright_hdr", "exr");
+	let mut ltp = LowTexPal::new(&filename, None);
+	let index = ltp.add_color_string_intensity(color, 2.0).unwrap();
+	assert!(ltp.is_hdr_entry(index));
+	ltp.save();
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.load();
+	std::fs::remove_file(&filename).unwrap();
+	assert!(loaded.is_hdr_entry(1));
+	assert!(loaded.colors()[0].rgba_linear()[0] > 2.0);
+}
+
+// ===== CSS Color 4 Parsing Tests =====
+
+#[test]
+fn test_parse_css4_functions_red() {
+	// Reference values for sRGB red from CSS Color 4
+	for css in [
+		"oklch(62.8% 0.2577 29.23)",
+		"oklab(0.628 0.2249 0.1258)",
+		"lab(54.29 80.8 69.89)",
+		"lch(54.29% 106.84 40.86deg)",
+		"hwb(0 0% 0%)",
+		"hwb(0deg 0 0)",
+	] {
+		let color = Color::parse(css).unwrap();
+		assert_eq!(color.rgba_u8(), [255, 0, 0, 255], "{}", css);
+	}
+}
+
+#[test]
+fn test_to_lab_reference() {
+	let lab = Color::from_string("red").unwrap().to_lab();
+	assert_f32_near(lab[0], 54.29, 0.02);
+	assert_f32_near(lab[1], 80.80, 0.05);
+	assert_f32_near(lab[2], 69.89, 0.05);
+
+	let white = Color::from_string("white").unwrap().to_lab();
+	assert_f32_near(white[0], 100.0, 0.01);
+	assert_f32_near(white[1], 0.0, 0.01);
+	assert_f32_near(white[2], 0.0, 0.01);
+}
+
+#[test]
+fn test_parse_hwb() {
+	assert_eq!(Color::parse("hwb(120 0% 0%)").unwrap().rgba_u8(), [0, 255, 0, 255]);
+	assert_eq!(Color::parse("hwb(240 20% 40%)").unwrap().rgba_u8(), [51, 51, 153, 255]);
+	// Whiteness and blackness adding up to more than 100% give a gray
+	assert_eq!(Color::parse("hwb(90 100% 100%)").unwrap().rgba(), [0.5, 0.5, 0.5, 1.0]);
+}
+
+#[test]
+fn test_parse_hue_units_and_alpha() {
+	let degrees = Color::parse("oklch(70% 0.1 180)").unwrap();
+	for css in ["oklch(70% 0.1 180deg)", "oklch(70% 0.1 0.5turn)", "oklch(70% 0.1 200grad)", "oklch(70% 0.1 3.14159265rad)"] {
+		let color = Color::parse(css).unwrap();
+		for c in 0..3 {
+			assert_f32_near(color.rgba()[c], degrees.rgba()[c], 0.0001);
+		}
+	}
+
+	let color = Color::parse("oklab(50% 25% -25% / 0.5)").unwrap();
+	assert_f32_near(color.alpha(), 0.5, 0.0001);
+	let lab = color.to_oklab();
+	assert_f32_near(lab[0], 0.5, 0.001);
+	assert_f32_near(lab[1], 0.1, 0.001);
+	assert_f32_near(lab[2], -0.1, 0.001);
+
+	// Powerless hue
+	assert_eq!(Color::parse("oklch(50% 0 none)").unwrap().rgba_u8(), Color::parse("oklch(50% 0 90)").unwrap().rgba_u8());
+}
+
+#[test]
+fn test_parse_errors() {
+	assert_eq!(Color::parse("notacolor").unwrap_err(), ColorParseError::UnknownColor("notacolor".to_string()));
+	assert_eq!(Color::parse("oklch(70% 0.1 140").unwrap_err(), ColorParseError::InvalidSyntax("oklch(70% 0.1 140".to_string()));
+	assert_eq!(
+		Color::parse("lab(50 20)").unwrap_err(),
+		ColorParseError::ComponentCount { function: "lab".to_string(), expected: 3, found: 2 }
+	);
+	assert_eq!(
+		Color::parse("oklch(70% 0.1 10%)").unwrap_err(),
+		ColorParseError::InvalidComponent {
+			function: "oklch".to_string(),
+			position: 3,
+			value: "10%".to_string(),
+			expected: "a hue (number or angle)",
+		}
+	);
+	assert!(matches!(Color::parse("oklab(0.5 0.1 0.1 / x)"), Err(ColorParseError::InvalidComponent { position: 4, .. })));
+	assert_eq!(Color::parse("color(xyz 1 0 0)").unwrap_err(), ColorParseError::UnknownColorSpace("xyz".to_string()));
+	assert_eq!(
+		Color::parse("oklch(70%, 0.1, 140)").unwrap_err().to_string(),
+		"oklch() component 1 '70%,' is not a number or percentage"
+	);
+}
+
+#[test]
+fn test_gradient_reports_parse_error() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	assert_eq!(
+		ltp.add_gradient_with_options("red", "lab(50 20)", 4, "oklab", &GradientOptions::default()).unwrap_err(),
+		ColorParseError::ComponentCount { function: "lab".to_string(), expected: 3, found: 2 }
+	);
+	assert!(ltp.colors().is_empty());
+}
+
+// ===== Color Expression Tests =====
+
+#[test]
+fn test_expression_mix() {
+	let red = Color::from_string("red").unwrap();
+	let blue = Color::from_string("blue").unwrap();
+
+	let half = expression::evaluate("mix(red, blue)", &[]).unwrap();
+	let lab = half.to_oklab();
+	let (r, b) = (red.to_oklab(), blue.to_oklab());
+	for c in 0..3 {
+		assert_f32_near(lab[c], (r[c] + b[c]) / 2.0, 0.001);
+	}
+
+	// The percentage is the share of the second color
+	let mixed = expression::evaluate("mix(red, blue, 30%)", &[]).unwrap().to_oklab();
+	assert_f32_near(mixed[0], r[0] + 0.3 * (b[0] - r[0]), 0.001);
+
+	assert_eq!(expression::evaluate("mix(red, blue, 0%)", &[]).unwrap().rgba_u8(), [255, 0, 0, 255]);
+	assert_eq!(expression::evaluate("mix(red, blue, 1)", &[]).unwrap().rgba_u8(), [0, 0, 255, 255]);
+}
+
+#[test]
+fn test_expression_lighten_darken_rotate() {
+	let base = Color::from_string("#884422").unwrap().to_oklch();
+
+	let lighter = expression::evaluate("lighten(#884422, 10%)", &[]).unwrap().to_oklch();
+	assert_f32_near(lighter[0], base[0] + 0.1, 0.001);
+	assert_f32_near(lighter[1], base[1], 0.001);
+
+	let darker = expression::evaluate("darken(#884422, 0.05)", &[]).unwrap().to_oklch();
+	assert_f32_near(darker[0], base[0] - 0.05, 0.001);
+
+	let teal = Color::from_string("teal").unwrap().to_oklch();
+	let rotated = expression::evaluate("rotate-hue(teal, 30deg)", &[]).unwrap().to_oklch();
+	assert_f32_near(rotated[0], teal[0], 0.001);
+	assert_f32_near((rotated[2] - teal[2]).to_degrees(), 30.0, 0.1);
+}
+
+#[test]
+fn test_expression_references_and_nesting() {
+	let palette = [Color::from_string("red").unwrap(), Color::from_string("#00ff0080").unwrap()];
+
+	assert_eq!(expression::evaluate("@2", &palette).unwrap().rgba_u8(), [0, 255, 0, 128]);
+	let nested = expression::evaluate("mix(lighten(@1, 5%), rgb(0, 0, 255), 25%)", &palette).unwrap();
+	let expected = expression::evaluate("mix(lighten(red, 5%), blue, 25%)", &palette).unwrap();
+	assert_eq!(nested.rgba_u8(), expected.rgba_u8());
+
+	// Alpha is kept and mixed
+	assert_f32_near(expression::evaluate("lighten(@2, 10%)", &palette).unwrap().alpha(), 128.0 / 255.0, 0.001);
+	assert_f32_near(expression::evaluate("mix(@1, @2)", &palette).unwrap().alpha(), 0.75, 0.01);
+}
+
+#[test]
+fn test_expression_errors() {
+	let palette = [Color::from_string("red").unwrap()];
+
+	assert_eq!(
+		expression::evaluate("@3", &palette).unwrap_err(),
+		ColorParseError::InvalidReference { reference: "@3".to_string(), entries: 1 }
+	);
+	assert!(matches!(expression::evaluate("@0", &palette), Err(ColorParseError::InvalidReference { .. })));
+	assert_eq!(
+		expression::evaluate("mix(red)", &palette).unwrap_err(),
+		ColorParseError::ArgumentCount { function: "mix".to_string(), expected: "2 or 3", found: 1 }
+	);
+	assert_eq!(
+		expression::evaluate("lighten(red, much)", &palette).unwrap_err().to_string(),
+		"lighten() argument 2 'much' is not a percentage"
+	);
+	assert!(matches!(expression::evaluate("mix(red, blue", &palette), Err(ColorParseError::InvalidSyntax(_))));
+	assert!(matches!(expression::evaluate("mix(red, nope)", &palette), Err(ColorParseError::UnknownColor(_))));
+	assert_eq!(
+		expression::evaluate("mix(red, blue, 150%)", &palette).unwrap_err().to_string(),
+		"mix() argument 3 '150%' is not a percentage between 0% and 100%"
+	);
+	assert!(matches!(expression::evaluate("mix(red, blue, -10%)", &palette), Err(ColorParseError::InvalidArgument { position: 3, .. })));
+}
+
+#[test]
+fn test_expression_nesting_limit() {
+	let nested = |depth: usize| format!("{}red{}", "lighten(".repeat(depth), ", 1%)".repeat(depth));
+	assert!(expression::evaluate(&nested(32), &[]).is_ok());
+	assert_eq!(expression::evaluate(&nested(33), &[]).unwrap_err(), ColorParseError::NestingTooDeep(32));
+	// Deep enough to overflow the stack without the limit
+	let deep = format!("{}red{}", "mix(".repeat(10000), ", blue)".repeat(10000));
+	assert!(matches!(expression::evaluate(&deep, &[]), Err(ColorParseError::NestingTooDeep(_))));
+}
+
+#[test]
+fn test_add_color_expression() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	ltp.add_color_string("#884422").unwrap();
+	let index = ltp.add_color_string("lighten(@1, 10%)").unwrap();
+	assert_eq!(index, 2);
+	let indices = ltp.add_gradient_colorspace("@1", "@2", 3, "oklab").unwrap();
+	assert_eq!(indices.len(), 3);
+	assert!(ltp.parse_color("@9").is_err());
+}
+
+// ===== Entry Reference Tests =====
+
+#[test]
+fn test_reference_last() {
+	let palette = [Color::from_string("red").unwrap(), Color::from_string("blue").unwrap()];
+	assert_eq!(expression::evaluate("@last", &palette).unwrap().rgba_u8(), [0, 0, 255, 255]);
+	assert!(matches!(expression::evaluate("@last", &[]), Err(ColorParseError::InvalidReference { entries: 0, .. })));
+}
+
+#[test]
+fn test_entry_names() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	let skin = ltp.add_color_string("#e0ac69").unwrap();
+	let hair = ltp.add_color_string("#3b2219").unwrap();
+
+	assert!(ltp.set_name(skin, "skin"));
+	assert!(ltp.set_name(hair, "hair-dark_2"));
+	assert!(ltp.set_name(skin, "skin")); // renaming to the same name is fine
+	assert!(!ltp.set_name(hair, "skin")); // already used
+	assert!(!ltp.set_name(hair, "42"));
+	assert!(!ltp.set_name(hair, "last"));
+	assert!(!ltp.set_name(hair, "two words"));
+	assert!(!ltp.set_name(3, "missing"));
+
+	assert_eq!(ltp.entry_by_name("hair-dark_2"), Some(hair));
+	assert_eq!(ltp.entry_index("@skin"), Some(skin));
+	assert_eq!(ltp.entry_index("@last"), Some(hair));
+	assert_eq!(ltp.entry_index("@2"), Some(2));
+	assert_eq!(ltp.entry_index("@3"), None);
+	assert_eq!(ltp.entry_index("skin"), None);
+
+	assert_eq!(ltp.parse_color("@skin").unwrap().rgba_u8(), [0xe0, 0xac, 0x69, 255]);
+	assert!(ltp.parse_color("@nose").is_err());
+}
+
+#[test]
+fn test_extend_ramp_from_last() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	ltp.add_color_string("#884422").unwrap();
+	let indices = ltp.add_gradient_colorspace("@last", "darken(@last, 30%)", 4, "oklab").unwrap();
+	assert_eq!(indices, vec![2, 3, 4, 5]);
+	assert_eq!(ltp.colors()[1].rgba_u8(), ltp.colors()[0].rgba_u8());
+	assert_f32_near(ltp.colors()[4].to_oklch()[0], ltp.colors()[0].to_oklch()[0] - 0.3, 0.002);
+}
+
+#[test]
+fn test_entry_names_round_trip() {
+	let filename = temp_png("names");
+	let names_file = filename.replace(".png", "_names.txt");
+
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.add_color_string("red").unwrap();
+	let blue = ltp.add_color_string("blue").unwrap();
+	ltp.set_name(blue, "sky");
+	ltp.save();
+
+	assert_eq!(std::fs::read_to_string(&names_file).unwrap(), "2 sky\n");
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.load();
+	std::fs::remove_file(&filename).unwrap();
+	std::fs::remove_file(&names_file).unwrap();
+
+	assert_eq!(loaded.names(), &[None, Some("sky".to_string())]);
+	assert_eq!(loaded.parse_color("@sky").unwrap().rgba_u8(), [0, 0, 255, 255]);
+}
+
+// ===== Color Temperature Tests =====
+
+#[test]
+fn test_parse_kelvin() {
+	assert_eq!(Color::parse_kelvin("3200K"), Some(3200.0));
+	assert_eq!(Color::parse_kelvin(" 6500k "), Some(6500.0));
+	assert_eq!(Color::parse_kelvin("6500"), None);
+	assert_eq!(Color::parse_kelvin("black"), None);
+	assert!(matches!(Color::parse("500K"), Err(ColorParseError::InvalidTemperature(_))));
+	assert!(Color::parse("black").is_ok());
+}
+
+#[test]
+fn test_kelvin_white_points() {
+	// Close to D65, but the blackbody locus runs slightly below it
+	let d65ish = Color::parse("6500K").unwrap().rgba();
+	for channel in &d65ish[0..3] {
+		assert_f32_near(*channel, 1.0, 0.05);
+	}
+
+	// Tungsten is warm, the brightest channel is kept at 1.0
+	let tungsten = Color::parse("3200K").unwrap().rgba_u8();
+	assert_eq!(tungsten[0], 255);
+	assert!(tungsten[0] > tungsten[1] && tungsten[1] > tungsten[2], "{:?}", tungsten);
+
+	// Overcast sky is cold
+	let sky = Color::parse("10000K").unwrap().rgba_u8();
+	assert_eq!(sky[2], 255);
+	assert!(sky[2] > sky[0]);
+
+	// CIE xy of 5000K on the Planckian locus is about (0.3451, 0.3516)
+	let xyz = Color::kelvin_to_xyz(5000.0).unwrap();
+	let sum = xyz[0] + xyz[1] + xyz[2];
+	assert_f32_near(xyz[0] / sum, 0.3451, 0.0005);
+	assert_f32_near(xyz[1] / sum, 0.3516, 0.0005);
+
+	assert!(Color::kelvin_to_xyz(1000.0).is_none());
+	assert!(Color::kelvin_to_xyz(30000.0).is_none());
+}
+
+#[test]
+fn test_bradford_identity_and_white_mapping() {
+	let white = Color::kelvin_to_xyz(6500.0).unwrap();
+	let identity = Color::bradford_matrix(white, white);
+	for (r, row) in identity.iter().enumerate() {
+		for (c, value) in row.iter().enumerate() {
+			assert_f32_near(*value, if r == c { 1.0 } else { 0.0 }, 0.0001);
+		}
+	}
+
+	// The source white point maps onto the destination white point
+	let from = Color::parse("6500K").unwrap();
+	let to = Color::parse("3200K").unwrap();
+	let matrix = Color::bradford_matrix(Color::kelvin_to_xyz(6500.0).unwrap(), Color::kelvin_to_xyz(3200.0).unwrap());
+	let adapted = from.transform_linear(&matrix).rgba_linear();
+	let target = to.rgba_linear();
+	let scale = adapted[0] / target[0];
+	for c in 0..3 {
+		assert_f32_near(adapted[c], target[c] * scale, 0.002);
+	}
+}
+
+#[test]
+fn test_white_balance_palette() {
+	let mut ltp = LowTexPal::new("test.png", None);
+	ltp.add_color_string("#808080").unwrap();
+	ltp.add_color_string("#2e8b5780").unwrap();
+	let before: Vec<Color> = ltp.colors().to_vec();
+
+	assert!(!ltp.white_balance(6500.0, 100.0));
+	assert!(ltp.white_balance(6500.0, 3200.0));
+
+	let gray = ltp.colors()[0].rgba();
+	assert!(gray[0] > gray[1] && gray[1] > gray[2], "{:?}", gray);
+	assert_f32_near(ltp.colors()[1].alpha(), before[1].alpha(), 0.0001);
+
+	// Going back restores the palette
+	assert!(ltp.white_balance(3200.0, 6500.0));
+	for (color, original) in ltp.colors().iter().zip(before.iter()) {
+		for c in 0..4 {
+			assert_f32_near(color.rgba()[c], original.rgba()[c], 0.001);
+		}
+	}
+}
+
+// ===== Color Difference Tests =====
+
+#[test]
+fn test_delta_e_from_name() {
+	assert_eq!(DeltaE::from_name("ok"), Some(DeltaE::Ok));
+	assert_eq!(DeltaE::from_name("cie76"), Some(DeltaE::Cie76));
+	assert_eq!(DeltaE::from_name("cie94"), Some(DeltaE::Cie94));
+	assert_eq!(DeltaE::from_name("CIEDE2000"), Some(DeltaE::Ciede2000));
+	assert_eq!(DeltaE::from_name("cmc"), None);
+}
+
+#[test]
+fn test_ciede2000_reference_values() {
+	// Test data from Sharma, Wu and Dalal, "The CIEDE2000 Color-Difference Formula" (2005)
+	let pairs = [
+		([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+		([50.0, 3.1571, -77.2803], [50.0, 0.0, -82.7485], 2.8615),
+		([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.3669),
+		([50.0, -1.0, 2.0], [50.0, 0.0, 0.0], 2.3669),
+		([50.0, 2.49, -0.001], [50.0, -2.49, 0.0009], 7.1792),
+		([50.0, 2.49, -0.001], [50.0, -2.49, 0.0011], 7.2195),
+		([50.0, 2.5, 0.0], [73.0, 25.0, -18.0], 27.1492),
+		([50.0, 2.5, 0.0], [50.0, 3.1736, 0.5854], 1.0),
+		([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.2644),
+		([63.0109, -31.0961, -5.8663], [62.8187, -29.7946, -4.0864], 1.263),
+		([22.7233, 20.0904, -46.694], [23.0331, 14.973, -42.5619], 2.0373),
+		([2.0776, 0.0795, -1.135], [0.9033, -0.0636, -0.5514], 0.9082),
+	];
+	for (lab1, lab2, expected) in pairs {
+		assert_f32_near(Color::delta_e_ciede2000(lab1, lab2), expected, 0.0001);
+	}
+}
+
+#[test]
+fn test_cie76_and_cie94_reference_values() {
+	assert_f32_near(Color::delta_e_cie76([50.0, 0.0, 0.0], [50.0, 3.0, 4.0]), 5.0, 0.0001);
+	// CIE94 graphic arts, first color as reference
+	assert_f32_near(Color::delta_e_cie94([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485]), 1.3950, 0.0001);
+	assert_f32_near(Color::delta_e_cie94([50.0, 2.5, 0.0], [73.0, 25.0, -18.0]), 34.6892, 0.0001);
+	assert_f32_near(Color::delta_e_cie94([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387]), 1.3910, 0.0001);
+}
+
+#[test]
+fn test_color_delta_e() {
+	let red = Color::from_string("red").unwrap();
+	let dark_red = Color::from_string("darkred").unwrap();
+
+	for method in [DeltaE::Ok, DeltaE::Cie76, DeltaE::Cie94, DeltaE::Ciede2000] {
+		assert_f32_near(red.delta_e(&red, method), 0.0, 0.0001);
+		assert!(red.delta_e(&dark_red, method) > 0.0);
+	}
+
+	// Symmetric metrics
+	for method in [DeltaE::Ok, DeltaE::Cie76, DeltaE::Ciede2000] {
+		assert_f32_near(red.delta_e(&dark_red, method), dark_red.delta_e(&red, method), 0.0001);
+	}
+
+	// Black to white is 1.0 in OKLab and 100 in CIE Lab
+	let black = Color::from_string("black").unwrap();
+	let white = Color::from_string("white").unwrap();
+	assert_f32_near(black.delta_e(&white, DeltaE::Ok), 1.0, 0.001);
+	assert_f32_near(black.delta_e(&white, DeltaE::Cie76), 100.0, 0.01);
+	assert_f32_near(black.delta_e(&white, DeltaE::Ciede2000), 100.0, 0.01);
+}
+
+// ===== Nearest Entry Tests =====
+
+fn query_palette() -> LowTexPal {
+	let mut lowtexpal = LowTexPal::new("query_palette.png", None);
+	for color in ["red", "green", "blue", "#7a5230", "white"] {
+		lowtexpal.add_color_string(color).unwrap();
+	}
+	lowtexpal.set_name(4, "wood");
+	lowtexpal
+}
+
+#[test]
+fn test_nearest_entries() {
+	let lowtexpal = query_palette();
+	let query = Color::from_string("#7b5331").unwrap();
+
+	let nearest = lowtexpal.nearest_entries(&query, DeltaE::Ok, 3);
+	assert_eq!(nearest.len(), 3);
+	assert_eq!(nearest[0].index, 4);
+	assert!(nearest[0].delta_e < 0.01);
+	assert!(nearest[0].delta_e <= nearest[1].delta_e);
+	assert!(nearest[1].delta_e <= nearest[2].delta_e);
+
+	assert_eq!(lowtexpal.nearest_entries(&query, DeltaE::Ciede2000, 1)[0].index, 4);
+	assert_eq!(lowtexpal.nearest_entries(&query, DeltaE::Ok, 10).len(), 5);
+	assert!(LowTexPal::new("empty.png", None).nearest_entries(&query, DeltaE::Ok, 3).is_empty());
+}
+
+#[test]
+fn test_entry_uv() {
+	// 5 entries are laid out in a 4x4 grid
+	let mut lowtexpal = query_palette();
+	assert_eq!(lowtexpal.entry_uv(1), Some([0.125, 0.125]));
+	assert_eq!(lowtexpal.entry_uv(5), Some([0.125, 0.375]));
+	assert_eq!(lowtexpal.entry_uv(0), None);
+	assert_eq!(lowtexpal.entry_uv(6), None);
+
+	// Cells are scaled with the image, the UV centers stay put
+	lowtexpal.set_cell_size(4);
+	assert_eq!(lowtexpal.entry_uv(4), Some([0.875, 0.125]));
+
+	// A larger minimum width adds cells per row
+	let mut wide = LowTexPal::new("wide.png", Some(8));
+	wide.add_color_string("red").unwrap();
+	wide.add_color_string("blue").unwrap();
+	assert_eq!(wide.entry_uv(2), Some([0.1875, 0.0625]));
+}
+
+#[test]
+fn test_query_map_color() {
+	let lowtexpal = query_palette();
+	let mapping = query::map_color(&lowtexpal, "darken(@wood, 1%)", DeltaE::Ok).unwrap();
+	assert_eq!(mapping.index, 4);
+	assert_eq!(mapping.uv, [0.875, 0.125]);
+	assert!(query::map_color(&lowtexpal, "not a color", DeltaE::Ok).is_none());
+}
+
+#[test]
+fn test_query_map_csv() {
+	let lowtexpal = query_palette();
+
+	// With a header the color column is found by name and other columns are kept
+	let mapped = query::map_csv(&lowtexpal, "material,color\nbark,#7a5230\nsky,\"rgb(0, 0, 250)\"\nbroken,nope\n", DeltaE::Ok).unwrap();
+	let lines: Vec<&str> = mapped.lines().collect();
+	assert_eq!(lines[0], "material,color,index,u,v,delta_e");
+	assert!(lines[1].starts_with("bark,#7a5230,4,0.875,0.125,"));
+	assert!(lines[2].starts_with("sky,\"rgb(0, 0, 250)\",3,0.625,0.125,"));
+	assert_eq!(lines[3], "broken,nope,,,,");
+
+	// Without a header the first column is the color
+	let mapped = query::map_csv(&lowtexpal, "white\nred\n", DeltaE::Ok).unwrap();
+	let lines: Vec<&str> = mapped.lines().collect();
+	assert!(lines[0].starts_with("white,5,0.125,0.375,"));
+	assert!(lines[1].starts_with("red,1,0.125,0.125,"));
+}
+
+#[test]
+fn test_query_map_json() {
+	let lowtexpal = query_palette();
+
+	let mapped = query::map_json(&lowtexpal, r##"["#ff0000", {"material": "bark", "color": "#7a5230"}, "nope"]"##, DeltaE::Ok).unwrap();
+	let mapped: serde_json::Value = serde_json::from_str(&mapped).unwrap();
+	assert_eq!(mapped[0]["color"], "#ff0000");
+	assert_eq!(mapped[0]["index"], 1);
+	assert_eq!(mapped[1]["material"], "bark");
+	assert_eq!(mapped[1]["index"], 4);
+	assert_eq!(mapped[1]["u"], 0.875);
+	assert_eq!(mapped[1]["v"], 0.125);
+	assert!(mapped[2]["index"].is_null());
+
+	assert!(query::map_json(&lowtexpal, "{ not json", DeltaE::Ok).is_err());
+}
+
+// ===== Dedupe Tests =====
+
+#[test]
+fn test_dedupe_merges_near_duplicates() {
+	let mut lowtexpal = LowTexPal::new("dedupe.png", None);
+	for color in ["#7a5230", "red", "#7b5230", "blue", "#ff0101", "#7a5231"] {
+		lowtexpal.add_color_string(color).unwrap();
+	}
+	lowtexpal.set_name(3, "bark");
+
+	let remap = lowtexpal.dedupe(DeltaE::Ciede2000, 1.5);
+	assert_eq!(lowtexpal.colors().len(), 3);
+	assert_eq!(lowtexpal.colors()[0].rgba_u8(), [0x7a, 0x52, 0x30, 255]);
+	assert!(lowtexpal.was_modified());
+
+	let new_indices: Vec<usize> = remap.iter().map(|e| e.new_index).collect();
+	assert_eq!(new_indices, vec![1, 2, 1, 3, 2, 1]);
+	// The merged entry's name moves to the entry it was merged into
+	assert_eq!(lowtexpal.entry_by_name("bark"), Some(1));
+
+	// 6 entries were laid out 4x4, 3 entries are laid out 2x2
+	assert_eq!(remap[3].old_uv, [0.875, 0.125]);
+	assert_eq!(remap[3].new_uv, [0.25, 0.75]);
+	assert_eq!(remap[4].old_uv, [0.125, 0.375]);
+	assert_eq!(remap[4].new_uv, [0.75, 0.25]);
+}
+
+#[test]
+fn test_dedupe_keeps_different_alpha_and_material() {
+	let mut lowtexpal = LowTexPal::new("dedupe_material.png", None);
+	for color in ["#808080", "rgb(128 128 128 / 50%)", "#808080", "#818181"] {
+		lowtexpal.add_color_string(color).unwrap();
+	}
+	lowtexpal.set_material(3, Material { roughness: 0.2, metallic: 1.0, emission: 0.0 });
+
+	let remap = lowtexpal.dedupe(DeltaE::Ok, 0.02);
+	let new_indices: Vec<usize> = remap.iter().map(|e| e.new_index).collect();
+	assert_eq!(new_indices, vec![1, 2, 3, 1]);
+	assert_eq!(lowtexpal.materials()[2].unwrap().metallic, 1.0);
+
+	// Nothing within a zero threshold
+	let remap = lowtexpal.dedupe(DeltaE::Ok, 0.0);
+	assert!(remap.iter().all(|e| e.old_index == e.new_index && e.old_uv == e.new_uv));
+}
+
+#[test]
+fn test_remap_table_round_trip() {
+	let filename = temp_file("remap_table", "csv");
+	let remap = vec![
+		RemapEntry { old_index: 1, new_index: 1, old_uv: [0.125, 0.125], new_uv: [0.25, 0.25] },
+		RemapEntry { old_index: 2, new_index: 1, old_uv: [0.375, 0.125], new_uv: [0.25, 0.25] },
+	];
+	remap::write_table(&filename, &remap).unwrap();
+
+	let text = std::fs::read_to_string(&filename).unwrap();
+	assert!(text.starts_with("old_index,new_index,old_u,old_v,new_u,new_v\n"));
+	assert_eq!(remap::read_table(&filename).unwrap(), remap);
+
+	let _ = std::fs::remove_file(&filename);
+}
+
+// ===== Reduce Tests =====
+
+#[test]
+fn test_reduce_clusters_similar_entries() {
+	let mut lowtexpal = LowTexPal::new("reduce.png", None);
+	for color in ["#ff0000", "#0000ff", "#f01008", "#0a0af0", "#e00000", "#ffffff", "#1010ff"] {
+		lowtexpal.add_color_string(color).unwrap();
+	}
+	lowtexpal.set_name(5, "dark-red");
+
+	let remap = lowtexpal.reduce(3);
+	assert_eq!(lowtexpal.colors().len(), 3);
+	assert!(lowtexpal.was_modified());
+
+	// Clusters keep the order of their first entry
+	let new_indices: Vec<usize> = remap.iter().map(|e| e.new_index).collect();
+	assert_eq!(new_indices, vec![1, 2, 1, 2, 1, 3, 2]);
+	assert_eq!(lowtexpal.colors()[2].rgba_u8(), [255, 255, 255, 255]);
+
+	// Representatives are existing entries closest to the centroid, names are kept
+	let reds = ["#ff0000", "#f01008", "#e00000"].map(|c| Color::from_string(c).unwrap().rgba_u8());
+	assert!(reds.contains(&lowtexpal.colors()[0].rgba_u8()));
+	assert_eq!(lowtexpal.entry_by_name("dark-red"), Some(1));
+	assert_eq!(remap[5].new_uv, lowtexpal.entry_uv(3).unwrap());
+}
+
+#[test]
+fn test_reduce_keeps_materials_apart() {
+	let mut lowtexpal = LowTexPal::new("reduce_materials.png", None);
+	for color in ["#ff0000", "#ff0505", "#0000ff", "#0505ff"] {
+		lowtexpal.add_color_string(color).unwrap();
+	}
+	let emissive = Material { emission: 1.0, ..Material::default() };
+	lowtexpal.set_material(1, emissive);
+
+	// The two reds are closest, but only the plain entries are merged
+	let remap = lowtexpal.reduce(3);
+	let new_indices: Vec<usize> = remap.iter().map(|e| e.new_index).collect();
+	assert_eq!(new_indices, vec![1, 2, 3, 3]);
+	assert_eq!(lowtexpal.materials()[0], Some(emissive));
+
+	let remap = lowtexpal.reduce(2);
+	assert_eq!(remap[0].new_index, 1);
+	assert_eq!(remap[1].new_index, remap[2].new_index);
+	assert_eq!(lowtexpal.materials()[0], Some(emissive));
+
+	// Materials are only mixed when nothing else is left
+	let remap = lowtexpal.reduce(1);
+	assert!(remap.iter().all(|e| e.new_index == 1));
+	assert_eq!(lowtexpal.colors().len(), 1);
+}
+
+#[test]
+fn test_reduce_to_more_entries_keeps_palette() {
+	let mut lowtexpal = LowTexPal::new("reduce_all.png", None);
+	for color in ["red", "green", "blue"] {
+		lowtexpal.add_color_string(color).unwrap();
+	}
+	let remap = lowtexpal.reduce(16);
+	assert_eq!(lowtexpal.colors().len(), 3);
+	assert!(remap.iter().all(|e| e.old_index == e.new_index));
+
+	lowtexpal.reduce(0);
+	assert_eq!(lowtexpal.colors().len(), 1);
+}
+
+// ===== Sort Tests =====
+
+fn sort_palette() -> LowTexPal {
+	let mut lowtexpal = LowTexPal::new("sort.png", None);
+	for color in ["#0000ff", "#ffffff", "#ff0000", "#000000", "#00ff00", "#808080"] {
+		lowtexpal.add_color_string(color).unwrap();
+	}
+	lowtexpal
+}
+
+fn sorted_colors(lowtexpal: &LowTexPal) -> Vec<[u8; 4]> {
+	lowtexpal.colors().iter().map(|c| c.rgba_u8()).collect()
+}
+
+#[test]
+fn test_sort_key_from_name() {
+	assert_eq!(SortKey::from_name("hue"), Some(SortKey::Hue));
+	assert_eq!(SortKey::from_name("lightness"), Some(SortKey::Lightness));
+	assert_eq!(SortKey::from_name("chroma"), Some(SortKey::Chroma));
+	assert_eq!(SortKey::from_name("OKLCH"), Some(SortKey::Oklch));
+	assert_eq!(SortKey::from_name("luminance"), Some(SortKey::Luminance));
+	assert_eq!(SortKey::from_name("path"), Some(SortKey::Path));
+	assert_eq!(SortKey::from_name("random"), None);
+}
+
+#[test]
+fn test_sort_by_lightness_and_luminance() {
+	let mut lowtexpal = sort_palette();
+	lowtexpal.set_name(3, "red");
+	let remap = lowtexpal.sort(SortKey::Lightness, false);
+	assert_eq!(sorted_colors(&lowtexpal), vec![
+		[0, 0, 0, 255], [0, 0, 255, 255], [128, 128, 128, 255], [255, 0, 0, 255], [0, 255, 0, 255], [255, 255, 255, 255],
+	]);
+	let new_indices: Vec<usize> = remap.iter().map(|e| e.new_index).collect();
+	assert_eq!(new_indices, vec![2, 6, 4, 1, 5, 3]);
+	assert_eq!(lowtexpal.entry_by_name("red"), Some(4));
+
+	// Red is lighter than gray in OKLab, but has a lower luminance
+	lowtexpal.sort(SortKey::Luminance, false);
+	assert_eq!(&sorted_colors(&lowtexpal)[..4], &[[0, 0, 0, 255], [0, 0, 255, 255], [255, 0, 0, 255], [128, 128, 128, 255]]);
+	lowtexpal.sort(SortKey::Chroma, false);
+	assert_eq!(sorted_colors(&lowtexpal)[5], [0, 0, 255, 255]);
+}
+
+#[test]
+fn test_sort_by_hue() {
+	let mut lowtexpal = sort_palette();
+	lowtexpal.sort(SortKey::Hue, false);
+	// Grays by lightness first, then red, green and blue by hue
+	assert_eq!(sorted_colors(&lowtexpal), vec![
+		[0, 0, 0, 255], [128, 128, 128, 255], [255, 255, 255, 255], [255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255],
+	]);
+	lowtexpal.sort(SortKey::Oklch, false);
+	assert_eq!(sorted_colors(&lowtexpal)[3], [255, 0, 0, 255]);
+}
+
+#[test]
+fn test_sort_per_group() {
+	let mut lowtexpal = LowTexPal::new("sort_groups.png", None);
+	lowtexpal.add_color_string("#ffffff").unwrap();
+	let ramp = lowtexpal.add_gradient_strings("#ff0000", "#200000", 3).unwrap();
+	lowtexpal.add_color_string("#000000").unwrap();
+	assert_eq!(lowtexpal.groups()[1].as_deref(), Some("gradient-1"));
+	assert_eq!(lowtexpal.groups()[0], None);
+	assert_eq!(ramp, vec![2, 3, 4]);
+
+	let remap = lowtexpal.sort(SortKey::Lightness, true);
+	let new_indices: Vec<usize> = remap.iter().map(|e| e.new_index).collect();
+	// The gradient keeps its cells, the single colors swap
+	assert_eq!(new_indices, vec![5, 4, 3, 2, 1]);
+	assert!(lowtexpal.groups()[1..4].iter().all(|g| g.as_deref() == Some("gradient-1")));
+	assert_eq!(lowtexpal.groups()[4], None);
+}
+
+#[test]
+fn test_sort_perceptual_path() {
+	let mut lowtexpal = LowTexPal::new("sort_path.png", None);
+	for color in ["#ffffff", "#000000", "#ff0000", "#404040", "#c0c0c0", "#800000", "#808080"] {
+		lowtexpal.add_color_string(color).unwrap();
+	}
+	let path_length = |lowtexpal: &LowTexPal| lowtexpal.colors().windows(2).map(|w| w[0].delta_e(&w[1], DeltaE::Ok)).sum::<f32>();
+	let before = path_length(&lowtexpal);
+
+	lowtexpal.sort(SortKey::Path, false);
+	assert!(path_length(&lowtexpal) < before);
+	// Starts at the darkest entry and walks up the grays
+	let colors = sorted_colors(&lowtexpal);
+	assert_eq!(colors[0], [0, 0, 0, 255]);
+	let position = |color: [u8; 4]| colors.iter().position(|&c| c == color).unwrap() as i32;
+	let grays = [[64, 64, 64, 255], [128, 128, 128, 255], [192, 192, 192, 255], [255, 255, 255, 255]];
+	for pair in grays.windows(2) {
+		assert_eq!(position(pair[1]) - position(pair[0]), 1);
+	}
+}
+
+#[test]
+fn test_entry_groups_round_trip() {
+	let filename = temp_png("groups");
+	let groups_file = filename.replace(".png", "_groups.txt");
+
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.add_color_string("red").unwrap();
+	ltp.add_gradient_strings("blue", "navy", 2).unwrap();
+	assert!(ltp.set_group(1, "skin"));
+	assert!(!ltp.set_group(1, "not valid"));
+	ltp.save();
+
+	assert_eq!(std::fs::read_to_string(&groups_file).unwrap(), "1 skin\n2 gradient-1\n3 gradient-1\n");
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.load();
+	std::fs::remove_file(&filename).unwrap();
+	std::fs::remove_file(&groups_file).unwrap();
+
+	assert_eq!(loaded.groups(), ltp.groups());
+}
+
+// ===== Rows Layout Tests =====
+
+fn rows_palette(filename: &str) -> LowTexPal {
+	let mut lowtexpal = LowTexPal::new(filename, None);
+	lowtexpal.add_color_string("white").unwrap();
+	lowtexpal.add_gradient_strings("#ff0000", "#400000", 3).unwrap();
+	lowtexpal.add_color_string("black").unwrap();
+	lowtexpal.add_gradient_strings("#0000ff", "#000040", 12).unwrap();
+	lowtexpal
+}
+
+fn cell_of(lowtexpal: &LowTexPal, index: usize, size: f32) -> (u32, u32) {
+	let uv = lowtexpal.entry_uv(index).unwrap();
+	((uv[0] * size) as u32, (uv[1] * size) as u32)
+}
+
+#[test]
+fn test_layout_from_name() {
+	assert_eq!(Layout::from_name("compact"), Some(Layout::Compact));
+	assert_eq!(Layout::from_name("Rows"), Some(Layout::Rows));
+	assert_eq!(Layout::from_name("fixed"), Some(Layout::Fixed));
+	assert_eq!(Layout::from_name("spiral"), None);
+}
+
+#[test]
+fn test_rows_layout() {
+	let mut lowtexpal = rows_palette("rows.png");
+	// Compact: the 12 step gradient starts in the first row
+	assert_eq!(cell_of(&lowtexpal, 6, 8.0), (5, 0));
+
+	lowtexpal.set_layout(Layout::Rows);
+	// The short gradient is aligned to the left of the first row
+	assert_eq!(cell_of(&lowtexpal, 2, 8.0), (0, 0));
+	assert_eq!(cell_of(&lowtexpal, 4, 8.0), (2, 0));
+	// The long one takes the next two whole rows
+	assert_eq!(cell_of(&lowtexpal, 6, 8.0), (0, 1));
+	assert_eq!(cell_of(&lowtexpal, 17, 8.0), (3, 2));
+	// Single colors fill the holes
+	assert_eq!(cell_of(&lowtexpal, 1, 8.0), (3, 0));
+	assert_eq!(cell_of(&lowtexpal, 5, 8.0), (4, 0));
+}
+
+#[test]
+fn test_rows_layout_grows_texture() {
+	// 5 gradients of 3 don't fit into 4 rows, in 8 columns two share a row
+	let mut lowtexpal = LowTexPal::new("rows_grow.png", None);
+	for _ in 0..5 {
+		lowtexpal.add_gradient_strings("red", "blue", 3).unwrap();
+	}
+	assert_eq!(lowtexpal.entry_uv(15), Some([0.625, 0.875]));
+
+	lowtexpal.set_layout(Layout::Rows);
+	assert_eq!(cell_of(&lowtexpal, 4, 8.0), (4, 0));
+	assert_eq!(cell_of(&lowtexpal, 7, 8.0), (0, 1));
+	assert_eq!(cell_of(&lowtexpal, 13, 8.0), (0, 2));
+	assert_eq!(lowtexpal.entry_uv(13), Some([0.0625, 0.3125]));
+}
+
+#[test]
+fn test_relayout_remap() {
+	let mut lowtexpal = rows_palette("rows.png");
+	let remap = lowtexpal.relayout(Layout::Rows);
+	assert_eq!(lowtexpal.layout(), Layout::Rows);
+	assert!(lowtexpal.was_modified());
+	assert!(remap.iter().all(|e| e.old_index == e.new_index));
+	assert_eq!(remap[0].old_uv, [0.0625, 0.0625]);
+	assert_eq!(remap[0].new_uv, [0.4375, 0.0625]);
+}
+
+#[test]
+fn test_rows_layout_round_trip() {
+	let filename = temp_png("rows_layout");
+	let names_file = filename.replace(".png", "_names.txt");
+	let groups_file = filename.replace(".png", "_groups.txt");
+	let entries_file = filename.replace(".png", "_entries.txt");
+
+	let mut ltp = rows_palette(&filename);
+	ltp.set_layout(Layout::Rows);
+	ltp.set_name(1, "snow");
+	ltp.save();
+
+	// Holes stay transparent
+	let img = image::open(&filename).unwrap().to_rgba8();
+	assert_eq!(img.dimensions(), (8, 8));
+	assert_eq!(img.get_pixel(7, 0).0, [0, 0, 0, 0]);
+	assert_eq!(img.get_pixel(3, 0).0, [255, 255, 255, 255]);
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.set_layout(Layout::Rows);
+	loaded.load();
+	std::fs::remove_file(&filename).unwrap();
+	std::fs::remove_file(&names_file).unwrap();
+	std::fs::remove_file(&groups_file).unwrap();
+	std::fs::remove_file(&entries_file).unwrap();
+
+	// Every entry keeps its index and its cell
+	assert_eq!(loaded.colors().len(), 17);
+	assert_eq!(loaded.entry_by_name("snow"), Some(1));
+	for (i, color) in ltp.colors().iter().enumerate() {
+		assert_eq!(loaded.colors()[i].rgba_u8(), color.rgba_u8());
+		assert_eq!(loaded.entry_uv(i + 1), ltp.entry_uv(i + 1));
+		assert_eq!(loaded.groups()[i], ltp.groups()[i]);
+	}
+}
+
+#[test]
+fn test_rows_layout_keeps_entry_order() {
+	let filename = temp_png("rows_order");
+	let mut ltp = LowTexPal::new(&filename, None);
+	ltp.set_layout(Layout::Rows);
+	let lava = ltp.add_color_string("red").unwrap();
+	assert!(ltp.set_name(lava, "lava"));
+	ltp.add_gradient_strings("black", "white", 4).unwrap();
+	// The gradient takes the first row, the single color moves to the next one
+	assert_eq!(ltp.entry_uv(lava), Some([0.125, 0.375]));
+	ltp.save();
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.set_layout(Layout::Rows);
+	loaded.load();
+	assert_eq!(loaded.entry_by_name("lava"), Some(lava));
+	assert_eq!(loaded.parse_color("@1").unwrap().rgba_u8(), [255, 0, 0, 255]);
+	assert_eq!(loaded.nearest_entries(&Color::from_string("red").unwrap(), DeltaE::Ok, 1)[0].index, lava);
+	for i in 1..=5 {
+		assert_eq!(loaded.entry_uv(i), ltp.entry_uv(i));
+	}
+
+	// Later changes report the same old indices
+	let added = loaded.add_color_string("blue").unwrap();
+	assert_eq!(added, 6);
+	loaded.save();
+	let mut reloaded = LowTexPal::new(&filename, None);
+	reloaded.set_layout(Layout::Rows);
+	reloaded.load();
+	for suffix in ["names", "groups", "entries"] {
+		std::fs::remove_file(filename.replace(".png", &format!("_{}.txt", suffix))).unwrap();
+	}
+	std::fs::remove_file(&filename).unwrap();
+	assert_eq!(reloaded.entry_by_name("lava"), Some(lava));
+	assert_eq!(reloaded.colors()[5].rgba_u8(), [0, 0, 255, 255]);
+	assert_eq!(reloaded.entry_uv(6), loaded.entry_uv(6));
+}
+
+// ===== Fixed Layout Tests =====
+
+fn save_grays(filename: &str, min_width: Option<u32>, count: u8) {
+	let mut ltp = LowTexPal::new(filename, min_width);
+	ltp.set_layout(Layout::Fixed);
+	for i in 0..count {
+		ltp.add_color_rgb(i * 10, i * 10, i * 10);
+	}
+	ltp.save();
+}
+
+#[test]
+fn test_fixed_layout_refuses_to_grow() {
+	let filename = temp_png("fixed_full");
+	save_grays(&filename, Some(4), 16);
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.set_layout(Layout::Fixed);
+	loaded.load();
+	let uvs: Vec<[f32; 2]> = (1..=16).map(|i| loaded.entry_uv(i).unwrap()).collect();
+	let added = loaded.add_color_string("red").unwrap();
+
+	// No free cell left, saving is refused instead of moving the entries
+	assert_eq!(loaded.entry_uv(added), None);
+	loaded.save();
+	let img = image::open(&filename).unwrap().to_rgba8();
+	std::fs::remove_file(&filename).unwrap();
+	assert_eq!(img.dimensions(), (4, 4));
+	assert!(img.pixels().all(|p| p.0 != [255, 0, 0, 255]));
+
+	// Compact doubles the texture and every entry moves
+	loaded.set_layout(Layout::Compact);
+	assert_eq!(loaded.entry_uv(1), Some([0.0625, 0.0625]));
+	assert_ne!(loaded.entry_uv(1), Some(uvs[0]));
+}
+
+#[test]
+fn test_fixed_layout_reserves_default_width() {
+	let mut lowtexpal = LowTexPal::new("fixed_default.png", None);
+	lowtexpal.set_layout(Layout::Fixed);
+	let first = lowtexpal.add_color_string("red").unwrap();
+	assert_eq!(lowtexpal.entry_uv(first), Some([0.03125, 0.03125]));
+	let second = lowtexpal.add_color_string("blue").unwrap();
+	assert_eq!(lowtexpal.entry_uv(second), Some([0.09375, 0.03125]));
+	assert_eq!(lowtexpal.entry_uv(first), Some([0.03125, 0.03125]));
+}
+
+#[test]
+fn test_fixed_layout_keeps_uvs() {
+	// A reserved width leaves room to grow
+	let filename = temp_png("fixed_reserved");
+	save_grays(&filename, Some(8), 16);
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.set_layout(Layout::Fixed);
+	loaded.load();
+	let uvs: Vec<[f32; 2]> = (1..=16).map(|i| loaded.entry_uv(i).unwrap()).collect();
+	assert_eq!(uvs[15], [0.9375, 0.1875]);
+
+	let added = loaded.add_color_string("red").unwrap();
+	assert_eq!(loaded.entry_uv(added), Some([0.0625, 0.3125]));
+	assert_eq!((1..=16).map(|i| loaded.entry_uv(i).unwrap()).collect::<Vec<_>>(), uvs);
+
+	// Merged entries leave holes, the kept ones stay put
+	loaded.add_color_rgb(0, 0, 0);
+	let remap = loaded.dedupe(DeltaE::Ok, 0.01);
+	assert_eq!(remap[17].new_index, 1);
+	assert_eq!(loaded.entry_uv(17), Some([0.0625, 0.3125]));
+	loaded.save();
+
+	let img = image::open(&filename).unwrap().to_rgba8();
+	std::fs::remove_file(&filename).unwrap();
+	assert_eq!(img.dimensions(), (8, 8));
+	assert_eq!(img.get_pixel(0, 2).0, [255, 0, 0, 255]);
+	assert_eq!(img.get_pixel(1, 2).0, [0, 0, 0, 0]);
+}
+
+#[test]
+fn test_fixed_layout_sort_keeps_cells() {
+	let mut lowtexpal = LowTexPal::new("fixed_sort.png", Some(4));
+	lowtexpal.set_layout(Layout::Fixed);
+	for color in ["white", "black", "gray"] {
+		lowtexpal.add_color_string(color).unwrap();
+	}
+	let uvs: Vec<[f32; 2]> = (1..=3).map(|i| lowtexpal.entry_uv(i).unwrap()).collect();
+	let remap = lowtexpal.sort(SortKey::Lightness, false);
+	assert_eq!(remap[0].new_uv, uvs[2]);
+	assert_eq!((1..=3).map(|i| lowtexpal.entry_uv(i).unwrap()).collect::<Vec<_>>(), uvs);
+}
+
+#[test]
+fn test_relayout_fixed_grows_keeping_pixels() {
+	let filename = temp_png("fixed_grow");
+	save_grays(&filename, Some(4), 16);
+
+	// Without relayout a larger minimum width can't be used
+	let mut loaded = LowTexPal::new(&filename, Some(8));
+	loaded.set_layout(Layout::Fixed);
+	loaded.load();
+	assert_eq!(loaded.entry_uv(16), None);
+
+	let remap = loaded.relayout(Layout::Fixed);
+	assert_eq!(remap[15].old_uv, [0.875, 0.875]);
+	assert_eq!(remap[15].new_uv, [0.4375, 0.4375]);
+	let added = loaded.add_color_string("red").unwrap();
+	assert_eq!(loaded.entry_uv(added), Some([0.5625, 0.0625]));
+	loaded.save();
+
+	let img = image::open(&filename).unwrap().to_rgba8();
+	std::fs::remove_file(&filename).unwrap();
+	std::fs::remove_file(filename.replace(".png", "_entries.txt")).unwrap();
+	assert_eq!(img.dimensions(), (8, 8));
+	assert_eq!(img.get_pixel(3, 3).0, [150, 150, 150, 255]);
+	assert_eq!(img.get_pixel(4, 0).0, [255, 0, 0, 255]);
+}
+
+// ===== Model Remap Tests =====
+
+#[test]
+fn test_remap_old_grid_size() {
+	let entry = |old_uv: [f32; 2]| RemapEntry { old_index: 1, new_index: 1, old_uv, new_uv: [0.5, 0.5] };
+	assert_eq!(remap::old_grid_size(&[entry([0.5, 0.5])]), Some(1));
+	assert_eq!(remap::old_grid_size(&[entry([0.125, 0.125]), entry([0.375, 0.125])]), Some(4));
+	assert_eq!(remap::old_grid_size(&[entry([0.1875, 0.0625]), entry([0.0625, 0.9375])]), Some(8));
+	assert_eq!(remap::old_grid_size(&[]), None);
+}
+
+#[test]
+fn test_remap_obj() {
+	// White, black, red, gray on 2x2, sorted by lightness: black, gray, red, white
+	let mut lowtexpal = LowTexPal::new("remap_obj.png", None);
+	for color in ["white", "black", "red", "gray"] {
+		lowtexpal.add_color_string(color).unwrap();
+	}
+	let remap = lowtexpal.sort(SortKey::Lightness, false);
+
+	let obj = "# model\r\nv 0 0 0\r\nvt 0.25 0.75\r\nvt 0.3 0.9 0.0\r\nvt 0.75 0.25\r\nvt 1.1 0.7\r\nf 1/1 1/2 1/3\r\n";
+	let result = model::remap_obj(obj, &remap).unwrap();
+	assert_eq!(result.remapped, 4);
+	assert_eq!(result.unmapped, 0);
+	// white (top left) moves to the bottom right, anywhere in its cell, also when wrapping
+	assert_eq!(result.obj, "# model\r\nv 0 0 0\r\nvt 0.75 0.25\r\nvt 0.75 0.25 0.0\r\nvt 0.75 0.75\r\nvt 0.75 0.25\r\nf 1/1 1/2 1/3\r\n");
+}
+
+#[test]
+fn test_remap_obj_keeps_unused_cells() {
+	let mut lowtexpal = LowTexPal::new("remap_unused.png", None);
+	for color in ["white", "black", "red"] {
+		lowtexpal.add_color_string(color).unwrap();
+	}
+	let remap = lowtexpal.sort(SortKey::Lightness, false);
+
+	let result = model::remap_obj("vt 0.75 0.25\nvt 0.25 0.75\n", &remap).unwrap();
+	assert_eq!(result.remapped, 1);
+	assert_eq!(result.unmapped, 1);
+	assert!(result.obj.starts_with("vt 0.75 0.25\n"));
+	assert!(model::remap_obj("vt 0.5 0.5\n", &[]).is_none());
+}
+
+#[test]
+fn test_pending_remap_on_growth() {
+	let filename = temp_png("growth_remap");
+	let remap_file = filename.replace(".png", "_remap.csv");
+	let mut ltp = LowTexPal::new(&filename, None);
+	for i in 0..4 {
+		ltp.add_color_rgb(i * 50, 0, 0);
+	}
+	assert!(ltp.pending_remap().is_empty());
+	ltp.save();
+
+	let mut loaded = LowTexPal::new(&filename, None);
+	loaded.load();
+	assert!(loaded.pending_remap().is_empty());
+	loaded.add_color_string("blue").unwrap();
+	let remap = loaded.pending_remap();
+	loaded.save();
+	std::fs::remove_file(&filename).unwrap();
+
+	// Only reported, writing the table is up to the caller
+	assert!(!std::path::Path::new(&remap_file).exists());
+	assert_eq!(remap.len(), 4);
+	assert_eq!(remap[3].old_uv, [0.75, 0.75]);
+	assert_eq!(remap[3].new_uv, [0.875, 0.125]);
+	assert!(loaded.pending_remap().is_empty());
+}
+
+// ===== Bake OBJ Tests =====
+
+#[test]
+fn test_parse_mtl() {
+	let mtl = "# materials\nnewmtl Red\nKd 1 0 0\nd 0.5\n\nnewmtl Gray\nKd 0.2159 0.2159 0.2159\nnewmtl Wood\nKd 1 1 1\nmap_Kd wood.png\nnewmtl Empty\nnewmtl Hot\nKd 2 -1 0.5\n";
+	let bytes = |color: Option<Color>| color.unwrap().rgba().map(|c| (c * 255.0).round() as u8);
+	let linear = model::parse_mtl(mtl, Encoding::Linear);
+	assert_eq!(linear.len(), 5);
+	assert_eq!(linear[0].name, "Red");
+	assert_eq!(bytes(linear[0].color), [255, 0, 0, 128]);
+	assert_eq!(bytes(linear[1].color), [128, 128, 128, 255]);
+	assert!(linear[2].color.is_none());
+	assert_eq!(linear[2].definition, "newmtl Wood\nKd 1 1 1\nmap_Kd wood.png\n");
+	assert!(linear[3].color.is_none());
+	assert!(!linear[0].clamped);
+	// Out of range Kd is clamped instead of becoming an HDR or negative entry
+	assert!(linear[4].clamped);
+	assert_eq!(bytes(linear[4].color), [255, 0, 188, 255]);
+
+	let srgb = model::parse_mtl(mtl, Encoding::Srgb);
+	assert_eq!(bytes(srgb[1].color), [55, 55, 55, 255]);
+}
+
+#[test]
+fn test_bake_obj() {
+	let mut lowtexpal = LowTexPal::new("bake.png", None);
+	lowtexpal.add_color_string("red").unwrap();
+	let materials = model::parse_mtl("newmtl Red\nKd 1 0 0\nnewmtl Blue\nKd 0 0 1\nnewmtl AlsoBlue\nKd 0 0 1\nnewmtl Wood\nmap_Kd wood.png\n", Encoding::Linear);
+
+	let obj = "mtllib model.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0.3 0.3\nvn 0 0 1\nusemtl Blue\nf 1/1/1 2/1/1 3/1/1\nusemtl Red\nf 1//1 2//1 3//1\nusemtl AlsoBlue\nf 1 2 3\nusemtl Wood\nf 1/1 2/1 3/1\n";
+	let baked = model::bake_obj(&mut lowtexpal, obj, &materials, "baked.mtl", "bake.png", DeltaE::Ciede2000, 1.5).unwrap();
+
+	// Red is reused, both blues share the new entry
+	assert_eq!(lowtexpal.colors().len(), 2);
+	assert_eq!(lowtexpal.names()[1].as_deref(), Some("Blue"));
+	assert_eq!(baked.entries, vec![("Blue".to_string(), 2), ("Red".to_string(), 1), ("AlsoBlue".to_string(), 2)]);
+	assert_eq!(baked.skipped, vec!["Wood".to_string()]);
+	// The textured material keeps its coordinate, the palette ones follow it
+	assert_eq!(baked.obj, "mtllib baked.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0.3 0.3\nvn 0 0 1\nusemtl bake\nvt 0.75 0.75\nvt 0.25 0.75\nf 1/2/1 2/2/1 3/2/1\nusemtl bake\nf 1/3/1 2/3/1 3/3/1\nusemtl bake\nf 1/2 2/2 3/2\nusemtl Wood\nf 1/1 2/1 3/1\n");
+	assert!(baked.mtl.contains("newmtl bake\n"));
+	assert!(baked.mtl.contains("map_Kd bake.png\n"));
+	assert!(baked.mtl.ends_with("\nnewmtl Wood\nmap_Kd wood.png\n"));
+}
+
+#[test]
+fn test_bake_obj_keeps_later_texture_coordinates() {
+	let mut lowtexpal = LowTexPal::new("bake_objects.png", None);
+	let materials = model::parse_mtl("newmtl Red\nKd 1 0 0\nnewmtl Wood\nmap_Kd wood.png\n", Encoding::Linear);
+
+	// The second object's coordinates come after the palette one, relative references become absolute
+	let obj = "o A\nv 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nusemtl Red\nf 1/1 2/1 3/1\no B\nvt 0.5 0.5\nvt 1 1\nusemtl Wood\nf 1/-1 2/-2 3/2\n";
+	let baked = model::bake_obj(&mut lowtexpal, obj, &materials, "baked.mtl", "bake_objects.png", DeltaE::Ciede2000, 1.5).unwrap();
+	assert_eq!(baked.obj, "o A\nv 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nusemtl bake_objects\nvt 0.5 0.5\nf 1/2 2/2 3/2\no B\nvt 0.5 0.5\nvt 1 1\nusemtl Wood\nf 1/4 2/3 3/3\n");
+}